@@ -0,0 +1,1632 @@
+/*
+Assembles the subset of NASM syntax that the test listings and the disassembler use. The output
+matches the encodings nasm picks, so a listing can be assembled and disassembled without nasm.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::common_assembly::{
+    get_register_width, parse_register, parse_segment_register, register_to_assembly_name,
+    ArithmeticOpCode, OpCode, Register, SegmentRegister, WordByte,
+};
+use crate::instruction::{mnemonic_to_assembly_name, AddressBase, Mnemonic};
+
+/// the number of passes to try before giving up on branch sizes settling
+const MAX_PASSES: usize = 100;
+
+/// An error in the assembly source
+/// line: the 1-based line number of the statement that failed to assemble
+/// message: what was wrong with the statement
+#[derive(PartialEq, Clone, Debug)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[derive(PartialEq, Clone, Debug)]
+enum Token {
+    Identifier(String),
+    Number(i64),
+    Comma,
+    Colon,
+    Plus,
+    Minus,
+    OpenBracket,
+    CloseBracket,
+    Dollar,
+    /// the characters between matching ', " or ` quotes
+    String(String),
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum Term {
+    Number(i64),
+    /// $, the address of the start of the current line
+    Here,
+    Label(String),
+    Register(Register),
+}
+
+/// A sum of terms. The bool is true for terms that are subtracted.
+type Expression = Vec<(bool, Term)>;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+enum BranchKind {
+    Short,
+    Near,
+    Far,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+struct MemoryOperand {
+    size: Option<WordByte>,
+    segment: Option<SegmentRegister>,
+    base: Option<AddressBase>,
+    displacement: Option<Expression>,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum AsmOperand {
+    Register(Register),
+    SegmentRegister(SegmentRegister),
+    Memory(MemoryOperand),
+    Immediate(Expression),
+    FarPointer {
+        segment: Expression,
+        offset: Expression,
+    },
+}
+
+/// An operand along with the keywords written before it
+#[derive(PartialEq, Clone, Debug)]
+struct ParsedOperand {
+    operand: AsmOperand,
+    branch_kind: Option<BranchKind>,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum Body {
+    Instruction {
+        prefixes: Vec<u8>,
+        mnemonic: Mnemonic,
+        operands: Vec<ParsedOperand>,
+    },
+    Data {
+        word_byte: WordByte,
+        values: Vec<Expression>,
+    },
+    /// the address the machine code is loaded at, which labels and $ count from
+    Origin(usize),
+}
+
+#[derive(PartialEq, Clone, Debug)]
+struct Statement {
+    line: usize,
+    label: Option<String>,
+    body: Option<Body>,
+}
+
+/// The state shared by every statement in one pass over the source
+/// labels: label addresses found by the previous pass
+/// long_jumps: the indices of unsized jmp statements that did not fit in a short jump
+/// final_pass: whether undefined labels and out of range branches are errors
+/// changed: whether a jmp was lengthened during this pass
+struct Pass<'a> {
+    labels: &'a HashMap<String, usize>,
+    long_jumps: &'a mut HashSet<usize>,
+    final_pass: bool,
+    changed: bool,
+}
+
+/// splits a line into tokens. Comments must already be removed.
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let characters: Vec<char> = line.chars().collect();
+    let mut index = 0;
+
+    while index < characters.len() {
+        let character = characters[index];
+        let token = match character {
+            ' ' | '\t' | '\r' => {
+                index += 1;
+                continue;
+            }
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '[' => Token::OpenBracket,
+            ']' => Token::CloseBracket,
+            '$' => Token::Dollar,
+            '\'' | '"' | '`' => {
+                let length = characters[index + 1..]
+                    .iter()
+                    .position(|end| *end == character)
+                    .ok_or_else(|| "unterminated string".to_owned())?;
+                let string = characters[index + 1..index + 1 + length].iter().collect();
+                tokens.push(Token::String(string));
+                index += length + 2;
+                continue;
+            }
+            _ if character.is_ascii_alphanumeric() || "_.?@".contains(character) => {
+                let start = index;
+                while index < characters.len()
+                    && (characters[index].is_ascii_alphanumeric()
+                        || "_.?@$".contains(characters[index]))
+                {
+                    index += 1;
+                }
+                let word: String = characters[start..index].iter().collect();
+
+                if character.is_ascii_digit() {
+                    tokens.push(Token::Number(parse_number(&word)?));
+                } else {
+                    tokens.push(Token::Identifier(word));
+                }
+                continue;
+            }
+            _ => return Err(format!("unexpected character '{}'", character)),
+        };
+
+        tokens.push(token);
+        index += 1;
+    }
+
+    Ok(tokens)
+}
+
+/// parses decimal, 0x / h hexadecimal and 0b binary numbers
+fn parse_number(word: &str) -> Result<i64, String> {
+    let lowercase = word.to_lowercase();
+    let parsed = if let Some(digits) = lowercase.strip_prefix("0x") {
+        i64::from_str_radix(digits, 16)
+    } else if let Some(digits) = lowercase.strip_prefix("0b") {
+        i64::from_str_radix(digits, 2)
+    } else if let Some(digits) = lowercase.strip_suffix('h') {
+        i64::from_str_radix(digits, 16)
+    } else {
+        lowercase.parse::<i64>()
+    };
+
+    parsed.map_err(|_| format!("invalid number '{}'", word))
+}
+
+/// looks up a mnemonic, including the aliases nasm accepts
+fn parse_mnemonic(name: &str) -> Option<Mnemonic> {
+    let mnemonic = match name {
+        "mov" => Mnemonic::Mov,
+        "push" => Mnemonic::Push,
+        "pop" => Mnemonic::Pop,
+        "xchg" => Mnemonic::Xchg,
+        "in" => Mnemonic::In,
+        "out" => Mnemonic::Out,
+        "xlat" | "xlatb" => Mnemonic::Xlat,
+        "lea" => Mnemonic::Lea,
+        "lds" => Mnemonic::Lds,
+        "les" => Mnemonic::Les,
+        "lahf" => Mnemonic::Lahf,
+        "sahf" => Mnemonic::Sahf,
+        "pushf" => Mnemonic::Pushf,
+        "popf" => Mnemonic::Popf,
+        "add" => Mnemonic::Add,
+        "adc" => Mnemonic::Adc,
+        "inc" => Mnemonic::Inc,
+        "aaa" => Mnemonic::Aaa,
+        "daa" => Mnemonic::Daa,
+        "sub" => Mnemonic::Sub,
+        "sbb" => Mnemonic::Sbb,
+        "dec" => Mnemonic::Dec,
+        "neg" => Mnemonic::Neg,
+        "cmp" => Mnemonic::Cmp,
+        "aas" => Mnemonic::Aas,
+        "das" => Mnemonic::Das,
+        "mul" => Mnemonic::Mul,
+        "imul" => Mnemonic::Imul,
+        "aam" => Mnemonic::Aam,
+        "div" => Mnemonic::Div,
+        "idiv" => Mnemonic::Idiv,
+        "aad" => Mnemonic::Aad,
+        "cbw" => Mnemonic::Cbw,
+        "cwd" => Mnemonic::Cwd,
+        "not" => Mnemonic::Not,
+        "shl" | "sal" => Mnemonic::Shl,
+        "shr" => Mnemonic::Shr,
+        "sar" => Mnemonic::Sar,
+        "rol" => Mnemonic::Rol,
+        "ror" => Mnemonic::Ror,
+        "rcl" => Mnemonic::Rcl,
+        "rcr" => Mnemonic::Rcr,
+        "and" => Mnemonic::And,
+        "test" => Mnemonic::Test,
+        "or" => Mnemonic::Or,
+        "xor" => Mnemonic::Xor,
+        "movsb" => Mnemonic::Movsb,
+        "movsw" => Mnemonic::Movsw,
+        "cmpsb" => Mnemonic::Cmpsb,
+        "cmpsw" => Mnemonic::Cmpsw,
+        "scasb" => Mnemonic::Scasb,
+        "scasw" => Mnemonic::Scasw,
+        "lodsb" => Mnemonic::Lodsb,
+        "lodsw" => Mnemonic::Lodsw,
+        "stosb" => Mnemonic::Stosb,
+        "stosw" => Mnemonic::Stosw,
+        "call" => Mnemonic::Call,
+        "jmp" => Mnemonic::Jmp,
+        "ret" => Mnemonic::Ret,
+        "retf" => Mnemonic::Retf,
+        "jne" | "jnz" => Mnemonic::JneJnz,
+        "je" | "jz" => Mnemonic::Je,
+        "jl" | "jnge" => Mnemonic::Jl,
+        "jle" | "jng" => Mnemonic::Jle,
+        "jb" | "jnae" | "jc" => Mnemonic::Jb,
+        "jbe" | "jna" => Mnemonic::Jbe,
+        "jp" | "jpe" => Mnemonic::Jp,
+        "jo" => Mnemonic::Jo,
+        "js" => Mnemonic::Js,
+        "jnl" | "jge" => Mnemonic::Jnl,
+        "jg" | "jnle" => Mnemonic::Jg,
+        "jnb" | "jae" | "jnc" => Mnemonic::Jnb,
+        "ja" | "jnbe" => Mnemonic::Ja,
+        "jnp" | "jpo" => Mnemonic::Jnp,
+        "jno" => Mnemonic::Jno,
+        "jns" => Mnemonic::Jns,
+        "loop" => Mnemonic::Loop,
+        "loopz" | "loope" => Mnemonic::Loopz,
+        "loopnz" | "loopne" => Mnemonic::Loopnz,
+        "jcxz" => Mnemonic::Jcxz,
+        "int" => Mnemonic::Int,
+        "int3" => Mnemonic::Int3,
+        "into" => Mnemonic::Into,
+        "iret" => Mnemonic::Iret,
+        "clc" => Mnemonic::Clc,
+        "cmc" => Mnemonic::Cmc,
+        "stc" => Mnemonic::Stc,
+        "cld" => Mnemonic::Cld,
+        "std" => Mnemonic::Std,
+        "cli" => Mnemonic::Cli,
+        "sti" => Mnemonic::Sti,
+        "hlt" => Mnemonic::Hlt,
+        "wait" | "fwait" => Mnemonic::Wait,
+        "nop" => Mnemonic::Nop,
+        _ => return None,
+    };
+
+    Some(mnemonic)
+}
+
+/// the prefix byte for a lock, repeat or segment override keyword
+fn parse_prefix(name: &str) -> Option<u8> {
+    if let Some(segment_register) = parse_segment_register(name) {
+        return Some(segment_override(segment_register));
+    }
+
+    match name {
+        "lock" => Some(OpCode::Lock as u8),
+        "repne" | "repnz" => Some(OpCode::Rep as u8),
+        "rep" | "repe" | "repz" => Some(OpCode::Rep as u8 | 0b00000001),
+        _ => None,
+    }
+}
+
+fn segment_override(segment_register: SegmentRegister) -> u8 {
+    OpCode::SegmentOverride as u8 | ((segment_register as u8) << 3)
+}
+
+/// parses a sum of numbers, labels, $ and registers. Consecutive signs are combined, so
+/// `$ + 2 + -6` is accepted.
+fn parse_expression(tokens: &[Token]) -> Result<Expression, String> {
+    let mut expression = vec![];
+    let mut index = 0;
+
+    while index < tokens.len() {
+        let mut negative = false;
+        while let Some(Token::Plus | Token::Minus) = tokens.get(index) {
+            if tokens[index] == Token::Minus {
+                negative = !negative;
+            }
+            index += 1;
+        }
+
+        let term = match tokens.get(index) {
+            Some(Token::Number(number)) => Term::Number(*number),
+            Some(Token::String(string)) => Term::Number(character_constant(string)?),
+            Some(Token::Dollar) => Term::Here,
+            Some(Token::Identifier(name)) => match parse_register(name) {
+                Some(register) => Term::Register(register),
+                None => Term::Label(name.clone()),
+            },
+            _ => return Err("expected a number, label or register".to_owned()),
+        };
+        expression.push((negative, term));
+        index += 1;
+
+        match tokens.get(index) {
+            None | Some(Token::Plus | Token::Minus) => {}
+            _ => return Err("expected + or - between terms".to_owned()),
+        }
+    }
+
+    if expression.is_empty() {
+        return Err("expected an expression".to_owned());
+    }
+
+    Ok(expression)
+}
+
+/// the value of a string used as a number, like 'A'. The first character is the low byte.
+fn character_constant(string: &str) -> Result<i64, String> {
+    match string.as_bytes() {
+        [low] => Ok(*low as i64),
+        [low, high] => Ok(((*high as i64) << 8) | *low as i64),
+        _ => Err(format!(
+            "a string used as a number must have 1 or 2 characters, not '{}'",
+            string
+        )),
+    }
+}
+
+/// parses the comma separated values of db or dw. A string stands for each of its characters,
+/// and dw pads it with a zero byte to a whole number of words.
+fn parse_data(tokens: &[Token], word_byte: WordByte) -> Result<Vec<Expression>, String> {
+    let mut values = vec![];
+    for value_tokens in tokens.split(|token| *token == Token::Comma) {
+        match value_tokens {
+            [Token::String(string)] => {
+                let bytes = string.as_bytes();
+                let values_per_character = match word_byte {
+                    WordByte::Byte => 1,
+                    WordByte::Word => 2,
+                };
+                for chunk in bytes.chunks(values_per_character) {
+                    let value = chunk
+                        .iter()
+                        .rev()
+                        .fold(0, |value, byte| (value << 8) | *byte as i64);
+                    values.push(vec![(false, Term::Number(value))]);
+                }
+            }
+            _ => values.push(parse_expression(value_tokens)?),
+        }
+    }
+    Ok(values)
+}
+
+/// finds the ; that starts a comment, skipping any inside strings
+fn comment_start(line: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, character) in line.char_indices() {
+        match (quote, character) {
+            (None, ';') => return Some(index),
+            (None, '\'' | '"' | '`') => quote = Some(character),
+            (Some(open), _) if open == character => quote = None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// picks the addressing mode for a set of base and index registers
+fn get_address_base(registers: &[Register]) -> Result<Option<AddressBase>, String> {
+    let has = |register: Register| registers.contains(&register);
+    let base = match (registers.len(), has(Register::Bx), has(Register::Bp)) {
+        (0, _, _) => None,
+        (1, true, _) => Some(AddressBase::Bx),
+        (1, _, true) => Some(AddressBase::Bp),
+        (1, _, _) if has(Register::Si) => Some(AddressBase::Si),
+        (1, _, _) if has(Register::Di) => Some(AddressBase::Di),
+        (2, true, _) if has(Register::Si) => Some(AddressBase::BxSi),
+        (2, true, _) if has(Register::Di) => Some(AddressBase::BxDi),
+        (2, _, true) if has(Register::Si) => Some(AddressBase::BpSi),
+        (2, _, true) if has(Register::Di) => Some(AddressBase::BpDi),
+        _ => return Err("invalid effective address".to_owned()),
+    };
+
+    Ok(base)
+}
+
+/// parses the inside of square brackets
+fn parse_memory(tokens: &[Token], size: Option<WordByte>) -> Result<MemoryOperand, String> {
+    let (segment, tokens) = match tokens {
+        [Token::Identifier(name), Token::Colon, rest @ ..] => match parse_segment_register(name) {
+            Some(segment_register) => (Some(segment_register), rest),
+            None => return Err(format!("'{}' is not a segment register", name)),
+        },
+        _ => (None, tokens),
+    };
+
+    let mut registers = vec![];
+    let mut displacement = vec![];
+    for (negative, term) in parse_expression(tokens)? {
+        match term {
+            Term::Register(register) if !negative => registers.push(register),
+            Term::Register(_) => return Err("registers cannot be subtracted".to_owned()),
+            term => displacement.push((negative, term)),
+        }
+    }
+
+    Ok(MemoryOperand {
+        size,
+        segment,
+        base: get_address_base(&registers)?,
+        displacement: if displacement.is_empty() {
+            None
+        } else {
+            Some(displacement)
+        },
+    })
+}
+
+fn parse_operand(tokens: &[Token]) -> Result<ParsedOperand, String> {
+    let mut size = None;
+    let mut branch_kind = None;
+    let mut tokens = tokens;
+
+    while let Some(Token::Identifier(name)) = tokens.first() {
+        match name.to_lowercase().as_str() {
+            "byte" => size = Some(WordByte::Byte),
+            "word" => size = Some(WordByte::Word),
+            "short" => branch_kind = Some(BranchKind::Short),
+            "near" => branch_kind = Some(BranchKind::Near),
+            "far" => branch_kind = Some(BranchKind::Far),
+            _ => break,
+        }
+        tokens = &tokens[1..];
+    }
+
+    let operand = match tokens {
+        [] => return Err("expected an operand".to_owned()),
+        [Token::OpenBracket, inner @ .., Token::CloseBracket] => {
+            AsmOperand::Memory(parse_memory(inner, size)?)
+        }
+        [Token::Identifier(name)] if parse_register(name).is_some() => {
+            AsmOperand::Register(parse_register(name).unwrap())
+        }
+        [Token::Identifier(name)] if parse_segment_register(name).is_some() => {
+            AsmOperand::SegmentRegister(parse_segment_register(name).unwrap())
+        }
+        _ => match tokens.iter().position(|token| *token == Token::Colon) {
+            Some(colon_index) => AsmOperand::FarPointer {
+                segment: parse_expression(&tokens[..colon_index])?,
+                offset: parse_expression(&tokens[colon_index + 1..])?,
+            },
+            None => AsmOperand::Immediate(parse_expression(tokens)?),
+        },
+    };
+
+    Ok(ParsedOperand {
+        operand,
+        branch_kind,
+    })
+}
+
+/// parses one line of source. Returns None for lines that only hold directives or comments.
+fn parse_line(line: &str, line_number: usize) -> Result<Option<Statement>, String> {
+    let code = match comment_start(line) {
+        Some(comment_start) => &line[..comment_start],
+        None => line,
+    };
+    let mut tokens: &[Token] = &tokenize(code)?;
+
+    let label = match tokens {
+        [Token::Identifier(name), Token::Colon, rest @ ..] => {
+            tokens = rest;
+            Some(name.clone())
+        }
+        _ => None,
+    };
+
+    let body = match tokens {
+        [] => None,
+        [Token::Identifier(name), rest @ ..] => {
+            let name = name.to_lowercase();
+            match name.as_str() {
+                "bits" => {
+                    if rest != [Token::Number(16)] {
+                        return Err("only bits 16 is supported".to_owned());
+                    }
+                    None
+                }
+                "db" | "dw" => {
+                    let word_byte = if name == "db" {
+                        WordByte::Byte
+                    } else {
+                        WordByte::Word
+                    };
+                    let values = parse_data(rest, word_byte)?;
+                    Some(Body::Data { word_byte, values })
+                }
+                "org" => match rest {
+                    [Token::Number(origin)] if (0..=0xFFFF).contains(origin) => {
+                        Some(Body::Origin(*origin as usize))
+                    }
+                    _ => return Err("org needs an address from 0 to 0xFFFF".to_owned()),
+                },
+                _ => Some(parse_instruction(tokens)?),
+            }
+        }
+        _ => return Err("expected a label, directive or instruction".to_owned()),
+    };
+
+    if label.is_none() && body.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(Statement {
+        line: line_number,
+        label,
+        body,
+    }))
+}
+
+/// parses prefixes, a mnemonic and its operands
+fn parse_instruction(tokens: &[Token]) -> Result<Body, String> {
+    let mut prefixes = vec![];
+    let mut index = 0;
+
+    while let Some(Token::Identifier(name)) = tokens.get(index) {
+        match parse_prefix(&name.to_lowercase()) {
+            Some(prefix) => prefixes.push(prefix),
+            None => break,
+        }
+        index += 1;
+    }
+
+    let mnemonic = match tokens.get(index) {
+        Some(Token::Identifier(name)) => match parse_mnemonic(&name.to_lowercase()) {
+            Some(mnemonic) => mnemonic,
+            None => return Err(format!("unknown instruction '{}'", name)),
+        },
+        // a prefix can be written on a line of its own
+        None if !prefixes.is_empty() => {
+            return Ok(Body::Data {
+                word_byte: WordByte::Byte,
+                values: prefixes
+                    .iter()
+                    .map(|prefix| vec![(false, Term::Number(*prefix as i64))])
+                    .collect(),
+            })
+        }
+        _ => return Err("expected an instruction".to_owned()),
+    };
+
+    let operand_tokens = &tokens[index + 1..];
+    let operands = if operand_tokens.is_empty() {
+        vec![]
+    } else {
+        operand_tokens
+            .split(|token| *token == Token::Comma)
+            .map(parse_operand)
+            .collect::<Result<Vec<ParsedOperand>, String>>()?
+    };
+
+    Ok(Body::Instruction {
+        prefixes,
+        mnemonic,
+        operands,
+    })
+}
+
+impl Pass<'_> {
+    /// evaluates an expression. Before the final pass, labels that are not known yet evaluate to
+    /// the current address so that branches to them start out short.
+    fn evaluate(&self, expression: &Expression, address: usize) -> Result<i64, String> {
+        let mut value = 0;
+        for (negative, term) in expression {
+            let term_value = match term {
+                Term::Number(number) => *number,
+                Term::Here => address as i64,
+                Term::Label(name) => match self.labels.get(name) {
+                    Some(label_address) => *label_address as i64,
+                    None if !self.final_pass => address as i64,
+                    None => return Err(format!("undefined label '{}'", name)),
+                },
+                Term::Register(register) => {
+                    return Err(format!(
+                        "register {} is not allowed here",
+                        register_to_assembly_name(*register)
+                    ))
+                }
+            };
+
+            if *negative {
+                value -= term_value;
+            } else {
+                value += term_value;
+            }
+        }
+
+        Ok(value)
+    }
+}
+
+fn register_field(register: Register) -> u8 {
+    (register as u8) & 0b111
+}
+
+fn is_accumulator(register: Register) -> bool {
+    register == Register::Al || register == Register::Ax
+}
+
+/// whether value can be encoded as a sign-extended byte
+fn fits_signed_byte(value: i64) -> bool {
+    (-128..=127).contains(&(value as i16))
+}
+
+fn immediate_bytes(value: i64, word_byte: WordByte) -> Vec<u8> {
+    match word_byte {
+        WordByte::Byte => vec![value as u8],
+        WordByte::Word => (value as u16).to_le_bytes().to_vec(),
+    }
+}
+
+/// Encodes an instruction or data statement
+/// address: the address of the first byte of the statement
+/// statement_index: identifies the statement in pass.long_jumps
+fn encode_statement(
+    body: &Body,
+    address: usize,
+    statement_index: usize,
+    pass: &mut Pass,
+) -> Result<Vec<u8>, String> {
+    match body {
+        Body::Origin(_) => Ok(vec![]),
+        Body::Data { word_byte, values } => {
+            let mut bytes = vec![];
+            for value in values {
+                bytes.extend(immediate_bytes(pass.evaluate(value, address)?, *word_byte));
+            }
+            Ok(bytes)
+        }
+        Body::Instruction {
+            prefixes,
+            mnemonic,
+            operands,
+        } => {
+            let mut bytes = prefixes.clone();
+            for parsed_operand in operands {
+                if let AsmOperand::Memory(MemoryOperand {
+                    segment: Some(segment_register),
+                    ..
+                }) = parsed_operand.operand
+                {
+                    bytes.push(segment_override(segment_register));
+                }
+            }
+
+            let encoder = Encoder {
+                mnemonic: *mnemonic,
+                operands,
+                address,
+                operation_address: address + bytes.len(),
+                statement_index,
+            };
+            bytes.extend(encoder.encode(pass)?);
+            Ok(bytes)
+        }
+    }
+}
+
+/// Encodes a single instruction without its prefixes
+/// address: the address of the statement, which $ refers to
+/// operation_address: the address of the opcode, after any prefixes
+struct Encoder<'a> {
+    mnemonic: Mnemonic,
+    operands: &'a [ParsedOperand],
+    address: usize,
+    operation_address: usize,
+    statement_index: usize,
+}
+
+impl Encoder<'_> {
+    fn name(&self) -> &'static str {
+        mnemonic_to_assembly_name(self.mnemonic)
+    }
+
+    fn invalid_operands(&self) -> String {
+        format!("invalid combination of operands for {}", self.name())
+    }
+
+    fn operand_list(&self) -> Vec<&AsmOperand> {
+        self.operands
+            .iter()
+            .map(|parsed_operand| &parsed_operand.operand)
+            .collect()
+    }
+
+    /// the width of the operation, taken from registers first and then size keywords
+    fn width(&self, operands: &[&AsmOperand]) -> Result<WordByte, String> {
+        let mut width = None;
+        for operand in operands {
+            let operand_width = match operand {
+                AsmOperand::Register(register) => Some(get_register_width(*register)),
+                AsmOperand::SegmentRegister(_) => Some(WordByte::Word),
+                AsmOperand::Memory(memory) => memory.size,
+                _ => None,
+            };
+
+            match (width, operand_width) {
+                (Some(width), Some(operand_width)) if width != operand_width => {
+                    return Err("mismatch in operand sizes".to_owned())
+                }
+                (None, Some(_)) => width = operand_width,
+                _ => {}
+            }
+        }
+
+        width.ok_or_else(|| "operation size not specified".to_owned())
+    }
+
+    fn evaluate(&self, expression: &Expression, pass: &Pass) -> Result<i64, String> {
+        pass.evaluate(expression, self.address)
+    }
+
+    /// Encodes the ModRM byte and displacement
+    /// reg_field: the register or operation number for the reg bits
+    /// rm: the register or memory operand for the mod and rm bits
+    fn modrm(&self, reg_field: u8, rm: &AsmOperand, pass: &Pass) -> Result<Vec<u8>, String> {
+        let memory = match rm {
+            AsmOperand::Register(register) => {
+                return Ok(vec![
+                    0b11000000 | (reg_field << 3) | register_field(*register),
+                ])
+            }
+            AsmOperand::Memory(memory) => memory,
+            _ => return Err(self.invalid_operands()),
+        };
+
+        let displacement = match &memory.displacement {
+            Some(expression) => Some(self.evaluate(expression, pass)?),
+            None => None,
+        };
+
+        let (mode, rm_field, displacement_bytes) = match (memory.base, displacement) {
+            (None, displacement) => (
+                0b00,
+                0b110,
+                immediate_bytes(displacement.unwrap_or(0), WordByte::Word),
+            ),
+            // [bp] has no encoding without a displacement, so it uses a zero byte displacement
+            (Some(AddressBase::Bp), None) => (0b01, AddressBase::Bp as u8, vec![0]),
+            (Some(base), None) => (0b00, base as u8, vec![]),
+            (Some(AddressBase::Bp), Some(0)) => (0b01, AddressBase::Bp as u8, vec![0]),
+            (Some(base), Some(0)) => (0b00, base as u8, vec![]),
+            (Some(base), Some(value)) if fits_signed_byte(value) => {
+                (0b01, base as u8, vec![value as u8])
+            }
+            (Some(base), Some(value)) => (0b10, base as u8, immediate_bytes(value, WordByte::Word)),
+        };
+
+        let mut bytes = vec![(mode << 6) | (reg_field << 3) | rm_field];
+        bytes.extend(displacement_bytes);
+        Ok(bytes)
+    }
+
+    fn encode(&self, pass: &mut Pass) -> Result<Vec<u8>, String> {
+        let operands = self.operand_list();
+
+        match self.mnemonic {
+            Mnemonic::Mov => self.encode_mov(&operands, pass),
+            Mnemonic::Add => self.encode_arithmetic(ArithmeticOpCode::Add, &operands, pass),
+            Mnemonic::Or => self.encode_arithmetic(ArithmeticOpCode::Or, &operands, pass),
+            Mnemonic::Adc => self.encode_arithmetic(ArithmeticOpCode::Adc, &operands, pass),
+            Mnemonic::Sbb => self.encode_arithmetic(ArithmeticOpCode::Sbb, &operands, pass),
+            Mnemonic::And => self.encode_arithmetic(ArithmeticOpCode::And, &operands, pass),
+            Mnemonic::Sub => self.encode_arithmetic(ArithmeticOpCode::Sub, &operands, pass),
+            Mnemonic::Xor => self.encode_arithmetic(ArithmeticOpCode::Xor, &operands, pass),
+            Mnemonic::Cmp => self.encode_arithmetic(ArithmeticOpCode::Cmp, &operands, pass),
+            Mnemonic::Test => self.encode_test(&operands, pass),
+            Mnemonic::Xchg => self.encode_xchg(&operands, pass),
+            Mnemonic::Inc => self.encode_inc_dec(OpCode::IncRegister, 0b000, &operands, pass),
+            Mnemonic::Dec => self.encode_inc_dec(OpCode::DecRegister, 0b001, &operands, pass),
+            Mnemonic::Push => self.encode_push(&operands, pass),
+            Mnemonic::Pop => self.encode_pop(&operands, pass),
+            Mnemonic::In => self.encode_port(&operands, true, pass),
+            Mnemonic::Out => self.encode_port(&operands, false, pass),
+            Mnemonic::Lea => self.encode_load_address(OpCode::Lea, &operands, pass),
+            Mnemonic::Lds => self.encode_load_address(OpCode::Lds, &operands, pass),
+            Mnemonic::Les => self.encode_load_address(OpCode::Les, &operands, pass),
+            Mnemonic::Not => self.encode_group_3(0b010, &operands, pass),
+            Mnemonic::Neg => self.encode_group_3(0b011, &operands, pass),
+            Mnemonic::Mul => self.encode_group_3(0b100, &operands, pass),
+            Mnemonic::Imul => self.encode_group_3(0b101, &operands, pass),
+            Mnemonic::Div => self.encode_group_3(0b110, &operands, pass),
+            Mnemonic::Idiv => self.encode_group_3(0b111, &operands, pass),
+            Mnemonic::Rol => self.encode_shift_rotate(0b000, &operands, pass),
+            Mnemonic::Ror => self.encode_shift_rotate(0b001, &operands, pass),
+            Mnemonic::Rcl => self.encode_shift_rotate(0b010, &operands, pass),
+            Mnemonic::Rcr => self.encode_shift_rotate(0b011, &operands, pass),
+            Mnemonic::Shl => self.encode_shift_rotate(0b100, &operands, pass),
+            Mnemonic::Shr => self.encode_shift_rotate(0b101, &operands, pass),
+            Mnemonic::Sar => self.encode_shift_rotate(0b111, &operands, pass),
+            Mnemonic::Aam => self.encode_byte_immediate(OpCode::Aam, Some(10), &operands, pass),
+            Mnemonic::Aad => self.encode_byte_immediate(OpCode::Aad, Some(10), &operands, pass),
+            Mnemonic::Int => self.encode_byte_immediate(OpCode::Int, None, &operands, pass),
+            Mnemonic::Ret => self.encode_return(OpCode::Ret, OpCode::RetImmediate, &operands, pass),
+            Mnemonic::Retf => {
+                self.encode_return(OpCode::Retf, OpCode::RetfImmediate, &operands, pass)
+            }
+            Mnemonic::Call | Mnemonic::Jmp => self.encode_call_jmp(pass),
+            Mnemonic::JneJnz => self.encode_short_branch(OpCode::JneJnz, pass),
+            Mnemonic::Je => self.encode_short_branch(OpCode::Je, pass),
+            Mnemonic::Jl => self.encode_short_branch(OpCode::Jl, pass),
+            Mnemonic::Jle => self.encode_short_branch(OpCode::Jle, pass),
+            Mnemonic::Jb => self.encode_short_branch(OpCode::Jb, pass),
+            Mnemonic::Jbe => self.encode_short_branch(OpCode::Jbe, pass),
+            Mnemonic::Jp => self.encode_short_branch(OpCode::Jp, pass),
+            Mnemonic::Jo => self.encode_short_branch(OpCode::Jo, pass),
+            Mnemonic::Js => self.encode_short_branch(OpCode::Js, pass),
+            Mnemonic::Jnl => self.encode_short_branch(OpCode::Jnl, pass),
+            Mnemonic::Jg => self.encode_short_branch(OpCode::Jg, pass),
+            Mnemonic::Jnb => self.encode_short_branch(OpCode::Jnb, pass),
+            Mnemonic::Ja => self.encode_short_branch(OpCode::Ja, pass),
+            Mnemonic::Jnp => self.encode_short_branch(OpCode::Jnp, pass),
+            Mnemonic::Jno => self.encode_short_branch(OpCode::Jno, pass),
+            Mnemonic::Jns => self.encode_short_branch(OpCode::Jns, pass),
+            Mnemonic::Loop => self.encode_short_branch(OpCode::Loop, pass),
+            Mnemonic::Loopz => self.encode_short_branch(OpCode::Loopz, pass),
+            Mnemonic::Loopnz => self.encode_short_branch(OpCode::Loopnz, pass),
+            Mnemonic::Jcxz => self.encode_short_branch(OpCode::Jcxz, pass),
+            _ => {
+                if !operands.is_empty() {
+                    return Err(format!("{} takes no operands", self.name()));
+                }
+                self.encode_single_byte()
+            }
+        }
+    }
+
+    fn encode_single_byte(&self) -> Result<Vec<u8>, String> {
+        let opcode = match self.mnemonic {
+            Mnemonic::Xlat => OpCode::Xlat as u8,
+            Mnemonic::Lahf => OpCode::Lahf as u8,
+            Mnemonic::Sahf => OpCode::Sahf as u8,
+            Mnemonic::Pushf => OpCode::Pushf as u8,
+            Mnemonic::Popf => OpCode::Popf as u8,
+            Mnemonic::Aaa => OpCode::Aaa as u8,
+            Mnemonic::Daa => OpCode::Daa as u8,
+            Mnemonic::Aas => OpCode::Aas as u8,
+            Mnemonic::Das => OpCode::Das as u8,
+            Mnemonic::Cbw => OpCode::Cbw as u8,
+            Mnemonic::Cwd => OpCode::Cwd as u8,
+            Mnemonic::Movsb => OpCode::Movs as u8,
+            Mnemonic::Movsw => OpCode::Movs as u8 | 1,
+            Mnemonic::Cmpsb => OpCode::Cmps as u8,
+            Mnemonic::Cmpsw => OpCode::Cmps as u8 | 1,
+            Mnemonic::Scasb => OpCode::Scas as u8,
+            Mnemonic::Scasw => OpCode::Scas as u8 | 1,
+            Mnemonic::Lodsb => OpCode::Lods as u8,
+            Mnemonic::Lodsw => OpCode::Lods as u8 | 1,
+            Mnemonic::Stosb => OpCode::Stos as u8,
+            Mnemonic::Stosw => OpCode::Stos as u8 | 1,
+            Mnemonic::Int3 => OpCode::Int3 as u8,
+            Mnemonic::Into => OpCode::Into as u8,
+            Mnemonic::Iret => OpCode::Iret as u8,
+            Mnemonic::Clc => OpCode::Clc as u8,
+            Mnemonic::Cmc => OpCode::Cmc as u8,
+            Mnemonic::Stc => OpCode::Stc as u8,
+            Mnemonic::Cld => OpCode::Cld as u8,
+            Mnemonic::Std => OpCode::Std as u8,
+            Mnemonic::Cli => OpCode::Cli as u8,
+            Mnemonic::Sti => OpCode::Sti as u8,
+            Mnemonic::Hlt => OpCode::Hlt as u8,
+            Mnemonic::Wait => OpCode::Wait as u8,
+            Mnemonic::Nop => OpCode::XchgAccumulator as u8,
+            _ => return Err(format!("{} is not supported", self.name())),
+        };
+
+        Ok(vec![opcode])
+    }
+
+    fn encode_mov(&self, operands: &[&AsmOperand], pass: &Pass) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        match operands {
+            [AsmOperand::SegmentRegister(segment_register), rm] => {
+                bytes.push(OpCode::MovToSegment as u8);
+                bytes.extend(self.modrm(*segment_register as u8, rm, pass)?);
+            }
+            [rm, AsmOperand::SegmentRegister(segment_register)] => {
+                bytes.push(OpCode::MovFromSegment as u8);
+                bytes.extend(self.modrm(*segment_register as u8, rm, pass)?);
+            }
+            [AsmOperand::Register(register), AsmOperand::Immediate(immediate)] => {
+                let word_byte = get_register_width(*register);
+                bytes.push(
+                    OpCode::RegisterImmediateMov as u8
+                        | ((word_byte as u8) << 3)
+                        | register_field(*register),
+                );
+                bytes.extend(immediate_bytes(self.evaluate(immediate, pass)?, word_byte));
+            }
+            [rm @ AsmOperand::Memory(_), AsmOperand::Immediate(immediate)] => {
+                let word_byte = self.width(operands)?;
+                bytes.push(OpCode::ImmediateToMem as u8 | word_byte as u8);
+                bytes.extend(self.modrm(0b000, rm, pass)?);
+                bytes.extend(immediate_bytes(self.evaluate(immediate, pass)?, word_byte));
+            }
+            // the accumulator has a shorter encoding for direct addresses
+            [AsmOperand::Register(register), AsmOperand::Memory(memory)]
+            | [AsmOperand::Memory(memory), AsmOperand::Register(register)]
+                if is_accumulator(*register) && memory.base.is_none() =>
+            {
+                let word_byte = self.width(operands)?;
+                let direction = match operands[0] {
+                    AsmOperand::Register(_) => 0b00,
+                    _ => 0b10,
+                };
+                bytes.push(OpCode::MovAccumulatorMem as u8 | direction | word_byte as u8);
+                let address = match &memory.displacement {
+                    Some(expression) => self.evaluate(expression, pass)?,
+                    None => 0,
+                };
+                bytes.extend(immediate_bytes(address, WordByte::Word));
+            }
+            _ => return self.encode_reg_rm(OpCode::MovMem as u8, operands, pass),
+        }
+
+        Ok(bytes)
+    }
+
+    /// Encodes the register / memory forms shared by mov and the arithmetic instructions.
+    /// Register to register uses the rm, reg form like nasm.
+    fn encode_reg_rm(
+        &self,
+        opcode: u8,
+        operands: &[&AsmOperand],
+        pass: &Pass,
+    ) -> Result<Vec<u8>, String> {
+        let word_byte = self.width(operands)?;
+        let (direction, register, rm) = match operands {
+            [rm, AsmOperand::Register(register)] => (0b00, *register, *rm),
+            [AsmOperand::Register(register), rm @ AsmOperand::Memory(_)] => (0b10, *register, *rm),
+            _ => return Err(self.invalid_operands()),
+        };
+
+        let mut bytes = vec![opcode | direction | word_byte as u8];
+        bytes.extend(self.modrm(register_field(register), rm, pass)?);
+        Ok(bytes)
+    }
+
+    fn encode_arithmetic(
+        &self,
+        arithmetic: ArithmeticOpCode,
+        operands: &[&AsmOperand],
+        pass: &Pass,
+    ) -> Result<Vec<u8>, String> {
+        let operation = arithmetic as u8;
+        let (rm, immediate) = match operands {
+            [rm, AsmOperand::Immediate(immediate)] => (*rm, self.evaluate(immediate, pass)?),
+            _ => return self.encode_reg_rm(operation << 3, operands, pass),
+        };
+
+        let word_byte = self.width(operands)?;
+        let mut bytes = vec![];
+        match (rm, word_byte) {
+            (AsmOperand::Register(Register::Al), _) => {
+                bytes.push((operation << 3) | 0b100);
+                bytes.extend(immediate_bytes(immediate, WordByte::Byte));
+            }
+            (_, WordByte::Word) if fits_signed_byte(immediate) => {
+                bytes.push(OpCode::ImmediateArithmetic as u8 | 0b11);
+                bytes.extend(self.modrm(operation, rm, pass)?);
+                bytes.extend(immediate_bytes(immediate, WordByte::Byte));
+            }
+            (AsmOperand::Register(Register::Ax), _) => {
+                bytes.push((operation << 3) | 0b101);
+                bytes.extend(immediate_bytes(immediate, WordByte::Word));
+            }
+            _ => {
+                bytes.push(OpCode::ImmediateArithmetic as u8 | word_byte as u8);
+                bytes.extend(self.modrm(operation, rm, pass)?);
+                bytes.extend(immediate_bytes(immediate, word_byte));
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    fn encode_test(&self, operands: &[&AsmOperand], pass: &Pass) -> Result<Vec<u8>, String> {
+        let word_byte = self.width(operands)?;
+        let mut bytes = vec![];
+        match operands {
+            [AsmOperand::Register(register), AsmOperand::Immediate(immediate)]
+                if is_accumulator(*register) =>
+            {
+                bytes.push(OpCode::TestImmediateToAccumulator as u8 | word_byte as u8);
+                bytes.extend(immediate_bytes(self.evaluate(immediate, pass)?, word_byte));
+            }
+            [rm, AsmOperand::Immediate(immediate)] => {
+                bytes.push(OpCode::Group3 as u8 | word_byte as u8);
+                bytes.extend(self.modrm(0b000, rm, pass)?);
+                bytes.extend(immediate_bytes(self.evaluate(immediate, pass)?, word_byte));
+            }
+            [rm, AsmOperand::Register(register)]
+            | [AsmOperand::Register(register), rm @ AsmOperand::Memory(_)] => {
+                bytes.push(OpCode::TestMemMem as u8 | word_byte as u8);
+                bytes.extend(self.modrm(register_field(*register), rm, pass)?);
+            }
+            _ => return Err(self.invalid_operands()),
+        }
+
+        Ok(bytes)
+    }
+
+    fn encode_xchg(&self, operands: &[&AsmOperand], pass: &Pass) -> Result<Vec<u8>, String> {
+        let word_byte = self.width(operands)?;
+        let mut bytes = vec![];
+        match operands {
+            [AsmOperand::Register(Register::Ax), AsmOperand::Register(register)]
+            | [AsmOperand::Register(register), AsmOperand::Register(Register::Ax)] => {
+                bytes.push(OpCode::XchgAccumulator as u8 | register_field(*register));
+            }
+            // like nasm, the first register operand goes in the reg field
+            [AsmOperand::Register(register), rm] | [rm, AsmOperand::Register(register)] => {
+                bytes.push(OpCode::XchgMemMem as u8 | word_byte as u8);
+                bytes.extend(self.modrm(register_field(*register), rm, pass)?);
+            }
+            _ => return Err(self.invalid_operands()),
+        }
+
+        Ok(bytes)
+    }
+
+    fn encode_inc_dec(
+        &self,
+        register_opcode: OpCode,
+        operation: u8,
+        operands: &[&AsmOperand],
+        pass: &Pass,
+    ) -> Result<Vec<u8>, String> {
+        match operands {
+            [AsmOperand::Register(register)] if get_register_width(*register) == WordByte::Word => {
+                Ok(vec![register_opcode as u8 | register_field(*register)])
+            }
+            [rm] => {
+                let word_byte = self.width(operands)?;
+                let mut bytes = vec![OpCode::Group5 as u8 | word_byte as u8];
+                bytes.extend(self.modrm(operation, rm, pass)?);
+                Ok(bytes)
+            }
+            _ => Err(self.invalid_operands()),
+        }
+    }
+
+    fn encode_push(&self, operands: &[&AsmOperand], pass: &Pass) -> Result<Vec<u8>, String> {
+        match operands {
+            [AsmOperand::Register(register)] if get_register_width(*register) == WordByte::Word => {
+                Ok(vec![OpCode::PushRegister as u8 | register_field(*register)])
+            }
+            [AsmOperand::SegmentRegister(segment_register)] => Ok(vec![
+                OpCode::PushSegment as u8 | ((*segment_register as u8) << 3),
+            ]),
+            [rm @ AsmOperand::Memory(memory)] if memory.size != Some(WordByte::Byte) => {
+                let mut bytes = vec![OpCode::Group5 as u8 | 1];
+                bytes.extend(self.modrm(0b110, rm, pass)?);
+                Ok(bytes)
+            }
+            _ => Err(self.invalid_operands()),
+        }
+    }
+
+    fn encode_pop(&self, operands: &[&AsmOperand], pass: &Pass) -> Result<Vec<u8>, String> {
+        match operands {
+            [AsmOperand::Register(register)] if get_register_width(*register) == WordByte::Word => {
+                Ok(vec![OpCode::PopRegister as u8 | register_field(*register)])
+            }
+            [AsmOperand::SegmentRegister(segment_register)]
+                if *segment_register != SegmentRegister::Cs =>
+            {
+                Ok(vec![
+                    OpCode::PopSegment as u8 | ((*segment_register as u8) << 3),
+                ])
+            }
+            [rm @ AsmOperand::Memory(memory)] if memory.size != Some(WordByte::Byte) => {
+                let mut bytes = vec![OpCode::PopMem as u8];
+                bytes.extend(self.modrm(0b000, rm, pass)?);
+                Ok(bytes)
+            }
+            _ => Err(self.invalid_operands()),
+        }
+    }
+
+    /// in and out. The accumulator is the destination of in and the source of out.
+    fn encode_port(
+        &self,
+        operands: &[&AsmOperand],
+        is_in: bool,
+        pass: &Pass,
+    ) -> Result<Vec<u8>, String> {
+        let (accumulator, port) = match (operands, is_in) {
+            ([accumulator, port], true) | ([port, accumulator], false) => (*accumulator, *port),
+            _ => return Err(self.invalid_operands()),
+        };
+        let word_byte = match accumulator {
+            AsmOperand::Register(register) if is_accumulator(*register) => {
+                get_register_width(*register)
+            }
+            _ => return Err(self.invalid_operands()),
+        };
+
+        let (fixed_opcode, variable_opcode) = if is_in {
+            (OpCode::InFixedPort, OpCode::InVariablePort)
+        } else {
+            (OpCode::OutFixedPort, OpCode::OutVariablePort)
+        };
+
+        match port {
+            AsmOperand::Immediate(port) => Ok(vec![
+                fixed_opcode as u8 | word_byte as u8,
+                self.evaluate(port, pass)? as u8,
+            ]),
+            AsmOperand::Register(Register::Dx) => Ok(vec![variable_opcode as u8 | word_byte as u8]),
+            _ => Err(self.invalid_operands()),
+        }
+    }
+
+    fn encode_load_address(
+        &self,
+        opcode: OpCode,
+        operands: &[&AsmOperand],
+        pass: &Pass,
+    ) -> Result<Vec<u8>, String> {
+        match operands {
+            [AsmOperand::Register(register), rm @ AsmOperand::Memory(_)]
+                if get_register_width(*register) == WordByte::Word =>
+            {
+                let mut bytes = vec![opcode as u8];
+                bytes.extend(self.modrm(register_field(*register), rm, pass)?);
+                Ok(bytes)
+            }
+            _ => Err(self.invalid_operands()),
+        }
+    }
+
+    fn encode_group_3(
+        &self,
+        operation: u8,
+        operands: &[&AsmOperand],
+        pass: &Pass,
+    ) -> Result<Vec<u8>, String> {
+        match operands {
+            [rm] => {
+                let word_byte = self.width(operands)?;
+                let mut bytes = vec![OpCode::Group3 as u8 | word_byte as u8];
+                bytes.extend(self.modrm(operation, rm, pass)?);
+                Ok(bytes)
+            }
+            _ => Err(self.invalid_operands()),
+        }
+    }
+
+    /// shifts and rotates by 1 or by cl, the only counts the 8086 supports
+    fn encode_shift_rotate(
+        &self,
+        operation: u8,
+        operands: &[&AsmOperand],
+        pass: &Pass,
+    ) -> Result<Vec<u8>, String> {
+        let (rm, variable_count) = match operands {
+            [rm, AsmOperand::Register(Register::Cl)] => (*rm, 0b10),
+            [rm, AsmOperand::Immediate(count)] if self.evaluate(count, pass)? == 1 => (*rm, 0b00),
+            _ => return Err(self.invalid_operands()),
+        };
+
+        let word_byte = self.width(&[rm])?;
+        let mut bytes = vec![OpCode::ShiftRotate as u8 | variable_count | word_byte as u8];
+        bytes.extend(self.modrm(operation, rm, pass)?);
+        Ok(bytes)
+    }
+
+    /// instructions followed by a byte immediate. default is used when no operand is given.
+    fn encode_byte_immediate(
+        &self,
+        opcode: OpCode,
+        default: Option<i64>,
+        operands: &[&AsmOperand],
+        pass: &Pass,
+    ) -> Result<Vec<u8>, String> {
+        let value = match (operands, default) {
+            ([AsmOperand::Immediate(immediate)], _) => self.evaluate(immediate, pass)?,
+            ([], Some(default)) => default,
+            _ => return Err(self.invalid_operands()),
+        };
+
+        Ok(vec![opcode as u8, value as u8])
+    }
+
+    fn encode_return(
+        &self,
+        opcode: OpCode,
+        immediate_opcode: OpCode,
+        operands: &[&AsmOperand],
+        pass: &Pass,
+    ) -> Result<Vec<u8>, String> {
+        match operands {
+            [] => Ok(vec![opcode as u8]),
+            [AsmOperand::Immediate(immediate)] => {
+                let mut bytes = vec![immediate_opcode as u8];
+                bytes.extend(immediate_bytes(
+                    self.evaluate(immediate, pass)?,
+                    WordByte::Word,
+                ));
+                Ok(bytes)
+            }
+            _ => Err(self.invalid_operands()),
+        }
+    }
+
+    /// the displacement from the end of an instruction of the given size to the target
+    fn relative_displacement(&self, target: i64, size: usize) -> i64 {
+        target - (self.operation_address + size) as i64
+    }
+
+    fn encode_call_jmp(&self, pass: &mut Pass) -> Result<Vec<u8>, String> {
+        let is_call = self.mnemonic == Mnemonic::Call;
+        let parsed_operand = match self.operands {
+            [parsed_operand] => parsed_operand,
+            _ => return Err(self.invalid_operands()),
+        };
+
+        match (&parsed_operand.operand, parsed_operand.branch_kind) {
+            (AsmOperand::FarPointer { segment, offset }, None | Some(BranchKind::Far)) => {
+                let opcode = if is_call {
+                    OpCode::CallFar
+                } else {
+                    OpCode::JmpFar
+                };
+                let mut bytes = vec![opcode as u8];
+                bytes.extend(immediate_bytes(
+                    self.evaluate(offset, pass)?,
+                    WordByte::Word,
+                ));
+                bytes.extend(immediate_bytes(
+                    self.evaluate(segment, pass)?,
+                    WordByte::Word,
+                ));
+                Ok(bytes)
+            }
+            (rm @ (AsmOperand::Register(_) | AsmOperand::Memory(_)), branch_kind) => {
+                let operation = match (is_call, branch_kind == Some(BranchKind::Far)) {
+                    (true, false) => 0b010,
+                    (true, true) => 0b011,
+                    (false, false) => 0b100,
+                    (false, true) => 0b101,
+                };
+                // far transfers load cs:ip from memory, so they cannot take a register
+                if let AsmOperand::Register(register) = rm {
+                    if get_register_width(*register) == WordByte::Byte || operation & 1 == 1 {
+                        return Err(self.invalid_operands());
+                    }
+                }
+
+                let mut bytes = vec![OpCode::Group5 as u8 | 1];
+                bytes.extend(self.modrm(operation, rm, pass)?);
+                Ok(bytes)
+            }
+            (AsmOperand::Immediate(target), branch_kind) => {
+                let target = self.evaluate(target, pass)?;
+                let short = match (is_call, branch_kind) {
+                    (true, None | Some(BranchKind::Near)) => false,
+                    (false, Some(BranchKind::Short)) => true,
+                    (false, Some(BranchKind::Near)) => false,
+                    // like nasm, an unsized jmp is short whenever the target is in range
+                    (false, None) => {
+                        if !pass.long_jumps.contains(&self.statement_index)
+                            && !fits_i8(self.relative_displacement(target, 2))
+                        {
+                            pass.long_jumps.insert(self.statement_index);
+                            pass.changed = true;
+                        }
+                        !pass.long_jumps.contains(&self.statement_index)
+                    }
+                    _ => return Err(self.invalid_operands()),
+                };
+
+                if short {
+                    let displacement = self.short_displacement(target, pass)?;
+                    Ok(vec![OpCode::JmpShort as u8, displacement])
+                } else {
+                    let opcode = if is_call {
+                        OpCode::CallNear
+                    } else {
+                        OpCode::JmpNear
+                    };
+                    let mut bytes = vec![opcode as u8];
+                    bytes.extend(immediate_bytes(
+                        self.relative_displacement(target, 3),
+                        WordByte::Word,
+                    ));
+                    Ok(bytes)
+                }
+            }
+            _ => Err(self.invalid_operands()),
+        }
+    }
+
+    /// the displacement byte of a two byte branch
+    fn short_displacement(&self, target: i64, pass: &Pass) -> Result<u8, String> {
+        let displacement = self.relative_displacement(target, 2);
+        if pass.final_pass && !fits_i8(displacement) {
+            let excess = if displacement > 0 {
+                displacement - i8::MAX as i64
+            } else {
+                i8::MIN as i64 - displacement
+            };
+            return Err(format!("short jump out of range by {} bytes", excess));
+        }
+
+        Ok(displacement as u8)
+    }
+
+    /// conditional jumps, loops and jcxz, which only have a short form on the 8086
+    fn encode_short_branch(&self, opcode: OpCode, pass: &Pass) -> Result<Vec<u8>, String> {
+        match self.operands {
+            [ParsedOperand {
+                operand: AsmOperand::Immediate(target),
+                branch_kind: None | Some(BranchKind::Short),
+            }] => {
+                let target = self.evaluate(target, pass)?;
+                Ok(vec![opcode as u8, self.short_displacement(target, pass)?])
+            }
+            _ => Err(self.invalid_operands()),
+        }
+    }
+}
+
+fn fits_i8(value: i64) -> bool {
+    (-128..=127).contains(&value)
+}
+
+/// encodes every statement once
+/// origin: the address of the first byte of machine code
+/// returns: the machine code and the address of every label
+fn run_pass(
+    statements: &[Statement],
+    origin: usize,
+    pass: &mut Pass,
+) -> Result<(Vec<u8>, HashMap<String, usize>), AssembleError> {
+    let mut machine_code = vec![];
+    let mut labels = HashMap::new();
+
+    for (statement_index, statement) in statements.iter().enumerate() {
+        let address = origin + machine_code.len();
+        if let Some(label) = &statement.label {
+            if labels.insert(label.clone(), address).is_some() {
+                return Err(AssembleError {
+                    line: statement.line,
+                    message: format!("label '{}' is defined more than once", label),
+                });
+            }
+        }
+
+        if let Some(body) = &statement.body {
+            let bytes =
+                encode_statement(body, address, statement_index, pass).map_err(|message| {
+                    AssembleError {
+                        line: statement.line,
+                        message,
+                    }
+                })?;
+            machine_code.extend(bytes);
+        }
+    }
+
+    Ok((machine_code, labels))
+}
+
+/// Assembles NASM-style 16-bit source into flat binary machine code
+/// source: the assembly source text
+/// returns: the machine code, or the first error found in the source
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    let mut statements = vec![];
+    for (line_index, line) in source.lines().enumerate() {
+        let statement = parse_line(line, line_index + 1).map_err(|message| AssembleError {
+            line: line_index + 1,
+            message,
+        })?;
+        statements.extend(statement);
+    }
+
+    // like nasm's flat binary output, one org applies to the whole source
+    let mut origins = statements
+        .iter()
+        .filter_map(|statement| match statement.body {
+            Some(Body::Origin(origin)) => Some((statement.line, origin)),
+            _ => None,
+        });
+    let origin = origins.next().map_or(0, |(_, origin)| origin);
+    if let Some((line, _)) = origins.next() {
+        return Err(AssembleError {
+            line,
+            message: "org can only be given once".to_owned(),
+        });
+    }
+
+    // branch sizes depend on label addresses and label addresses depend on branch sizes, so
+    // repeat until the addresses settle. Jumps only ever grow, so this terminates.
+    let mut labels = HashMap::new();
+    let mut long_jumps = HashSet::new();
+    for _ in 0..MAX_PASSES {
+        let mut pass = Pass {
+            labels: &labels,
+            long_jumps: &mut long_jumps,
+            final_pass: false,
+            changed: false,
+        };
+        let (_, pass_labels) = run_pass(&statements, origin, &mut pass)?;
+
+        if !pass.changed && pass_labels == labels {
+            let mut final_pass = Pass {
+                labels: &labels,
+                long_jumps: &mut long_jumps,
+                final_pass: true,
+                changed: false,
+            };
+            let (machine_code, _) = run_pass(&statements, origin, &mut final_pass)?;
+            return Ok(machine_code);
+        }
+        labels = pass_labels;
+    }
+
+    Err(AssembleError {
+        line: statements.last().map_or(0, |statement| statement.line),
+        message: "label addresses did not settle".to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// checks each line against the bytes nasm assembles it to
+    fn assert_assembles(cases: &[(&str, &[u8])]) {
+        for (source, expected) in cases {
+            let machine_code = assemble(&format!("bits 16\n{}\n", source))
+                .unwrap_or_else(|error| panic!("{}: {}", source, error));
+            assert_eq!(machine_code, *expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn mov() {
+        assert_assembles(&[
+            ("mov cx, bx", &[0x89, 0xD9]),
+            ("mov dh, al", &[0x88, 0xC6]),
+            ("mov cl, 12", &[0xB1, 0x0C]),
+            ("mov cx, -12", &[0xB9, 0xF4, 0xFF]),
+            ("mov dx, 3948", &[0xBA, 0x6C, 0x0F]),
+            ("mov al, [bx + si]", &[0x8A, 0x00]),
+            ("mov bx, [bp + di]", &[0x8B, 0x1B]),
+            ("mov dx, [bp]", &[0x8B, 0x56, 0x00]),
+            ("mov ah, [bx + si + 4]", &[0x8A, 0x60, 0x04]),
+            ("mov al, [bx + si + 4999]", &[0x8A, 0x80, 0x87, 0x13]),
+            ("mov ax, [bx + di - 37]", &[0x8B, 0x41, 0xDB]),
+            ("mov [bp], ch", &[0x88, 0x6E, 0x00]),
+            ("mov bp, [5]", &[0x8B, 0x2E, 0x05, 0x00]),
+            ("mov byte [bp + di], 7", &[0xC6, 0x03, 0x07]),
+            (
+                "mov word [di + 901], 347",
+                &[0xC7, 0x85, 0x85, 0x03, 0x5B, 0x01],
+            ),
+            ("mov ax, [2555]", &[0xA1, 0xFB, 0x09]),
+            ("mov [2554], ax", &[0xA3, 0xFA, 0x09]),
+            ("mov ds, ax", &[0x8E, 0xD8]),
+            ("mov ax, es", &[0x8C, 0xC0]),
+        ]);
+    }
+
+    #[test]
+    fn arithmetic_and_test() {
+        assert_assembles(&[
+            ("add bx, [bx + si]", &[0x03, 0x18]),
+            ("add bx, [bp]", &[0x03, 0x5E, 0x00]),
+            ("add si, 2", &[0x83, 0xC6, 0x02]),
+            ("add ax, 1000", &[0x05, 0xE8, 0x03]),
+            ("add al, -30", &[0x04, 0xE2]),
+            ("add byte [bx], 34", &[0x80, 0x07, 0x22]),
+            (
+                "add word [bp + si + 1000], 29",
+                &[0x83, 0x82, 0xE8, 0x03, 0x1D],
+            ),
+            ("sub bx, [bx + si]", &[0x2B, 0x18]),
+            ("cmp si, 2", &[0x83, 0xFE, 0x02]),
+            ("test al, 5", &[0xA8, 0x05]),
+            ("test bx, cx", &[0x85, 0xCB]),
+            ("test byte [bx], 1", &[0xF6, 0x07, 0x01]),
+        ]);
+    }
+
+    #[test]
+    fn single_operand_and_stack() {
+        assert_assembles(&[
+            ("xchg ax, bx", &[0x93]),
+            ("xchg [bx], cx", &[0x87, 0x0F]),
+            ("inc cx", &[0x41]),
+            ("dec byte [bx]", &[0xFE, 0x0F]),
+            ("inc word [si]", &[0xFF, 0x04]),
+            ("push cx", &[0x51]),
+            ("push ds", &[0x1E]),
+            ("push word [bx]", &[0xFF, 0x37]),
+            ("pop es", &[0x07]),
+            ("pop word [bx]", &[0x8F, 0x07]),
+            ("neg ax", &[0xF7, 0xD8]),
+            ("not dl", &[0xF6, 0xD2]),
+            ("mul byte [bx]", &[0xF6, 0x27]),
+            ("div cx", &[0xF7, 0xF1]),
+            ("shl ax, 1", &[0xD1, 0xE0]),
+            ("sar byte [bx], cl", &[0xD2, 0x3F]),
+            ("rol dx, cl", &[0xD3, 0xC2]),
+            ("lea bx, [bp + si + 1]", &[0x8D, 0x5A, 0x01]),
+            ("lds si, [bx]", &[0xC5, 0x37]),
+            ("les di, [bx]", &[0xC4, 0x3F]),
+        ]);
+    }
+
+    #[test]
+    fn ports_interrupts_and_single_bytes() {
+        assert_assembles(&[
+            ("in al, 200", &[0xE4, 0xC8]),
+            ("in ax, dx", &[0xED]),
+            ("out 44, ax", &[0xE7, 0x2C]),
+            ("out dx, al", &[0xEE]),
+            ("int 21h", &[0xCD, 0x21]),
+            ("aam", &[0xD4, 0x0A]),
+            ("aad", &[0xD5, 0x0A]),
+            ("xlat", &[0xD7]),
+            ("cbw", &[0x98]),
+            ("cwd", &[0x99]),
+            ("nop", &[0x90]),
+            ("cli", &[0xFA]),
+            ("movsw", &[0xA5]),
+            ("rep stosb", &[0xF3, 0xAA]),
+            ("mov al, [es:bx]", &[0x26, 0x8A, 0x07]),
+        ]);
+    }
+
+    #[test]
+    fn control_transfers() {
+        assert_assembles(&[
+            ("ret", &[0xC3]),
+            ("ret 4", &[0xC2, 0x04, 0x00]),
+            ("retf", &[0xCB]),
+            ("retf 2", &[0xCA, 0x02, 0x00]),
+            ("call [bx]", &[0xFF, 0x17]),
+            ("call far [bx]", &[0xFF, 0x1F]),
+            ("jmp ax", &[0xFF, 0xE0]),
+            ("jmp 1234h:5678h", &[0xEA, 0x78, 0x56, 0x34, 0x12]),
+            ("call 1234h:5678h", &[0x9A, 0x78, 0x56, 0x34, 0x12]),
+            ("call target\ntarget:", &[0xE8, 0x00, 0x00]),
+            ("jmp near target\ntarget:", &[0xE9, 0x00, 0x00]),
+            ("target: jmp target", &[0xEB, 0xFE]),
+            ("target: jne target", &[0x75, 0xFE]),
+            ("target: loop target", &[0xE2, 0xFE]),
+            ("target: jcxz target", &[0xE3, 0xFE]),
+        ]);
+    }
+
+    #[test]
+    fn data_and_org() {
+        assert_assembles(&[
+            ("db 1, 2", &[0x01, 0x02]),
+            ("dw 1234h", &[0x34, 0x12]),
+            ("db 'AB', 0", &[0x41, 0x42, 0x00]),
+            ("mov al, 'A'", &[0xB0, 0x41]),
+            ("org 100h\nstart: mov ax, start", &[0xB8, 0x00, 0x01]),
+        ]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = assemble("bits 16\nmov ax, bx\nmov cl, ax\n").unwrap_err();
+        assert_eq!(error.line, 3);
+        assert_eq!(assemble("bits 16\nfoo ax\n").unwrap_err().line, 2);
+    }
+}
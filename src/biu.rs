@@ -0,0 +1,221 @@
+/*
+Emulation of the bus interface unit, which fetches instruction bytes into a queue while the
+execution unit runs. Every bus cycle takes four clocks, T1 to T4, and either fetches instruction
+bytes or makes a data transfer for the execution unit. The timing tables assume the queue always
+holds the next instruction, so this accounts for the clocks they leave out: waiting for instruction
+bytes after a jump or a run of fast instructions, and data transfers held up by a fetch.
+ */
+
+use std::collections::VecDeque;
+
+use crate::clocks::{
+    estimate_clocks, memory_transfers, CpuModel, InstructionClocks, Timing, BUS_CYCLE_CLOCKS,
+};
+use crate::instruction::Instruction;
+use crate::simulator_state::SimulationState;
+
+/// The clocks an instruction took with the instruction queue emulated
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
+pub struct QueuedClocks {
+    /// the clocks from the timing tables
+    pub execute: u32,
+    /// the clocks the execution unit waited for instruction bytes to reach the queue
+    pub queue_wait: u32,
+    /// the clocks data transfers waited for an instruction fetch that was already on the bus
+    pub bus_wait: u32,
+}
+
+impl QueuedClocks {
+    pub fn total(&self) -> u32 {
+        self.execute + self.queue_wait + self.bus_wait
+    }
+
+    /// the parts of the total, like " (13 + 4q + 2b)", or nothing if there was no waiting
+    pub fn breakdown(&self) -> String {
+        if self.queue_wait == 0 && self.bus_wait == 0 {
+            return "".to_owned();
+        }
+
+        let mut breakdown = format!(" ({}", self.execute);
+        if self.queue_wait != 0 {
+            breakdown.push_str(&format!(" + {}q", self.queue_wait));
+        }
+        if self.bus_wait != 0 {
+            breakdown.push_str(&format!(" + {}b", self.bus_wait));
+        }
+        breakdown.push(')');
+        breakdown
+    }
+}
+
+/// The bus interface unit and its instruction queue
+pub struct BusInterfaceUnit {
+    cpu_model: CpuModel,
+    /// the clock each queued byte arrives at, oldest first
+    queue: VecDeque<u64>,
+    /// the offset in the code segment of the next byte to fetch
+    fetch_offset: u16,
+    /// the clock the execution unit has reached
+    clock: u64,
+    /// the clock the bus finishes its current cycle
+    bus_free: u64,
+}
+
+impl BusInterfaceUnit {
+    /// Starts with an empty queue, fetching from ip
+    pub fn new(cpu_model: CpuModel, ip: u16) -> Self {
+        BusInterfaceUnit {
+            cpu_model,
+            queue: VecDeque::with_capacity(cpu_model.queue_size()),
+            fetch_offset: ip,
+            clock: 0,
+            bus_free: 0,
+        }
+    }
+
+    /// the clocks elapsed since the first instruction started
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// the bytes the next fetch brings in. The 8086 fetches a word at an even address and a byte at
+    /// an odd one, and the 8088 always fetches a byte.
+    fn fetch_size(&self) -> usize {
+        match self.cpu_model {
+            CpuModel::I8086 if self.fetch_offset.is_multiple_of(2) => 2,
+            _ => 1,
+        }
+    }
+
+    /// whether the queue has room for the next fetch
+    fn has_room(&self) -> bool {
+        self.queue.len() + self.fetch_size() <= self.cpu_model.queue_size()
+    }
+
+    /// Runs one instruction fetch bus cycle, once the bus is free and no earlier than start
+    fn fetch(&mut self, start: u64) {
+        let ready = self.bus_free.max(start) + BUS_CYCLE_CLOCKS;
+        let fetch_size = self.fetch_size();
+        for _ in 0..fetch_size {
+            self.queue.push_back(ready);
+        }
+        self.fetch_offset = self.fetch_offset.wrapping_add(fetch_size as u16);
+        self.bus_free = ready;
+    }
+
+    /// Advances through an executed instruction: waits for its bytes to reach the queue, runs its
+    /// clocks from the timing tables while prefetching on the idle bus, then makes its data
+    /// transfers. A transfer of control flushes the queue.
+    /// before: the state before the instruction ran
+    /// after: the state after the instruction ran
+    /// transferred_control: whether the simulator took a jump, loop or interrupt
+    /// clocks: the instruction's clocks from the timing tables
+    pub fn execute(
+        &mut self,
+        instruction: &Instruction,
+        before: &SimulationState,
+        after: &SimulationState,
+        transferred_control: bool,
+        clocks: &InstructionClocks,
+    ) -> QueuedClocks {
+        // the execution unit can't start until every byte of the instruction has arrived
+        while self.queue.len() < instruction.size {
+            self.fetch(self.clock);
+        }
+        let ready = self
+            .queue
+            .drain(..instruction.size)
+            .next_back()
+            .unwrap_or(self.clock);
+        let queue_wait = ready.saturating_sub(self.clock);
+        self.clock += queue_wait;
+
+        let execute = clocks.total() as u64;
+        let data_cycles: u32 = memory_transfers(instruction, before, after, transferred_control)
+            .iter()
+            .map(|transfer| transfer.bus_cycles(self.cpu_model))
+            .sum();
+        let data_clocks = (data_cycles as u64 * BUS_CYCLE_CLOCKS).min(execute);
+
+        // the bus prefetches until the execution unit needs it for data. A fetch that has started
+        // runs to T4, so it can hold up the data transfers.
+        let transfers_start = self.clock + execute - data_clocks;
+        while self.has_room() && self.bus_free.max(self.clock) < transfers_start {
+            self.fetch(self.clock);
+        }
+
+        let bus_wait = if data_cycles == 0 {
+            0
+        } else {
+            let bus_wait = self.bus_free.saturating_sub(transfers_start);
+            self.bus_free = transfers_start + bus_wait + data_clocks;
+            bus_wait
+        };
+        self.clock += execute + bus_wait;
+
+        // fetched bytes past a jump are discarded, and fetching restarts at the new ip
+        if transferred_control {
+            self.queue.clear();
+            self.fetch_offset = after.ip;
+        }
+
+        QueuedClocks {
+            execute: execute as u32,
+            queue_wait: queue_wait as u32,
+            bus_wait: bus_wait as u32,
+        }
+    }
+}
+
+/// Estimates the clocks of each executed instruction, from the timing tables alone or with the
+/// instruction queue emulated
+pub struct InstructionTimer {
+    cpu_model: CpuModel,
+    bus_interface_unit: Option<BusInterfaceUnit>,
+}
+
+impl InstructionTimer {
+    /// ip: where the first instruction is fetched from
+    pub fn new(timing: Timing, ip: u16) -> Self {
+        InstructionTimer {
+            cpu_model: timing.cpu_model,
+            bus_interface_unit: timing
+                .emulate_prefetch
+                .then(|| BusInterfaceUnit::new(timing.cpu_model, ip)),
+        }
+    }
+
+    /// Estimates the clocks of an executed instruction
+    /// before: the state before the instruction ran
+    /// after: the state after the instruction ran
+    /// transferred_control: whether the simulator took a jump, loop or interrupt
+    /// returns: the clocks, and a breakdown of them to print after the total
+    pub fn time(
+        &mut self,
+        instruction: &Instruction,
+        before: &SimulationState,
+        after: &SimulationState,
+        transferred_control: bool,
+    ) -> (u32, String) {
+        let clocks = estimate_clocks(
+            instruction,
+            before,
+            after,
+            transferred_control,
+            self.cpu_model,
+        );
+        match &mut self.bus_interface_unit {
+            Some(bus_interface_unit) => {
+                let queued = bus_interface_unit.execute(
+                    instruction,
+                    before,
+                    after,
+                    transferred_control,
+                    &clocks,
+                );
+                (queued.total(), queued.breakdown())
+            }
+            None => (clocks.total(), clocks.breakdown()),
+        }
+    }
+}
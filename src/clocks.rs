@@ -0,0 +1,682 @@
+/*
+Clock estimates for executed instructions, from the 8086 instruction timing tables. Instructions
+whose timing depends on their operands, like mul and div, use the lowest clock count in their range.
+ */
+
+use std::fmt;
+
+use crate::common_assembly::{get_register_width, Register, WordByte};
+use crate::instruction::{
+    AddressBase, Displacement, EffectiveAddress, Instruction, Mnemonic, Operand,
+};
+use crate::simulate::effective_address;
+use crate::simulator_state::SimulationState;
+
+/// the extra clocks for each word transfer that takes two bus cycles: one to or from an odd address
+/// on the 8086, and every word on the 8088
+const WORD_TRANSFER_PENALTY: u32 = 4;
+
+/// the clocks a divide error adds to the divide to enter its handler, the same as an int
+const DIVIDE_ERROR_CLOCKS: u32 = 51;
+
+/// the clocks of one bus cycle, T1 to T4
+pub const BUS_CYCLE_CLOCKS: u64 = 4;
+
+/// The processor whose timing is estimated. The two run the same instructions, but the 8088 has an
+/// 8-bit data bus, so every word it transfers takes two bus cycles. The 8086 only needs two for
+/// words at odd addresses.
+#[derive(Default, PartialEq, Copy, Clone, Debug)]
+pub enum CpuModel {
+    #[default]
+    I8086,
+    I8088,
+}
+
+impl CpuModel {
+    /// the bytes in the instruction queue
+    pub fn queue_size(&self) -> usize {
+        match self {
+            CpuModel::I8086 => 6,
+            CpuModel::I8088 => 4,
+        }
+    }
+}
+
+/// How clocks are estimated
+/// cpu_model: the processor whose clocks are estimated
+/// emulate_prefetch: whether to model the bus interface unit and its instruction queue, rather than
+/// assuming every instruction is already in the queue as the timing tables do
+#[derive(Default, PartialEq, Copy, Clone, Debug)]
+pub struct Timing {
+    pub cpu_model: CpuModel,
+    pub emulate_prefetch: bool,
+}
+
+impl fmt::Display for CpuModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuModel::I8086 => write!(f, "8086"),
+            CpuModel::I8088 => write!(f, "8088"),
+        }
+    }
+}
+
+/// The clocks one executed instruction took
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
+pub struct InstructionClocks {
+    /// the clocks from the timing table, without effective address calculation
+    pub base: u32,
+    /// the clocks to calculate the address of a memory operand
+    pub effective_address: u32,
+    /// the extra clocks for word transfers that take two bus cycles
+    pub transfer_penalty: u32,
+}
+
+impl InstructionClocks {
+    pub fn total(&self) -> u32 {
+        self.base + self.effective_address + self.transfer_penalty
+    }
+
+    /// the parts of the total, like " (8 + 5ea + 4p)", or nothing if it is all base clocks
+    pub fn breakdown(&self) -> String {
+        if self.effective_address == 0 && self.transfer_penalty == 0 {
+            return "".to_owned();
+        }
+
+        let mut breakdown = format!(" ({}", self.base);
+        if self.effective_address != 0 {
+            breakdown.push_str(&format!(" + {}ea", self.effective_address));
+        }
+        if self.transfer_penalty != 0 {
+            breakdown.push_str(&format!(" + {}p", self.transfer_penalty));
+        }
+        breakdown.push(')');
+        breakdown
+    }
+}
+
+impl fmt::Display for InstructionClocks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "+{}{}", self.total(), self.breakdown())
+    }
+}
+
+/// Bytes or words an instruction moves over the bus to or from one place in memory
+/// offset: the offset of the first transfer. Only whether it is odd matters.
+/// count: how many transfers there are
+/// width: whether each transfer is a byte or a word
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MemoryTransfer {
+    pub offset: u16,
+    pub count: u32,
+    pub width: WordByte,
+}
+
+impl MemoryTransfer {
+    /// Whether each transfer takes two bus cycles: every word on the 8088, and words at odd
+    /// addresses on the 8086
+    pub fn is_split(&self, cpu_model: CpuModel) -> bool {
+        self.width == WordByte::Word && (cpu_model == CpuModel::I8088 || self.offset % 2 == 1)
+    }
+
+    /// the bus cycles all of the transfers take
+    pub fn bus_cycles(&self, cpu_model: CpuModel) -> u32 {
+        if self.is_split(cpu_model) {
+            self.count * 2
+        } else {
+            self.count
+        }
+    }
+}
+
+/// The memory operand of an instruction, if it has one
+fn memory_operand(instruction: &Instruction) -> Option<EffectiveAddress> {
+    instruction
+        .operands
+        .iter()
+        .find_map(|operand| match operand {
+            Operand::Memory(address) => Some(*address),
+            _ => None,
+        })
+}
+
+/// Whether a mov is the short accumulator form with a direct address, which calculates no
+/// effective address. The general form of the same mov takes 4 bytes, and 3 byte general forms
+/// address through a base register with a byte displacement.
+fn is_accumulator_direct_mov(instruction: &Instruction) -> bool {
+    let prefix_bytes = instruction.prefixes.lock as usize
+        + instruction.prefixes.repeat.is_some() as usize
+        + instruction.prefixes.segment.is_some() as usize;
+
+    let is_direct = |address: &EffectiveAddress| address.base.is_none();
+    instruction.opcode == Mnemonic::Mov
+        && instruction.size - prefix_bytes == 3
+        && match instruction.operands.as_slice() {
+            [Operand::Register(Register::Al | Register::Ax), Operand::Memory(address)]
+            | [Operand::Memory(address), Operand::Register(Register::Al | Register::Ax)] => {
+                is_direct(address)
+            }
+            _ => false,
+        }
+}
+
+/// The clocks to calculate the address of a memory operand, including a segment override
+pub fn effective_address_clocks(instruction: &Instruction, address: &EffectiveAddress) -> u32 {
+    let has_displacement = address.displacement != Displacement::None;
+    let clocks = match (address.base, has_displacement) {
+        (None, _) => 6,
+        (Some(AddressBase::Bx | AddressBase::Bp | AddressBase::Si | AddressBase::Di), false) => 5,
+        (Some(AddressBase::Bx | AddressBase::Bp | AddressBase::Si | AddressBase::Di), true) => 9,
+        (Some(AddressBase::BpDi | AddressBase::BxSi), false) => 7,
+        (Some(AddressBase::BpSi | AddressBase::BxDi), false) => 8,
+        (Some(AddressBase::BpDi | AddressBase::BxSi), true) => 11,
+        (Some(AddressBase::BpSi | AddressBase::BxDi), true) => 12,
+    };
+
+    let segment_override = match instruction.prefixes.segment {
+        Some(_) => 2,
+        None => 0,
+    };
+    clocks + segment_override
+}
+
+/// How many times a string instruction ran. A rep prefix runs it once per count in cx.
+fn repetitions(
+    instruction: &Instruction,
+    before: &SimulationState,
+    after: &SimulationState,
+) -> u32 {
+    match instruction.prefixes.repeat {
+        Some(_) => before.cx.wrapping_sub(after.cx) as u32,
+        None => 1,
+    }
+}
+
+/// The clocks from the timing table for an executed instruction, without effective address
+/// calculation or transfer penalties
+/// before: the state before the instruction ran
+/// after: the state after the instruction ran
+/// transferred_control: whether the simulator took a jump, loop or interrupt
+pub fn base_clocks(
+    instruction: &Instruction,
+    before: &SimulationState,
+    after: &SimulationState,
+    transferred_control: bool,
+) -> u32 {
+    use Operand::{Immediate, Memory, Register as Reg, SegmentRegister as Seg};
+
+    let operands = instruction.operands.as_slice();
+    let is_immediate = |operand: &Operand| {
+        matches!(
+            operand,
+            Operand::Immediate(_) | Operand::SignExtendedImmediate(_)
+        )
+    };
+    let on_memory = memory_operand(instruction).is_some();
+    let is_word = |operand: &Operand| match operand {
+        Reg(register) => get_register_width(*register) == WordByte::Word,
+        Memory(address) => address.width == WordByte::Word,
+        _ => true,
+    };
+    let taken = transferred_control;
+
+    match instruction.opcode {
+        Mnemonic::Mov => match operands {
+            _ if is_accumulator_direct_mov(instruction) => 10,
+            [Reg(_) | Seg(_), Reg(_) | Seg(_)] => 2,
+            [Reg(_) | Seg(_), Memory(_)] => 8,
+            [Memory(_), Reg(_) | Seg(_)] => 9,
+            [Reg(_), _] => 4,
+            _ => 10,
+        },
+        Mnemonic::Add
+        | Mnemonic::Adc
+        | Mnemonic::Sub
+        | Mnemonic::Sbb
+        | Mnemonic::And
+        | Mnemonic::Or
+        | Mnemonic::Xor => match operands {
+            [Reg(_), Reg(_)] => 3,
+            [Reg(_), Memory(_)] => 9,
+            [Memory(_), Reg(_)] => 16,
+            [Reg(_), _] => 4,
+            _ => 17,
+        },
+        Mnemonic::Cmp => match operands {
+            [Reg(_), Reg(_)] => 3,
+            [Reg(_), Memory(_)] | [Memory(_), Reg(_)] => 9,
+            [Reg(_), _] => 4,
+            _ => 10,
+        },
+        Mnemonic::Test => match operands {
+            [Reg(_), Reg(_)] => 3,
+            [Reg(_), Memory(_)] | [Memory(_), Reg(_)] => 9,
+            [Reg(Register::Al | Register::Ax), source] if is_immediate(source) => 4,
+            [Reg(_), _] => 5,
+            _ => 11,
+        },
+        Mnemonic::Xchg => match operands {
+            [Reg(Register::Ax), Reg(_)] | [Reg(_), Reg(Register::Ax)] => 3,
+            [Reg(_), Reg(_)] => 4,
+            _ => 17,
+        },
+        Mnemonic::Inc | Mnemonic::Dec => match operands {
+            [Memory(_)] => 15,
+            [operand] if is_word(operand) => 2,
+            _ => 3,
+        },
+        Mnemonic::Neg | Mnemonic::Not => {
+            if on_memory {
+                16
+            } else {
+                3
+            }
+        }
+        Mnemonic::Shl
+        | Mnemonic::Shr
+        | Mnemonic::Sar
+        | Mnemonic::Rol
+        | Mnemonic::Ror
+        | Mnemonic::Rcl
+        | Mnemonic::Rcr => match (operands, on_memory) {
+            ([_, Reg(Register::Cl)], false) => 8 + 4 * (before.cx & 0xFF) as u32,
+            ([_, Reg(Register::Cl)], true) => 20 + 4 * (before.cx & 0xFF) as u32,
+            (_, false) => 2,
+            (_, true) => 15,
+        },
+        Mnemonic::Mul | Mnemonic::Imul | Mnemonic::Div | Mnemonic::Idiv => {
+            let (byte_clocks, word_clocks) = match instruction.opcode {
+                Mnemonic::Mul => (70, 118),
+                Mnemonic::Imul => (80, 128),
+                Mnemonic::Div => (80, 144),
+                _ => (101, 165),
+            };
+            let clocks = if is_word(&operands[0]) {
+                word_clocks
+            } else {
+                byte_clocks
+            };
+            // the memory forms take 6 more clocks on top of the effective address
+            let memory_clocks = if on_memory { 6 } else { 0 };
+            let divide_error = if taken { DIVIDE_ERROR_CLOCKS } else { 0 };
+            clocks + memory_clocks + divide_error
+        }
+        Mnemonic::Lea => 2,
+        Mnemonic::Lds | Mnemonic::Les => 16,
+        Mnemonic::Push => match operands {
+            [Reg(_)] => 11,
+            [Seg(_)] => 10,
+            _ => 16,
+        },
+        Mnemonic::Pop => match operands {
+            [Memory(_)] => 17,
+            _ => 8,
+        },
+        Mnemonic::Pushf => 10,
+        Mnemonic::Popf => 8,
+        Mnemonic::Xlat => 11,
+        Mnemonic::Lahf | Mnemonic::Sahf => 4,
+        Mnemonic::Cbw => 2,
+        Mnemonic::Cwd => 5,
+        Mnemonic::Aaa | Mnemonic::Aas | Mnemonic::Daa | Mnemonic::Das => 4,
+        // a divide error enters its handler like an int
+        Mnemonic::Aam if taken => 83 + DIVIDE_ERROR_CLOCKS,
+        Mnemonic::Aam => 83,
+        Mnemonic::Aad => 60,
+        Mnemonic::In | Mnemonic::Out => match operands {
+            [_, Immediate(_)] | [Immediate(_), _] => 10,
+            _ => 8,
+        },
+        Mnemonic::Movsb
+        | Mnemonic::Movsw
+        | Mnemonic::Cmpsb
+        | Mnemonic::Cmpsw
+        | Mnemonic::Scasb
+        | Mnemonic::Scasw
+        | Mnemonic::Lodsb
+        | Mnemonic::Lodsw
+        | Mnemonic::Stosb
+        | Mnemonic::Stosw => {
+            // (without a prefix, per repetition with a prefix)
+            let (single, repeated) = match instruction.opcode {
+                Mnemonic::Movsb | Mnemonic::Movsw => (18, 17),
+                Mnemonic::Cmpsb | Mnemonic::Cmpsw => (22, 22),
+                Mnemonic::Scasb | Mnemonic::Scasw => (15, 15),
+                Mnemonic::Lodsb | Mnemonic::Lodsw => (12, 13),
+                _ => (11, 10),
+            };
+            match instruction.prefixes.repeat {
+                Some(_) => 9 + repeated * repetitions(instruction, before, after),
+                None => single,
+            }
+        }
+        Mnemonic::Call => match operands {
+            [Reg(_)] => 16,
+            [Memory(_)] => 21,
+            _ => 19,
+        },
+        Mnemonic::CallFar => {
+            if on_memory {
+                37
+            } else {
+                28
+            }
+        }
+        Mnemonic::Jmp => match operands {
+            [Reg(_)] => 11,
+            [Memory(_)] => 18,
+            _ => 15,
+        },
+        Mnemonic::JmpFar => {
+            if on_memory {
+                24
+            } else {
+                15
+            }
+        }
+        Mnemonic::Ret => match operands {
+            [] => 8,
+            _ => 12,
+        },
+        Mnemonic::Retf => match operands {
+            [] => 18,
+            _ => 17,
+        },
+        Mnemonic::JneJnz
+        | Mnemonic::Je
+        | Mnemonic::Jl
+        | Mnemonic::Jle
+        | Mnemonic::Jb
+        | Mnemonic::Jbe
+        | Mnemonic::Jp
+        | Mnemonic::Jo
+        | Mnemonic::Js
+        | Mnemonic::Jnl
+        | Mnemonic::Jg
+        | Mnemonic::Jnb
+        | Mnemonic::Ja
+        | Mnemonic::Jnp
+        | Mnemonic::Jno
+        | Mnemonic::Jns => {
+            if taken {
+                16
+            } else {
+                4
+            }
+        }
+        Mnemonic::Loop => {
+            if taken {
+                17
+            } else {
+                5
+            }
+        }
+        Mnemonic::Loopz | Mnemonic::Jcxz => {
+            if taken {
+                18
+            } else {
+                6
+            }
+        }
+        Mnemonic::Loopnz => {
+            if taken {
+                19
+            } else {
+                5
+            }
+        }
+        // an interrupt serviced by a native hook is charged the same, as if the hook were a handler
+        // entered through the interrupt vector table
+        Mnemonic::Int => 51,
+        Mnemonic::Int3 => 52,
+        Mnemonic::Into => {
+            if taken {
+                53
+            } else {
+                4
+            }
+        }
+        Mnemonic::Iret => 24,
+        Mnemonic::Clc
+        | Mnemonic::Cmc
+        | Mnemonic::Stc
+        | Mnemonic::Cld
+        | Mnemonic::Std
+        | Mnemonic::Cli
+        | Mnemonic::Sti
+        | Mnemonic::Hlt => 2,
+        Mnemonic::Wait | Mnemonic::Nop => 3,
+        Mnemonic::Esc => {
+            if on_memory {
+                8
+            } else {
+                2
+            }
+        }
+    }
+}
+
+/// The bytes and words an executed instruction transferred to or from memory, including the
+/// stack, string operands and the interrupt vector table
+/// before: the state before the instruction ran
+/// after: the state after the instruction ran
+/// transferred_control: whether the simulator took a jump, loop or interrupt
+pub fn memory_transfers(
+    instruction: &Instruction,
+    before: &SimulationState,
+    after: &SimulationState,
+    transferred_control: bool,
+) -> Vec<MemoryTransfer> {
+    let mut transfers = Vec::new();
+    let mut transfer = |offset: u16, count: u32, width: WordByte| {
+        if count != 0 {
+            transfers.push(MemoryTransfer {
+                offset,
+                count,
+                width,
+            });
+        }
+    };
+
+    if let Some(address) = memory_operand(instruction) {
+        // a memory destination is read, then written back
+        let destination_on_memory =
+            matches!(instruction.operands.first(), Some(Operand::Memory(_)));
+        let count = match instruction.opcode {
+            Mnemonic::Lea => 0,
+            Mnemonic::Esc => 0,
+            Mnemonic::Mov | Mnemonic::Cmp | Mnemonic::Test | Mnemonic::Push | Mnemonic::Pop => 1,
+            Mnemonic::Mul | Mnemonic::Imul | Mnemonic::Div | Mnemonic::Idiv => 1,
+            Mnemonic::Call | Mnemonic::Jmp => 1,
+            // a far pointer is an offset word followed by a segment word
+            Mnemonic::Lds | Mnemonic::Les | Mnemonic::CallFar | Mnemonic::JmpFar => 2,
+            _ if destination_on_memory => 2,
+            _ => 1,
+        };
+        let is_far_pointer = matches!(
+            instruction.opcode,
+            Mnemonic::Lds | Mnemonic::Les | Mnemonic::CallFar | Mnemonic::JmpFar
+        );
+        let width = if is_far_pointer {
+            WordByte::Word
+        } else {
+            address.width
+        };
+        transfer(effective_address(before, &address), count, width);
+    }
+
+    // stack words pushed or popped. Pushes write below sp, which has the same parity.
+    let stack_words = match instruction.opcode {
+        Mnemonic::Push | Mnemonic::Pop | Mnemonic::Pushf | Mnemonic::Popf => 1,
+        Mnemonic::Call | Mnemonic::Ret => 1,
+        Mnemonic::CallFar | Mnemonic::Retf => 2,
+        Mnemonic::Iret => 3,
+        Mnemonic::Int | Mnemonic::Int3 => 3,
+        Mnemonic::Into if transferred_control => 3,
+        // a divide error pushes the same words as an int
+        Mnemonic::Div | Mnemonic::Idiv | Mnemonic::Aam if transferred_control => 3,
+        _ => 0,
+    };
+    transfer(before.sp, stack_words, WordByte::Word);
+
+    // the handler's address is read from the interrupt vector table, which is word aligned
+    if stack_words == 3 && instruction.opcode != Mnemonic::Iret {
+        transfer(0, 2, WordByte::Word);
+    }
+
+    // xlat reads its table entry at bx + al
+    if instruction.opcode == Mnemonic::Xlat {
+        transfer(before.bx.wrapping_add(before.ax & 0xFF), 1, WordByte::Byte);
+    }
+
+    let repetitions = repetitions(instruction, before, after);
+    let string_width = match instruction.opcode {
+        Mnemonic::Movsb | Mnemonic::Cmpsb | Mnemonic::Scasb | Mnemonic::Lodsb | Mnemonic::Stosb => {
+            WordByte::Byte
+        }
+        _ => WordByte::Word,
+    };
+    match instruction.opcode {
+        Mnemonic::Movsb | Mnemonic::Movsw | Mnemonic::Cmpsb | Mnemonic::Cmpsw => {
+            transfer(before.si, repetitions, string_width);
+            transfer(before.di, repetitions, string_width);
+        }
+        Mnemonic::Lodsb | Mnemonic::Lodsw => transfer(before.si, repetitions, string_width),
+        Mnemonic::Scasb | Mnemonic::Scasw | Mnemonic::Stosb | Mnemonic::Stosw => {
+            transfer(before.di, repetitions, string_width)
+        }
+        _ => {}
+    }
+
+    transfers
+}
+
+/// Estimates the clocks an instruction took, from the states before and after it ran
+/// transferred_control: whether the simulator took a jump, loop or interrupt
+pub fn estimate_clocks(
+    instruction: &Instruction,
+    before: &SimulationState,
+    after: &SimulationState,
+    transferred_control: bool,
+    cpu_model: CpuModel,
+) -> InstructionClocks {
+    let effective_address = match memory_operand(instruction) {
+        Some(_) if is_accumulator_direct_mov(instruction) => 0,
+        Some(address) => effective_address_clocks(instruction, &address),
+        None => 0,
+    };
+    let split_transfers: u32 = memory_transfers(instruction, before, after, transferred_control)
+        .iter()
+        .filter(|transfer| transfer.is_split(cpu_model))
+        .map(|transfer| transfer.count)
+        .sum();
+
+    InstructionClocks {
+        base: base_clocks(instruction, before, after, transferred_control),
+        effective_address,
+        transfer_penalty: split_transfers * WORD_TRANSFER_PENALTY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::assemble;
+    use crate::decode::get_instruction;
+
+    fn decode(source: &str) -> Instruction {
+        let machine_code = assemble(&format!("bits 16\n{}\n", source)).unwrap();
+        get_instruction(&machine_code, 0).unwrap()
+    }
+
+    fn clocks(
+        source: &str,
+        before: &SimulationState,
+        after: &SimulationState,
+    ) -> InstructionClocks {
+        estimate_clocks(&decode(source), before, after, false, CpuModel::I8086)
+    }
+
+    #[test]
+    fn effective_address_clocks_per_addressing_mode() {
+        let cases = [
+            ("mov ax, [1000]", 6),
+            ("mov ax, [bx]", 5),
+            ("mov ax, [si]", 5),
+            ("mov ax, [di + 4]", 9),
+            ("mov ax, [bp + 300]", 9),
+            ("mov ax, [bp + di]", 7),
+            ("mov ax, [bx + si]", 7),
+            ("mov ax, [bp + si]", 8),
+            ("mov ax, [bx + di]", 8),
+            ("mov ax, [bp + di + 2]", 11),
+            ("mov ax, [bx + si + 300]", 11),
+            ("mov ax, [bp + si + 2]", 12),
+            ("mov ax, [bx + di - 300]", 12),
+            ("mov ax, [es:bx]", 7),
+        ];
+        for (source, expected) in cases {
+            let instruction = decode(source);
+            let address = memory_operand(&instruction).unwrap();
+            assert_eq!(
+                effective_address_clocks(&instruction, &address),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn word_transfers_to_odd_addresses_take_4_more_clocks() {
+        let even = SimulationState {
+            bx: 0x1000,
+            ..Default::default()
+        };
+        let odd = SimulationState {
+            bx: 0x1001,
+            ..Default::default()
+        };
+
+        // mov reg16, mem is 8 + ea
+        assert_eq!(clocks("mov ax, [bx]", &even, &even).total(), 13);
+        assert_eq!(clocks("mov ax, [bx]", &odd, &odd).total(), 17);
+        assert_eq!(clocks("mov ax, [bx]", &odd, &odd).transfer_penalty, 4);
+        // bytes never take a second bus cycle
+        assert_eq!(clocks("mov al, [bx]", &odd, &odd).total(), 13);
+        // add mem16, reg16 is 16 + ea and reads then writes the word
+        assert_eq!(clocks("add [bx], ax", &odd, &odd).total(), 16 + 5 + 8);
+
+        // every word transfer takes two bus cycles on the 8088
+        let instruction = decode("mov ax, [bx]");
+        let clocks_8088 = estimate_clocks(&instruction, &even, &even, false, CpuModel::I8088);
+        assert_eq!(clocks_8088.total(), 17);
+    }
+
+    #[test]
+    fn rep_movsw_takes_9_plus_17_per_repetition() {
+        let before = SimulationState {
+            cx: 5,
+            si: 0x100,
+            di: 0x200,
+            ..Default::default()
+        };
+        let after = SimulationState {
+            cx: 0,
+            si: 0x10A,
+            di: 0x20A,
+            ..Default::default()
+        };
+        assert_eq!(clocks("rep movsw", &before, &after).total(), 9 + 17 * 5);
+        assert_eq!(clocks("movsw", &before, &before).total(), 18);
+
+        // each repetition reads and writes a word, which costs 4 more clocks at an odd address
+        let odd_source = SimulationState {
+            si: 0x101,
+            ..before.clone()
+        };
+        let rep_movsw = clocks("rep movsw", &odd_source, &after);
+        assert_eq!(rep_movsw.transfer_penalty, 4 * 5);
+        assert_eq!(rep_movsw.total(), 9 + 17 * 5 + 4 * 5);
+    }
+}
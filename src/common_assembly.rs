@@ -1,23 +1,109 @@
+use std::fmt;
+
 #[repr(u8)]
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum OpCode {
     // 4 bit op codes - final 4 bits are irrelevant
     RegisterImmediateMov = 0b10110000,
 
+    // 5 bit op codes - final 3 bits are irrelevant
+    IncRegister = 0b01000000,
+    DecRegister = 0b01001000,
+    PushRegister = 0b01010000,
+    PopRegister = 0b01011000,
+    XchgAccumulator = 0b10010000,
+    Esc = 0b11011000,
+
     // 6 bit op codes - final 2 bits are irrelevant
     MovMem = 0b10001000,
     AddMemMem = 0b00000000,
+    OrMemMem = 0b00001000,
+    AdcMemMem = 0b00010000,
+    SbbMemMem = 0b00011000,
+    AndMemMem = 0b00100000,
     SubMemMem = 0b00101000,
+    XorMemMem = 0b00110000,
     CmpMemMem = 0b00111000,
     ImmediateArithmetic = 0b10000000,
+    MovAccumulatorMem = 0b10100000,
+    ShiftRotate = 0b11010000,
+
+    // 7 bit op codes
     ImmediateToAccumulator = 0b00000100,
+    OrImmediateToAccumulator = 0b00001100,
+    AdcImmediateToAccumulator = 0b00010100,
+    SbbImmediateFromAccumulator = 0b00011100,
+    AndImmediateToAccumulator = 0b00100100,
     ImmediateFromAccumulator = 0b00101100,
+    XorImmediateToAccumulator = 0b00110100,
     CmpImmediateToAccumulator = 0b00111100,
-
-    // 7 bit op codes
+    TestMemMem = 0b10000100,
+    XchgMemMem = 0b10000110,
+    Movs = 0b10100100,
+    Cmps = 0b10100110,
+    TestImmediateToAccumulator = 0b10101000,
+    Stos = 0b10101010,
+    Lods = 0b10101100,
+    Scas = 0b10101110,
     ImmediateToMem = 0b11000110,
+    InFixedPort = 0b11100100,
+    OutFixedPort = 0b11100110,
+    InVariablePort = 0b11101100,
+    OutVariablePort = 0b11101110,
+    Rep = 0b11110010,
+    // test, not, neg, mul, imul, div and idiv, selected by the reg field
+    Group3 = 0b11110110,
+    // inc, dec, call, jmp and push, selected by the reg field
+    Group5 = 0b11111110,
+
+    // segment register op codes - bits 3 and 4 select the segment register
+    PushSegment = 0b00000110,
+    PopSegment = 0b00000111,
+    SegmentOverride = 0b00100110,
 
     // 8 bit opcodes
+    Daa = 0b00100111,
+    Das = 0b00101111,
+    Aaa = 0b00110111,
+    Aas = 0b00111111,
+    MovFromSegment = 0b10001100,
+    Lea = 0b10001101,
+    MovToSegment = 0b10001110,
+    PopMem = 0b10001111,
+    Cbw = 0b10011000,
+    Cwd = 0b10011001,
+    CallFar = 0b10011010,
+    Wait = 0b10011011,
+    Pushf = 0b10011100,
+    Popf = 0b10011101,
+    Sahf = 0b10011110,
+    Lahf = 0b10011111,
+    RetImmediate = 0b11000010,
+    Ret = 0b11000011,
+    Les = 0b11000100,
+    Lds = 0b11000101,
+    RetfImmediate = 0b11001010,
+    Retf = 0b11001011,
+    Int3 = 0b11001100,
+    Int = 0b11001101,
+    Into = 0b11001110,
+    Iret = 0b11001111,
+    Aam = 0b11010100,
+    Aad = 0b11010101,
+    Xlat = 0b11010111,
+    CallNear = 0b11101000,
+    JmpNear = 0b11101001,
+    JmpFar = 0b11101010,
+    JmpShort = 0b11101011,
+    Lock = 0b11110000,
+    Hlt = 0b11110100,
+    Cmc = 0b11110101,
+    Clc = 0b11111000,
+    Stc = 0b11111001,
+    Cli = 0b11111010,
+    Sti = 0b11111011,
+    Cld = 0b11111100,
+    Std = 0b11111101,
     JneJnz = 0b01110101,
     Je = 0b01110100,
     Jl = 0b01111100,
@@ -40,86 +126,219 @@ pub enum OpCode {
     Jcxz = 0b11100011,
 }
 
-/// get the 6-bit op code from the first byte of an instruction
-/// byte: the byte containing the opcode
-/// returns: an OpCode enum type
-pub fn get_opcode(byte: u8) -> OpCode {
-    let first_four_bits = byte & 0b11110000;
-    if first_four_bits == (OpCode::RegisterImmediateMov as u8) {
-        return OpCode::RegisterImmediateMov;
-    }
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum DecodeErrorReason {
+    /// the byte is not a documented 8086 opcode
+    UnexpectedOpcode,
+    /// the reg field selects an operation that is not defined for the opcode
+    UnexpectedOperation,
+    /// the mod field selects a register for an instruction that requires a memory operand
+    UnexpectedRegisterMode,
+    /// the machine code ended before the instruction did
+    Truncated,
+}
 
-    let first_six_bits = byte & 0b11111100;
-    if first_six_bits == (OpCode::MovMem as u8) {
-        return OpCode::MovMem;
-    } else if first_six_bits == (OpCode::AddMemMem as u8) {
-        return OpCode::AddMemMem;
-    } else if first_six_bits == (OpCode::ImmediateArithmetic as u8) {
-        return OpCode::ImmediateArithmetic;
-    } else if first_six_bits == (OpCode::ImmediateToAccumulator as u8) {
-        return OpCode::ImmediateToAccumulator;
-    } else if first_six_bits == (OpCode::SubMemMem as u8) {
-        return OpCode::SubMemMem;
-    } else if first_six_bits == (OpCode::ImmediateFromAccumulator as u8) {
-        return OpCode::ImmediateFromAccumulator;
-    } else if first_six_bits == (OpCode::CmpMemMem as u8) {
-        return OpCode::CmpMemMem;
-    } else if first_six_bits == (OpCode::CmpImmediateToAccumulator as u8) {
-        return OpCode::CmpImmediateToAccumulator;
+impl fmt::Display for DecodeErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeErrorReason::UnexpectedOpcode => write!(f, "unexpected opcode"),
+            DecodeErrorReason::UnexpectedOperation => write!(f, "unexpected operation"),
+            DecodeErrorReason::UnexpectedRegisterMode => {
+                write!(f, "unexpected register operand")
+            }
+            DecodeErrorReason::Truncated => write!(f, "truncated instruction"),
+        }
     }
+}
 
-    let first_seven_bits = byte & 0b11111110;
-    if first_seven_bits == (OpCode::ImmediateToMem as u8) {
-        return OpCode::ImmediateToMem;
+/// An instruction that could not be decoded
+/// offset: the index of the first byte of the instruction
+/// bytes: the bytes of the instruction that were read before decoding failed
+/// reason: why decoding failed
+#[derive(PartialEq, Clone, Debug)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    pub reason: DecodeErrorReason,
+}
+
+impl DecodeError {
+    /// creates an error for the bytes from offset up to end. end is clamped to the machine code.
+    pub fn new(machine_code: &[u8], offset: usize, end: usize, reason: DecodeErrorReason) -> Self {
+        let offset = offset.min(machine_code.len());
+        let end = end.clamp(offset, machine_code.len());
+
+        Self {
+            offset,
+            bytes: machine_code[offset..end].to_vec(),
+            reason,
+        }
     }
+}
 
-    if byte == (OpCode::JneJnz as u8) {
-        OpCode::JneJnz
-    } else if byte == (OpCode::Je as u8) {
-        OpCode::Je
-    } else if byte == (OpCode::Jl as u8) {
-        OpCode::Jl
-    } else if byte == (OpCode::Jle as u8) {
-        OpCode::Jle
-    } else if byte == (OpCode::Jb as u8) {
-        OpCode::Jb
-    } else if byte == (OpCode::Jbe as u8) {
-        OpCode::Jbe
-    } else if byte == (OpCode::Jp as u8) {
-        OpCode::Jp
-    } else if byte == (OpCode::Jo as u8) {
-        OpCode::Jo
-    } else if byte == (OpCode::Js as u8) {
-        OpCode::Js
-    } else if byte == (OpCode::Jnl as u8) {
-        OpCode::Jnl
-    } else if byte == (OpCode::Jg as u8) {
-        OpCode::Jg
-    } else if byte == (OpCode::Jnb as u8) {
-        OpCode::Jnb
-    } else if byte == (OpCode::Ja as u8) {
-        OpCode::Ja
-    } else if byte == (OpCode::Jnp as u8) {
-        OpCode::Jnp
-    } else if byte == (OpCode::Jno as u8) {
-        OpCode::Jno
-    } else if byte == (OpCode::Jns as u8) {
-        OpCode::Jns
-    } else if byte == (OpCode::Loop as u8) {
-        OpCode::Loop
-    } else if byte == (OpCode::Loopz as u8) {
-        OpCode::Loopz
-    } else if byte == (OpCode::Loopnz as u8) {
-        OpCode::Loopnz
-    } else if byte == (OpCode::Jcxz as u8) {
-        OpCode::Jcxz
-    } else {
-        panic!("Unexpected opcode");
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        write!(
+            f,
+            "{} at offset {:#06x} (bytes: {})",
+            self.reason,
+            self.offset,
+            bytes.join(" ")
+        )
     }
 }
 
+impl std::error::Error for DecodeError {}
+
+/// get the op code from the first byte of an instruction. Only the bits that identify the
+/// opcode are considered, the remaining bits are left for the caller to decode.
+/// machine_code: the machine code vector
+/// index: the index of the byte containing the opcode
+/// returns: an OpCode enum type
+pub fn get_opcode(machine_code: &[u8], index: usize) -> Result<OpCode, DecodeError> {
+    let byte = match machine_code.get(index) {
+        Some(byte) => *byte,
+        None => {
+            return Err(DecodeError::new(
+                machine_code,
+                index,
+                index,
+                DecodeErrorReason::Truncated,
+            ))
+        }
+    };
+
+    let opcode = match byte {
+        0x00..=0x03 => OpCode::AddMemMem,
+        0x04..=0x05 => OpCode::ImmediateToAccumulator,
+        0x08..=0x0B => OpCode::OrMemMem,
+        0x0C..=0x0D => OpCode::OrImmediateToAccumulator,
+        0x10..=0x13 => OpCode::AdcMemMem,
+        0x14..=0x15 => OpCode::AdcImmediateToAccumulator,
+        0x18..=0x1B => OpCode::SbbMemMem,
+        0x1C..=0x1D => OpCode::SbbImmediateFromAccumulator,
+        0x20..=0x23 => OpCode::AndMemMem,
+        0x24..=0x25 => OpCode::AndImmediateToAccumulator,
+        0x28..=0x2B => OpCode::SubMemMem,
+        0x2C..=0x2D => OpCode::ImmediateFromAccumulator,
+        0x30..=0x33 => OpCode::XorMemMem,
+        0x34..=0x35 => OpCode::XorImmediateToAccumulator,
+        0x38..=0x3B => OpCode::CmpMemMem,
+        0x3C..=0x3D => OpCode::CmpImmediateToAccumulator,
+        // 0x0F would be pop cs, which is undocumented
+        0x06 | 0x0E | 0x16 | 0x1E => OpCode::PushSegment,
+        0x07 | 0x17 | 0x1F => OpCode::PopSegment,
+        0x26 | 0x2E | 0x36 | 0x3E => OpCode::SegmentOverride,
+        0x27 => OpCode::Daa,
+        0x2F => OpCode::Das,
+        0x37 => OpCode::Aaa,
+        0x3F => OpCode::Aas,
+        0x40..=0x47 => OpCode::IncRegister,
+        0x48..=0x4F => OpCode::DecRegister,
+        0x50..=0x57 => OpCode::PushRegister,
+        0x58..=0x5F => OpCode::PopRegister,
+        0x70 => OpCode::Jo,
+        0x71 => OpCode::Jno,
+        0x72 => OpCode::Jb,
+        0x73 => OpCode::Jnb,
+        0x74 => OpCode::Je,
+        0x75 => OpCode::JneJnz,
+        0x76 => OpCode::Jbe,
+        0x77 => OpCode::Ja,
+        0x78 => OpCode::Js,
+        0x79 => OpCode::Jns,
+        0x7A => OpCode::Jp,
+        0x7B => OpCode::Jnp,
+        0x7C => OpCode::Jl,
+        0x7D => OpCode::Jnl,
+        0x7E => OpCode::Jle,
+        0x7F => OpCode::Jg,
+        0x80..=0x83 => OpCode::ImmediateArithmetic,
+        0x84..=0x85 => OpCode::TestMemMem,
+        0x86..=0x87 => OpCode::XchgMemMem,
+        0x88..=0x8B => OpCode::MovMem,
+        0x8C => OpCode::MovFromSegment,
+        0x8D => OpCode::Lea,
+        0x8E => OpCode::MovToSegment,
+        0x8F => OpCode::PopMem,
+        0x90..=0x97 => OpCode::XchgAccumulator,
+        0x98 => OpCode::Cbw,
+        0x99 => OpCode::Cwd,
+        0x9A => OpCode::CallFar,
+        0x9B => OpCode::Wait,
+        0x9C => OpCode::Pushf,
+        0x9D => OpCode::Popf,
+        0x9E => OpCode::Sahf,
+        0x9F => OpCode::Lahf,
+        0xA0..=0xA3 => OpCode::MovAccumulatorMem,
+        0xA4..=0xA5 => OpCode::Movs,
+        0xA6..=0xA7 => OpCode::Cmps,
+        0xA8..=0xA9 => OpCode::TestImmediateToAccumulator,
+        0xAA..=0xAB => OpCode::Stos,
+        0xAC..=0xAD => OpCode::Lods,
+        0xAE..=0xAF => OpCode::Scas,
+        0xB0..=0xBF => OpCode::RegisterImmediateMov,
+        0xC2 => OpCode::RetImmediate,
+        0xC3 => OpCode::Ret,
+        0xC4 => OpCode::Les,
+        0xC5 => OpCode::Lds,
+        0xC6..=0xC7 => OpCode::ImmediateToMem,
+        0xCA => OpCode::RetfImmediate,
+        0xCB => OpCode::Retf,
+        0xCC => OpCode::Int3,
+        0xCD => OpCode::Int,
+        0xCE => OpCode::Into,
+        0xCF => OpCode::Iret,
+        0xD0..=0xD3 => OpCode::ShiftRotate,
+        0xD4 => OpCode::Aam,
+        0xD5 => OpCode::Aad,
+        0xD7 => OpCode::Xlat,
+        0xD8..=0xDF => OpCode::Esc,
+        0xE0 => OpCode::Loopnz,
+        0xE1 => OpCode::Loopz,
+        0xE2 => OpCode::Loop,
+        0xE3 => OpCode::Jcxz,
+        0xE4..=0xE5 => OpCode::InFixedPort,
+        0xE6..=0xE7 => OpCode::OutFixedPort,
+        0xE8 => OpCode::CallNear,
+        0xE9 => OpCode::JmpNear,
+        0xEA => OpCode::JmpFar,
+        0xEB => OpCode::JmpShort,
+        0xEC..=0xED => OpCode::InVariablePort,
+        0xEE..=0xEF => OpCode::OutVariablePort,
+        0xF0 => OpCode::Lock,
+        0xF2..=0xF3 => OpCode::Rep,
+        0xF4 => OpCode::Hlt,
+        0xF5 => OpCode::Cmc,
+        0xF6..=0xF7 => OpCode::Group3,
+        0xF8 => OpCode::Clc,
+        0xF9 => OpCode::Stc,
+        0xFA => OpCode::Cli,
+        0xFB => OpCode::Sti,
+        0xFC => OpCode::Cld,
+        0xFD => OpCode::Std,
+        0xFE..=0xFF => OpCode::Group5,
+        // 0x0F, 0x60-0x6F, 0xC0, 0xC1, 0xC8, 0xC9, 0xD6 and 0xF1 are undocumented on the 8086
+        _ => {
+            return Err(DecodeError::new(
+                machine_code,
+                index,
+                index + 1,
+                DecodeErrorReason::UnexpectedOpcode,
+            ))
+        }
+    };
+
+    Ok(opcode)
+}
+
 #[repr(u8)]
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Register {
     // Byte reg
     Al = 0b0000,
@@ -168,6 +387,24 @@ pub fn get_register_enum(register_field: u8, word_byte_field: WordByte) -> Regis
     TO_REGISTER[(register_field as usize) + (8 * (word_byte_field as usize))]
 }
 
+/// returns whether the register holds a byte or a word
+pub fn get_register_width(register: Register) -> WordByte {
+    if (register as u8) < 8 {
+        WordByte::Byte
+    } else {
+        WordByte::Word
+    }
+}
+
+/// looks up a register by its assembly name, like ax or cl
+pub fn parse_register(name: &str) -> Option<Register> {
+    let name = name.to_lowercase();
+    [WordByte::Byte, WordByte::Word]
+        .into_iter()
+        .flat_map(|word_byte| (0..8).map(move |field| get_register_enum(field, word_byte)))
+        .find(|register| register_to_assembly_name(*register) == name)
+}
+
 /// takes a byte and interprets the three least-significant bits as the register field
 /// byte: the byte to collect the data from
 /// word_byte_field: enum indicating the contents of the word/byte field
@@ -199,7 +436,7 @@ pub fn register_to_assembly_name(register: Register) -> String {
 }
 
 #[repr(u8)]
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum WordByte {
     Byte = 0b0,
     Word = 0b1,
@@ -218,7 +455,7 @@ impl From<u8> for WordByte {
 }
 
 #[repr(u8)]
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Direction {
     RegRm = 0b0,
     RmReg = 0b1,
@@ -237,7 +474,7 @@ impl From<u8> for Direction {
 }
 
 #[repr(u8)]
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Mode {
     MemNoDisplacement = 0b00,
     Mem8BitDisplacement = 0b01,
@@ -262,23 +499,69 @@ impl From<u8> for Mode {
 }
 
 #[repr(u8)]
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum ArithmeticOpCode {
     Add = 0b000,
+    Or = 0b001,
+    Adc = 0b010,
+    Sbb = 0b011,
+    And = 0b100,
     Sub = 0b101,
+    Xor = 0b110,
     Cmp = 0b111,
 }
 
 impl From<u8> for ArithmeticOpCode {
     fn from(value: u8) -> Self {
-        if value == 0b000 {
-            ArithmeticOpCode::Add
-        } else if value == 0b101 {
-            ArithmeticOpCode::Sub
-        } else if value == 0b111 {
-            ArithmeticOpCode::Cmp
-        } else {
-            panic!("Bad mode value")
+        match value {
+            0b000 => ArithmeticOpCode::Add,
+            0b001 => ArithmeticOpCode::Or,
+            0b010 => ArithmeticOpCode::Adc,
+            0b011 => ArithmeticOpCode::Sbb,
+            0b100 => ArithmeticOpCode::And,
+            0b101 => ArithmeticOpCode::Sub,
+            0b110 => ArithmeticOpCode::Xor,
+            0b111 => ArithmeticOpCode::Cmp,
+            _ => panic!("Bad arithmetic op code value"),
         }
     }
 }
+
+#[repr(u8)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum SegmentRegister {
+    Es = 0b00,
+    Cs = 0b01,
+    Ss = 0b10,
+    Ds = 0b11,
+}
+
+impl From<u8> for SegmentRegister {
+    fn from(value: u8) -> Self {
+        match value {
+            0b00 => SegmentRegister::Es,
+            0b01 => SegmentRegister::Cs,
+            0b10 => SegmentRegister::Ss,
+            0b11 => SegmentRegister::Ds,
+            _ => panic!("Bad segment register value"),
+        }
+    }
+}
+
+/// Takes a segment register enum and returns the string reflecting the name in assembly
+pub fn segment_register_to_assembly_name(segment_register: SegmentRegister) -> String {
+    match segment_register {
+        SegmentRegister::Es => "es".to_owned(),
+        SegmentRegister::Cs => "cs".to_owned(),
+        SegmentRegister::Ss => "ss".to_owned(),
+        SegmentRegister::Ds => "ds".to_owned(),
+    }
+}
+
+/// looks up a segment register by its assembly name, like ds
+pub fn parse_segment_register(name: &str) -> Option<SegmentRegister> {
+    let name = name.to_lowercase();
+    (0..4)
+        .map(SegmentRegister::from)
+        .find(|segment_register| segment_register_to_assembly_name(*segment_register) == name)
+}
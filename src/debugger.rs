@@ -0,0 +1,664 @@
+/*
+An interactive debugger for simulated programs. It reads one command per line, and stops running
+the program at breakpoints, when it halts, or when cs:ip leaves the program like the simulator does.
+Running also stops when a watched range of memory is accessed, or when a condition becomes true.
+Addresses, lengths and values are hexadecimal, and step counts are decimal.
+ */
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+use crate::common_assembly::parse_segment_register;
+use crate::expression::{parse_expression, Expression};
+use crate::instruction::Mnemonic;
+use crate::simulate::{
+    fetch_instruction, in_program, load_program, step, Executed, Execution, InterruptHooks,
+    SimulationConfig,
+};
+use crate::simulator_state::{
+    get_sim_state_diff, physical_address, AccessKind, MemoryAccess, SimMem, SimulationState,
+    MEMORY_SIZE,
+};
+
+/// the bytes examine shows when no length is given
+const DEFAULT_EXAMINE_LENGTH: u16 = 0x40;
+
+/// the bytes on each line of examine's output
+const EXAMINE_LINE_BYTES: u16 = 16;
+
+const HELP: &str = "\
+step [count]               s  execute count instructions, 1 by default
+next                       n  execute one instruction, running any call or interrupt to its return
+continue                   c  run until a breakpoint, a hlt or the end of the program
+break [address]            b  set a breakpoint at address, or list the breakpoints
+break [address] if expr    b  stop at address when expr is true, or anywhere once expr becomes true
+delete address|if expr     d  remove the breakpoint at address, or the condition expr
+watch address [length]        stop after the program writes to length bytes, 1 by default
+rwatch address [length]       stop after the program reads them
+awatch address [length]       stop after the program reads or writes them
+watch                         list the watchpoints
+unwatch address               remove the watchpoints starting at address
+registers                  r  print the registers and flags
+examine address [length]   x  print length bytes of memory, 40 by default
+set register value            set a register, or a flag like zf to 0 or 1
+set byte|word address value   write a byte or word to memory
+help                       h  print this help
+quit                       q  leave the debugger
+Addresses are segment:offset or an offset, and either part can be a register, like ss:sp. Breakpoints
+default to cs and memory to ds. Numbers are hexadecimal, except step counts. Expressions use register
+and flag names, numbers starting with a digit, parentheses, and the operators || && == != < <= > >= + -
+& | ^ ! like `cx == 0 && zf`.
+";
+
+/// What the debugger does after a command
+#[derive(PartialEq, Clone, Debug)]
+pub enum Response {
+    /// print the output and read another command
+    Output(String),
+    Quit,
+}
+
+/// An expression to stop on, with the text it was parsed from
+struct Condition {
+    expression: Expression,
+    text: String,
+    /// whether the expression was true after the last instruction, so running stops only when it
+    /// becomes true
+    was_true: bool,
+}
+
+/// A breakpoint at the segment:offset it was set at, which only stops when its condition is true
+struct Breakpoint {
+    segment: u16,
+    offset: u16,
+    condition: Option<Condition>,
+}
+
+/// A range of memory that stops running when the program accesses it
+/// kind: the access to stop on, or None to stop on reads and writes
+struct Watchpoint {
+    segment: u16,
+    offset: u16,
+    length: u16,
+    kind: Option<AccessKind>,
+}
+
+impl Watchpoint {
+    /// the offset in the watchpoint's segment of an access to its range, if it's a watched kind
+    fn watched_offset(&self, access: &MemoryAccess) -> Option<u16> {
+        let start = physical_address(self.segment, self.offset);
+        let distance = (access.address + MEMORY_SIZE - start) % MEMORY_SIZE;
+        let watched_kind = self.kind.is_none_or(|kind| kind == access.kind);
+        (watched_kind && distance < self.length as usize)
+            .then(|| self.offset.wrapping_add(distance as u16))
+    }
+}
+
+/// A program being debugged, with its breakpoints and watchpoints
+pub struct Debugger {
+    pub sim_state: SimulationState,
+    pub sim_mem: SimMem,
+    pub interrupt_hooks: InterruptHooks,
+    /// breakpoints by physical address
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    /// conditions checked after every instruction
+    conditions: Vec<Condition>,
+    watchpoints: Vec<Watchpoint>,
+    /// the memory accesses of the last instruction, recorded by the SimMem access hook
+    memory_accesses: Rc<RefCell<Vec<MemoryAccess>>>,
+    program_start: usize,
+    program_length: usize,
+    halted: bool,
+}
+
+impl Debugger {
+    /// Loads a program to debug like simulate_with_config
+    pub fn new(machine_code: &[u8], config: &SimulationConfig) -> Self {
+        let mut sim_state = SimulationState {
+            ..Default::default()
+        };
+        let mut sim_mem = SimMem::new(MEMORY_SIZE);
+        load_program(&mut sim_state, &mut sim_mem, machine_code, config);
+
+        let memory_accesses = Rc::new(RefCell::new(Vec::new()));
+        let hook_accesses = Rc::clone(&memory_accesses);
+        sim_mem.set_access_hook(Some(Box::new(move |access| {
+            hook_accesses.borrow_mut().push(access)
+        })));
+
+        Debugger {
+            sim_state,
+            sim_mem,
+            interrupt_hooks: InterruptHooks::new(),
+            breakpoints: BTreeMap::new(),
+            conditions: Vec::new(),
+            watchpoints: Vec::new(),
+            memory_accesses,
+            program_start: physical_address(config.load_segment, config.load_offset),
+            program_length: machine_code.len(),
+            halted: false,
+        }
+    }
+
+    /// Reads commands until quit or the end of the input, printing the output of each. An empty
+    /// line repeats the previous command.
+    pub fn run_repl(&mut self, input: impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "{}", self.location())?;
+        write!(output, "- ")?;
+        output.flush()?;
+
+        let mut previous_command = "".to_owned();
+        for line in input.lines() {
+            let line = line?;
+            let command = match line.trim() {
+                "" => previous_command.clone(),
+                command => command.to_owned(),
+            };
+
+            match self.execute_command(&command) {
+                Ok(Response::Output(text)) => write!(output, "{}", text)?,
+                Ok(Response::Quit) => return Ok(()),
+                Err(error) => writeln!(output, "Error: {}", error)?,
+            }
+            previous_command = command;
+
+            write!(output, "- ")?;
+            output.flush()?;
+        }
+
+        writeln!(output)
+    }
+
+    /// Runs one debugger command
+    /// returns: the output to print, or an error describing a bad command
+    pub fn execute_command(&mut self, command: &str) -> Result<Response, String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let Some((name, arguments)) = words.split_first() else {
+            return Ok(Response::Output("".to_owned()));
+        };
+
+        // a condition is the rest of a break or delete after if, and can contain spaces. Other
+        // commands can take if as an argument, like set if 1 for the interrupt flag.
+        let takes_condition =
+            matches!(name.to_lowercase().as_str(), "break" | "b" | "delete" | "d");
+        let condition_start = arguments
+            .iter()
+            .position(|word| takes_condition && word.eq_ignore_ascii_case("if"));
+        let (arguments, condition) = match condition_start {
+            Some(index) => (&arguments[..index], Some(arguments[index + 1..].join(" "))),
+            None => (arguments, None),
+        };
+
+        let output = match (name.to_lowercase().as_str(), arguments, condition) {
+            ("break" | "b", [], Some(text)) => {
+                let expression = parse_expression(&text)?;
+                let was_true = expression.is_true(&self.sim_state);
+                self.conditions.push(Condition {
+                    expression,
+                    text: text.clone(),
+                    was_true,
+                });
+                format!("Stopping when {} becomes true\n", text)
+            }
+            ("break" | "b", [address], Some(text)) => {
+                let expression = parse_expression(&text)?;
+                let (segment, offset) = self.parse_address(address, self.sim_state.cs)?;
+                let condition = Condition {
+                    expression,
+                    text: text.clone(),
+                    was_true: false,
+                };
+                self.add_breakpoint(segment, offset, Some(condition))
+            }
+            ("delete" | "d", [], Some(text)) => {
+                let expression = parse_expression(&text)?;
+                let count = self.conditions.len();
+                self.conditions
+                    .retain(|condition| condition.expression != expression);
+                if self.conditions.len() == count {
+                    return Err(format!("no condition {}", text));
+                }
+                "".to_owned()
+            }
+            ("quit" | "q", [], None) => return Ok(Response::Quit),
+            (_, _, Some(_)) => return Err(format!("unexpected arguments before if in {}", name)),
+            (name, arguments, None) => self.execute_unconditional(command, name, arguments)?,
+        };
+
+        Ok(Response::Output(output))
+    }
+
+    /// Runs a command that has no condition
+    /// name: the command name, in lowercase
+    fn execute_unconditional(
+        &mut self,
+        command: &str,
+        name: &str,
+        arguments: &[&str],
+    ) -> Result<String, String> {
+        let output = match (name, arguments) {
+            ("step" | "s", []) => self.step_count(1),
+            ("step" | "s", [count]) => {
+                let count = count
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid step count '{}'", count))?;
+                self.step_count(count)
+            }
+            ("next" | "n", []) => self.step_over(),
+            ("continue" | "c", []) => self.continue_running(),
+            ("break" | "b", []) => self.list_breakpoints(),
+            ("break" | "b", [address]) => {
+                let (segment, offset) = self.parse_address(address, self.sim_state.cs)?;
+                self.add_breakpoint(segment, offset, None)
+            }
+            ("delete" | "d", [address]) => {
+                let (segment, offset) = self.parse_address(address, self.sim_state.cs)?;
+                self.breakpoints
+                    .remove(&physical_address(segment, offset))
+                    .ok_or_else(|| format!("no breakpoint at {:04X}:{:04X}", segment, offset))?;
+                "".to_owned()
+            }
+            ("registers" | "r", []) => format!("{}\n", self.sim_state.pretty_string()),
+            ("examine" | "x", [address]) => self.examine(address, DEFAULT_EXAMINE_LENGTH)?,
+            ("examine" | "x", [address, length]) => self.examine(address, parse_hex(length)?)?,
+            ("set", [width @ ("byte" | "word"), address, value]) => {
+                self.set_memory(width, address, value)?
+            }
+            ("set", [name, value]) => self.set_register(name, value)?,
+            ("watch", []) => self.list_watchpoints(),
+            ("watch", [address]) => self.add_watchpoint(address, "1", Some(AccessKind::Write))?,
+            ("watch", [address, length]) => {
+                self.add_watchpoint(address, length, Some(AccessKind::Write))?
+            }
+            ("rwatch", [address]) => self.add_watchpoint(address, "1", Some(AccessKind::Read))?,
+            ("rwatch", [address, length]) => {
+                self.add_watchpoint(address, length, Some(AccessKind::Read))?
+            }
+            ("awatch", [address]) => self.add_watchpoint(address, "1", None)?,
+            ("awatch", [address, length]) => self.add_watchpoint(address, length, None)?,
+            ("unwatch", [address]) => {
+                let (segment, offset) = self.parse_address(address, self.sim_state.ds)?;
+                let start = physical_address(segment, offset);
+                let count = self.watchpoints.len();
+                self.watchpoints.retain(|watchpoint| {
+                    physical_address(watchpoint.segment, watchpoint.offset) != start
+                });
+                if self.watchpoints.len() == count {
+                    return Err(format!("no watchpoint at {:04X}:{:04X}", segment, offset));
+                }
+                "".to_owned()
+            }
+            ("help" | "h", []) => HELP.to_owned(),
+            _ => {
+                return Err(format!(
+                    "unknown command '{}', type help for a list",
+                    command.trim()
+                ))
+            }
+        };
+
+        Ok(output)
+    }
+
+    /// cs:ip and the instruction there
+    fn location(&self) -> String {
+        let address = format!("{:04X}:{:04X}", self.sim_state.cs, self.sim_state.ip);
+        match fetch_instruction(&self.sim_state, &self.sim_mem) {
+            Ok(instruction) => format!("{}  {}", address, instruction),
+            Err(error) => format!("{}  Decode error: {}", address, error),
+        }
+    }
+
+    /// Executes the instruction at cs:ip
+    /// output: the instruction and the state changes are appended here when trace is set, and the
+    /// reason the program stopped always is
+    /// returns: whether the program can keep running
+    fn execute(&mut self, output: &mut String, trace: bool) -> bool {
+        // memory changed by set commands isn't an access by the program, and a halted program
+        // makes no accesses, so watchpoints can't fire on stale ones
+        self.memory_accesses.borrow_mut().clear();
+        if self.halted {
+            output.push_str("The program has halted\n");
+            return false;
+        }
+
+        let previous_state = self.sim_state.clone();
+        match step(
+            &mut self.sim_state,
+            &mut self.sim_mem,
+            &mut self.interrupt_hooks,
+        ) {
+            Ok((
+                instruction,
+                Executed {
+                    execution: Execution::Unsupported,
+                    ..
+                },
+            )) => {
+                output.push_str(&format!("Unsupported instruction: {}\n", instruction));
+                false
+            }
+            Ok((instruction, Executed { execution, .. })) => {
+                if trace {
+                    let state_diff = get_sim_state_diff(&previous_state, &self.sim_state);
+                    output.push_str(&format!("{} ; {}", instruction, state_diff));
+                }
+                if execution == Execution::Halt {
+                    self.halted = true;
+                    output.push_str("Halted\n");
+                    return false;
+                }
+                true
+            }
+            Err(error) => {
+                output.push_str(&format!("Decode error: {}\n", error));
+                false
+            }
+        }
+    }
+
+    /// Whether running should stop after an instruction, adding every reason to output. The
+    /// instruction's memory accesses and the conditions are checked, then the breakpoints at cs:ip.
+    fn should_stop(&mut self, output: &mut String) -> bool {
+        let mut stop = false;
+
+        for access in self.memory_accesses.borrow_mut().drain(..) {
+            for watchpoint in &self.watchpoints {
+                if let Some(offset) = watchpoint.watched_offset(&access) {
+                    let kind = match access.kind {
+                        AccessKind::Read => "Read",
+                        AccessKind::Write => "Write",
+                    };
+                    output.push_str(&format!(
+                        "Watchpoint: {} of {:02X} at {:04X}:{:04X}\n",
+                        kind, access.value, watchpoint.segment, offset
+                    ));
+                    stop = true;
+                }
+            }
+        }
+
+        // every condition is evaluated, so each one tracks whether it has become true
+        for condition in &mut self.conditions {
+            let is_true = condition.expression.is_true(&self.sim_state);
+            if is_true && !condition.was_true {
+                output.push_str(&format!("Condition {} is true\n", condition.text));
+                stop = true;
+            }
+            condition.was_true = is_true;
+        }
+
+        let address = physical_address(self.sim_state.cs, self.sim_state.ip);
+        if let Some(breakpoint) = self.breakpoints.get(&address) {
+            let condition_met = breakpoint
+                .condition
+                .as_ref()
+                .is_none_or(|condition| condition.expression.is_true(&self.sim_state));
+            if condition_met {
+                output.push_str(&format!(
+                    "Breakpoint at {}\n",
+                    breakpoint_string(breakpoint)
+                ));
+                stop = true;
+            }
+        }
+
+        if !in_program(&self.sim_state, self.program_start, self.program_length) {
+            output.push_str("The program ended\n");
+            stop = true;
+        }
+        stop
+    }
+
+    /// Executes up to count instructions, printing each of them
+    fn step_count(&mut self, count: usize) -> String {
+        let mut output = "".to_owned();
+        for _ in 0..count {
+            if !self.execute(&mut output, true) || self.should_stop(&mut output) {
+                break;
+            }
+        }
+        output.push_str(&format!("{}\n", self.location()));
+        output
+    }
+
+    /// Executes one instruction. A call or interrupt runs until it returns to the next instruction
+    /// with the stack no deeper than before, so recursive calls don't stop it early.
+    fn step_over(&mut self) -> String {
+        let steps_into = fetch_instruction(&self.sim_state, &self.sim_mem)
+            .map(|instruction| {
+                let return_offset = self.sim_state.ip.wrapping_add(instruction.size as u16);
+                let steps_into = matches!(
+                    instruction.opcode,
+                    Mnemonic::Call
+                        | Mnemonic::CallFar
+                        | Mnemonic::Int
+                        | Mnemonic::Int3
+                        | Mnemonic::Into
+                );
+                (steps_into, return_offset)
+            })
+            .ok();
+
+        let Some((true, return_offset)) = steps_into else {
+            return self.step_count(1);
+        };
+
+        let (return_segment, stack_pointer) = (self.sim_state.cs, self.sim_state.sp);
+        let mut output = "".to_owned();
+        while self.execute(&mut output, false) {
+            let returned = self.sim_state.cs == return_segment
+                && self.sim_state.ip == return_offset
+                && self.sim_state.sp >= stack_pointer;
+            if self.should_stop(&mut output) || returned {
+                break;
+            }
+        }
+        output.push_str(&format!("{}\n", self.location()));
+        output
+    }
+
+    /// Runs until a breakpoint, a hlt or the end of the program. A breakpoint at cs:ip doesn't stop
+    /// the first instruction, so running can continue from one.
+    fn continue_running(&mut self) -> String {
+        let mut output = "".to_owned();
+        while self.execute(&mut output, false) && !self.should_stop(&mut output) {}
+        output.push_str(&format!("{}\n", self.location()));
+        output
+    }
+
+    /// Sets a breakpoint, replacing any other at the same physical address
+    fn add_breakpoint(
+        &mut self,
+        segment: u16,
+        offset: u16,
+        condition: Option<Condition>,
+    ) -> String {
+        let breakpoint = Breakpoint {
+            segment,
+            offset,
+            condition,
+        };
+        let output = format!("Breakpoint at {}\n", breakpoint_string(&breakpoint));
+        self.breakpoints
+            .insert(physical_address(segment, offset), breakpoint);
+        output
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() && self.conditions.is_empty() {
+            return "No breakpoints\n".to_owned();
+        }
+        let breakpoints = self
+            .breakpoints
+            .values()
+            .map(|breakpoint| format!("{}\n", breakpoint_string(breakpoint)));
+        let conditions = self
+            .conditions
+            .iter()
+            .map(|condition| format!("if {}\n", condition.text));
+        breakpoints.chain(conditions).collect()
+    }
+
+    /// Watches length bytes of memory from address
+    /// kind: the access to stop on, or None to stop on reads and writes
+    fn add_watchpoint(
+        &mut self,
+        address: &str,
+        length: &str,
+        kind: Option<AccessKind>,
+    ) -> Result<String, String> {
+        let (segment, offset) = self.parse_address(address, self.sim_state.ds)?;
+        let length = parse_hex(length)?;
+        if length == 0 {
+            return Err("a watchpoint needs at least one byte".to_owned());
+        }
+
+        let watchpoint = Watchpoint {
+            segment,
+            offset,
+            length,
+            kind,
+        };
+        let output = format!("Watchpoint on {}\n", watchpoint_string(&watchpoint));
+        self.watchpoints.push(watchpoint);
+        Ok(output)
+    }
+
+    fn list_watchpoints(&self) -> String {
+        if self.watchpoints.is_empty() {
+            return "No watchpoints\n".to_owned();
+        }
+        self.watchpoints
+            .iter()
+            .map(|watchpoint| format!("{}\n", watchpoint_string(watchpoint)))
+            .collect()
+    }
+
+    /// Prints memory as hexadecimal bytes, followed by the printable ones as text
+    /// length: the number of bytes to print
+    fn examine(&self, address: &str, length: u16) -> Result<String, String> {
+        let (segment, offset) = self.parse_address(address, self.sim_state.ds)?;
+
+        let mut output = "".to_owned();
+        for line_start in (0..length).step_by(EXAMINE_LINE_BYTES as usize) {
+            let line_offset = offset.wrapping_add(line_start);
+            let bytes: Vec<u8> = (0..EXAMINE_LINE_BYTES.min(length - line_start))
+                .map(|index| {
+                    self.sim_mem
+                        .peek_byte(physical_address(segment, line_offset.wrapping_add(index)))
+                })
+                .collect();
+
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let text: String = bytes
+                .iter()
+                .map(|byte| match byte {
+                    0x20..=0x7E => *byte as char,
+                    _ => '.',
+                })
+                .collect();
+            output.push_str(&format!(
+                "{:04X}:{:04X}  {:<47}  {}\n",
+                segment,
+                line_offset,
+                hex.join(" "),
+                text
+            ));
+        }
+
+        Ok(output)
+    }
+
+    /// Sets a register, or a flag to 0 or 1
+    fn set_register(&mut self, name: &str, value: &str) -> Result<String, String> {
+        let value = parse_hex(value)?;
+        if self.sim_state.get_flag(name).is_some() {
+            if value > 1 {
+                return Err(format!("{} can only be set to 0 or 1", name));
+            }
+            self.sim_state.set_flag(name, value == 1);
+        } else if !self.sim_state.set_named_register(name, value) {
+            return Err(format!("unknown register '{}'", name));
+        }
+        Ok("".to_owned())
+    }
+
+    /// Writes a little-endian byte or word to memory
+    /// width: byte or word
+    fn set_memory(&mut self, width: &str, address: &str, value: &str) -> Result<String, String> {
+        let (segment, offset) = self.parse_address(address, self.sim_state.ds)?;
+        let value = parse_hex(value)?;
+
+        let bytes = match width {
+            "byte" => {
+                let byte = u8::try_from(value)
+                    .map_err(|_| format!("{:X} doesn't fit in a byte", value))?;
+                vec![byte]
+            }
+            _ => value.to_le_bytes().to_vec(),
+        };
+        for (index, byte) in bytes.into_iter().enumerate() {
+            self.sim_mem.write_byte(
+                physical_address(segment, offset.wrapping_add(index as u16)),
+                byte,
+            );
+        }
+        Ok("".to_owned())
+    }
+
+    /// Parses segment:offset, or an offset in default_segment. Either part can be a register.
+    fn parse_address(&self, address: &str, default_segment: u16) -> Result<(u16, u16), String> {
+        let (segment, offset) = match address.split_once(':') {
+            Some((segment, offset)) => {
+                let segment = match parse_segment_register(segment) {
+                    Some(segment_register) => {
+                        self.sim_state.get_segment_register_value(segment_register)
+                    }
+                    None => parse_hex(segment)?,
+                };
+                (segment, offset)
+            }
+            None => (default_segment, address),
+        };
+
+        let offset = match self.sim_state.get_named_register(offset) {
+            Some(value) => value,
+            None => parse_hex(offset)?,
+        };
+        Ok((segment, offset))
+    }
+}
+
+/// a breakpoint's segment:offset, followed by its condition
+fn breakpoint_string(breakpoint: &Breakpoint) -> String {
+    let address = format!("{:04X}:{:04X}", breakpoint.segment, breakpoint.offset);
+    match &breakpoint.condition {
+        Some(condition) => format!("{} if {}", address, condition.text),
+        None => address,
+    }
+}
+
+/// a watchpoint's range and the accesses it stops on, like 0000:03E8 length 2 (write)
+fn watchpoint_string(watchpoint: &Watchpoint) -> String {
+    let kind = match watchpoint.kind {
+        Some(AccessKind::Read) => "read",
+        Some(AccessKind::Write) => "write",
+        None => "read or write",
+    };
+    format!(
+        "{:04X}:{:04X} length {:X} ({})",
+        watchpoint.segment, watchpoint.offset, watchpoint.length, kind
+    )
+}
+
+/// parses a hexadecimal number up to ffff, with or without a 0x prefix
+fn parse_hex(number: &str) -> Result<u16, String> {
+    let digits = number
+        .strip_prefix("0x")
+        .or_else(|| number.strip_prefix("0X"))
+        .unwrap_or(number);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid hexadecimal number '{}'", number))
+}
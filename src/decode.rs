@@ -0,0 +1,700 @@
+use crate::byte_operations::concat_bytes;
+use crate::common_assembly::{
+    get_opcode, get_register_enum, get_rm_register_field, ArithmeticOpCode, DecodeError,
+    DecodeErrorReason, Direction, Mode, OpCode, Register, SegmentRegister, WordByte,
+};
+use crate::instruction::{
+    Displacement, EffectiveAddress, Instruction, Mnemonic, Operand, Prefixes, Repeat,
+};
+
+type DecodeResult = Result<(Mnemonic, Vec<Operand>, usize), DecodeError>;
+
+/// Fetches the byte at index, failing if the machine code ends before it
+fn byte_at(machine_code: &[u8], index: usize) -> Result<u8, DecodeError> {
+    match machine_code.get(index) {
+        Some(byte) => Ok(*byte),
+        None => Err(DecodeError::new(
+            machine_code,
+            index,
+            index,
+            DecodeErrorReason::Truncated,
+        )),
+    }
+}
+
+/// Fetches the little-endian word starting at index
+fn word_at(machine_code: &[u8], index: usize) -> Result<u16, DecodeError> {
+    let low_byte = byte_at(machine_code, index)?;
+    let high_byte = byte_at(machine_code, index + 1)?;
+    Ok(concat_bytes(high_byte, low_byte))
+}
+
+/// Error for an opcode whose reg field selects an undefined operation
+/// index: the index of the opcode-containing byte
+fn unexpected_operation(machine_code: &[u8], index: usize) -> DecodeError {
+    DecodeError::new(
+        machine_code,
+        index,
+        index + 2,
+        DecodeErrorReason::UnexpectedOperation,
+    )
+}
+
+/// Error for an instruction that requires a memory operand but was given a register
+/// index: the index of the opcode-containing byte
+fn unexpected_register_mode(machine_code: &[u8], index: usize) -> DecodeError {
+    DecodeError::new(
+        machine_code,
+        index,
+        index + 2,
+        DecodeErrorReason::UnexpectedRegisterMode,
+    )
+}
+
+/// Decodes an instruction whose reg field names a register and whose rm field must name memory,
+/// like lea, les and lds
+fn reg_memory(opcode: Mnemonic, machine_code: &[u8], index: usize) -> DecodeResult {
+    let decoded = reg_rm(opcode, machine_code, index, WordByte::Word, true)?;
+    match decoded.1[1] {
+        Operand::Register(_) => Err(unexpected_register_mode(machine_code, index)),
+        _ => Ok(decoded),
+    }
+}
+
+/// Returns the operand described by the mod and rm fields of the byte after the opcode and the
+/// number of displacement bytes that follow that byte
+/// machine_code: the machine code vector
+/// index: The index of the opcode-containing byte
+/// word_byte: the word/byte field. The width of the register or memory operand
+pub fn rm_operand(
+    machine_code: &[u8],
+    index: usize,
+    word_byte: WordByte,
+) -> Result<(Operand, usize), DecodeError> {
+    let second_byte = byte_at(machine_code, index + 1)?;
+    let mode: Mode = ((second_byte & 0b11000000) >> 6).into();
+    let rm_field = second_byte & 0b00000111;
+
+    let (base, displacement, displacement_bytes) = match mode {
+        Mode::MemNoDisplacement => {
+            if rm_field == 0b110 {
+                // direct address
+                let displacement = word_at(machine_code, index + 2)?;
+                (None, Displacement::Word(displacement as i16), 2)
+            } else {
+                (Some(rm_field.into()), Displacement::None, 0)
+            }
+        }
+        Mode::Mem8BitDisplacement => {
+            let displacement = byte_at(machine_code, index + 2)? as i8;
+            (Some(rm_field.into()), Displacement::Byte(displacement), 1)
+        }
+        Mode::Mem16BitDisplacement => {
+            let displacement = word_at(machine_code, index + 2)?;
+            (
+                Some(rm_field.into()),
+                Displacement::Word(displacement as i16),
+                2,
+            )
+        }
+        Mode::Register => {
+            let register = get_rm_register_field(second_byte, word_byte);
+            return Ok((Operand::Register(register), 0));
+        }
+    };
+
+    Ok((
+        Operand::Memory(EffectiveAddress {
+            base,
+            displacement,
+            width: word_byte,
+        }),
+        displacement_bytes,
+    ))
+}
+
+/// Returns the register operand in the reg field of the byte after the opcode
+fn reg_operand(
+    machine_code: &[u8],
+    index: usize,
+    word_byte: WordByte,
+) -> Result<Operand, DecodeError> {
+    let register_field = (byte_at(machine_code, index + 1)? & 0b00111000) >> 3;
+    Ok(Operand::Register(get_register_enum(
+        register_field,
+        word_byte,
+    )))
+}
+
+/// Returns the reg field of the byte after the opcode, for opcodes that use it to select the
+/// operation
+fn reg_field(machine_code: &[u8], index: usize) -> Result<u8, DecodeError> {
+    Ok((byte_at(machine_code, index + 1)? & 0b00111000) >> 3)
+}
+
+/// get the segment register encoded in bits 3 and 4 of a byte
+fn get_segment_register(byte: u8) -> SegmentRegister {
+    ((byte & 0b00011000) >> 3).into()
+}
+
+/// get the accumulator register for the width of the instruction
+fn accumulator(word_byte: WordByte) -> Operand {
+    match word_byte {
+        WordByte::Byte => Operand::Register(Register::Al),
+        WordByte::Word => Operand::Register(Register::Ax),
+    }
+}
+
+/// Decodes any instruction with the form [opcode:6 d:1 w:1] [mod:2 reg:3 rm:3] [disp-lo] [disp-hi]
+fn mem_mem(opcode: Mnemonic, machine_code: &[u8], index: usize) -> DecodeResult {
+    let first_byte = byte_at(machine_code, index)?;
+
+    let direction: Direction = ((first_byte & 0b00000010) >> 1).into();
+    let word_byte: WordByte = (first_byte & 0b00000001).into();
+
+    let register = reg_operand(machine_code, index, word_byte)?;
+    let (rm, displacement_bytes) = rm_operand(machine_code, index, word_byte)?;
+
+    let operands = match direction {
+        Direction::RegRm => vec![rm, register],
+        Direction::RmReg => vec![register, rm],
+    };
+
+    Ok((opcode, operands, 2 + displacement_bytes))
+}
+
+/// Decodes instructions with the form [opcode:7 w:1] [mod:2 reg:3 rm:3] [disp-lo] [disp-hi] that
+/// have no direction bit
+/// word_byte: the width of the operands
+/// register_first: whether the reg field is the first operand
+fn reg_rm(
+    opcode: Mnemonic,
+    machine_code: &[u8],
+    index: usize,
+    word_byte: WordByte,
+    register_first: bool,
+) -> DecodeResult {
+    let register = reg_operand(machine_code, index, word_byte)?;
+    let (rm, displacement_bytes) = rm_operand(machine_code, index, word_byte)?;
+
+    let operands = if register_first {
+        vec![register, rm]
+    } else {
+        vec![rm, register]
+    };
+
+    Ok((opcode, operands, 2 + displacement_bytes))
+}
+
+/// common function for immediate to accumulator instructions
+fn accumulator_immediate(opcode: Mnemonic, machine_code: &[u8], index: usize) -> DecodeResult {
+    let word_byte: WordByte = (byte_at(machine_code, index)? & 0b00000001).into();
+    let (data, index_increment) = match word_byte {
+        WordByte::Byte => (byte_at(machine_code, index + 1)? as u16, 2),
+        WordByte::Word => (word_at(machine_code, index + 1)?, 3),
+    };
+
+    Ok((
+        opcode,
+        vec![accumulator(word_byte), Operand::Immediate(data)],
+        index_increment,
+    ))
+}
+
+/// Get the immediate at data_index and return both it and the number of bytes in the immediate value
+/// machine_code: the vector containing all of the machine code
+/// data_index: the index of the low byte of the immediate
+/// word_byte: the word/byte field enum
+/// sign_extension: the sign_extension field
+fn get_immediate(
+    machine_code: &[u8],
+    data_index: usize,
+    word_byte: WordByte,
+    sign_extension: u8,
+) -> Result<(Operand, usize), DecodeError> {
+    let low_byte = byte_at(machine_code, data_index)?;
+
+    let immediate = match word_byte {
+        WordByte::Byte => (Operand::Immediate(low_byte as u16), 1),
+        WordByte::Word => {
+            if sign_extension == 0 {
+                let immediate = word_at(machine_code, data_index)?;
+                (Operand::Immediate(immediate), 2)
+            } else {
+                (Operand::SignExtendedImmediate(low_byte as i8), 1)
+            }
+        }
+    };
+
+    Ok(immediate)
+}
+
+/// Decodes the instructions that take a register/memory destination and an immediate source
+/// (0x80 - 0x83, 0xC6 - 0xC7 and test in 0xF6 - 0xF7)
+fn immediate_to_rm(
+    opcode: Mnemonic,
+    machine_code: &[u8],
+    index: usize,
+    sign_extension: u8,
+) -> DecodeResult {
+    let word_byte: WordByte = (byte_at(machine_code, index)? & 0b00000001).into();
+    let (destination, displacement_bytes) = rm_operand(machine_code, index, word_byte)?;
+
+    let (immediate, data_bytes) = get_immediate(
+        machine_code,
+        index + 2 + displacement_bytes,
+        word_byte,
+        sign_extension,
+    )?;
+
+    Ok((
+        opcode,
+        vec![destination, immediate],
+        2 + displacement_bytes + data_bytes,
+    ))
+}
+
+/// Decodes a single register/memory operand instruction
+fn unary_rm(
+    opcode: Mnemonic,
+    machine_code: &[u8],
+    index: usize,
+    word_byte: WordByte,
+) -> DecodeResult {
+    let (operand, displacement_bytes) = rm_operand(machine_code, index, word_byte)?;
+
+    Ok((opcode, vec![operand], 2 + displacement_bytes))
+}
+
+/// Decodes the shift and rotate group
+fn shift_rotate(machine_code: &[u8], index: usize) -> DecodeResult {
+    let first_byte = byte_at(machine_code, index)?;
+    let word_byte: WordByte = (first_byte & 0b00000001).into();
+    let count = if (first_byte & 0b00000010) == 0 {
+        Operand::Immediate(1)
+    } else {
+        Operand::Register(Register::Cl)
+    };
+
+    let opcode = match reg_field(machine_code, index)? {
+        0b000 => Mnemonic::Rol,
+        0b001 => Mnemonic::Ror,
+        0b010 => Mnemonic::Rcl,
+        0b011 => Mnemonic::Rcr,
+        0b100 => Mnemonic::Shl,
+        0b101 => Mnemonic::Shr,
+        0b111 => Mnemonic::Sar,
+        _ => return Err(unexpected_operation(machine_code, index)),
+    };
+
+    let (operand, displacement_bytes) = rm_operand(machine_code, index, word_byte)?;
+
+    Ok((opcode, vec![operand, count], 2 + displacement_bytes))
+}
+
+/// Decodes test, not, neg, mul, imul, div and idiv
+fn group_3(machine_code: &[u8], index: usize) -> DecodeResult {
+    let word_byte: WordByte = (byte_at(machine_code, index)? & 0b00000001).into();
+
+    match reg_field(machine_code, index)? {
+        0b000 => immediate_to_rm(Mnemonic::Test, machine_code, index, 0),
+        0b010 => unary_rm(Mnemonic::Not, machine_code, index, word_byte),
+        0b011 => unary_rm(Mnemonic::Neg, machine_code, index, word_byte),
+        0b100 => unary_rm(Mnemonic::Mul, machine_code, index, word_byte),
+        0b101 => unary_rm(Mnemonic::Imul, machine_code, index, word_byte),
+        0b110 => unary_rm(Mnemonic::Div, machine_code, index, word_byte),
+        0b111 => unary_rm(Mnemonic::Idiv, machine_code, index, word_byte),
+        _ => Err(unexpected_operation(machine_code, index)),
+    }
+}
+
+/// Decodes inc, dec, call, jmp and push with a register/memory operand
+fn group_5(machine_code: &[u8], index: usize) -> DecodeResult {
+    let word_byte: WordByte = (byte_at(machine_code, index)? & 0b00000001).into();
+
+    let opcode = match (word_byte, reg_field(machine_code, index)?) {
+        (_, 0b000) => Mnemonic::Inc,
+        (_, 0b001) => Mnemonic::Dec,
+        (WordByte::Word, 0b010) => Mnemonic::Call,
+        (WordByte::Word, 0b011) => Mnemonic::CallFar,
+        (WordByte::Word, 0b100) => Mnemonic::Jmp,
+        (WordByte::Word, 0b101) => Mnemonic::JmpFar,
+        (WordByte::Word, 0b110) => Mnemonic::Push,
+        _ => return Err(unexpected_operation(machine_code, index)),
+    };
+
+    let decoded = unary_rm(opcode, machine_code, index, word_byte)?;
+    match (opcode, &decoded.1[0]) {
+        // far transfers read the segment from memory, so a register cannot hold the target
+        (Mnemonic::CallFar | Mnemonic::JmpFar, Operand::Register(_)) => {
+            Err(unexpected_register_mode(machine_code, index))
+        }
+        _ => Ok(decoded),
+    }
+}
+
+/// Decodes the string instructions, which have no operands
+fn string_instruction(
+    byte_opcode: Mnemonic,
+    word_opcode: Mnemonic,
+    machine_code: &[u8],
+    index: usize,
+) -> DecodeResult {
+    let word_byte: WordByte = (byte_at(machine_code, index)? & 0b00000001).into();
+    let opcode = match word_byte {
+        WordByte::Byte => byte_opcode,
+        WordByte::Word => word_opcode,
+    };
+
+    Ok((opcode, vec![], 1))
+}
+
+/// Decodes in and out
+/// fixed_port: whether the port is in the byte after the opcode, otherwise it is dx
+fn port_instruction(
+    opcode: Mnemonic,
+    machine_code: &[u8],
+    index: usize,
+    fixed_port: bool,
+) -> DecodeResult {
+    let word_byte: WordByte = (byte_at(machine_code, index)? & 0b00000001).into();
+
+    let (port, index_increment) = if fixed_port {
+        (
+            Operand::Immediate(byte_at(machine_code, index + 1)? as u16),
+            2,
+        )
+    } else {
+        (Operand::Register(Register::Dx), 1)
+    };
+
+    let operands = if opcode == Mnemonic::In {
+        vec![accumulator(word_byte), port]
+    } else {
+        vec![port, accumulator(word_byte)]
+    };
+
+    Ok((opcode, operands, index_increment))
+}
+
+/// Common function for jump opcodes with an 8 bit displacement
+fn jump_opcode(opcode: Mnemonic, machine_code: &[u8], index: usize) -> DecodeResult {
+    let signed_displacement = byte_at(machine_code, index + 1)? as i8;
+
+    Ok((opcode, vec![Operand::ShortRelative(signed_displacement)], 2))
+}
+
+/// Common function for near call and jump opcodes with a 16 bit displacement
+fn near_jump_opcode(opcode: Mnemonic, machine_code: &[u8], index: usize) -> DecodeResult {
+    let signed_displacement = word_at(machine_code, index + 1)? as i16;
+
+    Ok((opcode, vec![Operand::NearRelative(signed_displacement)], 3))
+}
+
+/// Common function for far call and jump opcodes with an immediate segment and offset
+fn far_jump_opcode(opcode: Mnemonic, machine_code: &[u8], index: usize) -> DecodeResult {
+    let offset = word_at(machine_code, index + 1)?;
+    let segment = word_at(machine_code, index + 3)?;
+
+    Ok((opcode, vec![Operand::FarAddress { segment, offset }], 5))
+}
+
+/// Common function for opcodes followed by a 16 bit immediate, like ret imm16
+fn word_immediate(opcode: Mnemonic, machine_code: &[u8], index: usize) -> DecodeResult {
+    let immediate = word_at(machine_code, index + 1)?;
+
+    Ok((opcode, vec![Operand::Immediate(immediate)], 3))
+}
+
+/// Common function for opcodes followed by an 8 bit immediate, like int imm8 and aam
+fn byte_immediate(opcode: Mnemonic, machine_code: &[u8], index: usize) -> DecodeResult {
+    let immediate = byte_at(machine_code, index + 1)? as u16;
+
+    Ok((opcode, vec![Operand::Immediate(immediate)], 2))
+}
+
+/// Decodes the coprocessor escape. The first operand holds the 6 bit escape code.
+fn esc_instruction(machine_code: &[u8], index: usize) -> DecodeResult {
+    let escape_code =
+        ((byte_at(machine_code, index)? & 0b00000111) << 3) | reg_field(machine_code, index)?;
+    let (operand, displacement_bytes) = rm_operand(machine_code, index, WordByte::Word)?;
+
+    Ok((
+        Mnemonic::Esc,
+        vec![Operand::Immediate(escape_code as u16), operand],
+        2 + displacement_bytes,
+    ))
+}
+
+/// Decodes the instruction whose opcode is at index, after any prefixes
+fn decode_operation(machine_code: &[u8], index: usize) -> DecodeResult {
+    let first_byte = byte_at(machine_code, index)?;
+    let opcode = get_opcode(machine_code, index)?;
+
+    match opcode {
+        OpCode::RegisterImmediateMov => {
+            let word_byte: WordByte = ((first_byte & 0b00001000) >> 3).into();
+            let register_field = first_byte & 0b00000111;
+            let register = get_register_enum(register_field, word_byte);
+
+            let (immediate, immediate_bytes) = match word_byte {
+                WordByte::Byte => (byte_at(machine_code, index + 1)? as u16, 1),
+                WordByte::Word => (word_at(machine_code, index + 1)?, 2),
+            };
+
+            // 1 byte for the opcode + the number of bytes in the immediate
+            Ok((
+                Mnemonic::Mov,
+                vec![Operand::Register(register), Operand::Immediate(immediate)],
+                immediate_bytes + 1,
+            ))
+        }
+        OpCode::ImmediateToMem => immediate_to_rm(Mnemonic::Mov, machine_code, index, 0),
+        OpCode::MovMem => mem_mem(Mnemonic::Mov, machine_code, index),
+        OpCode::AddMemMem => mem_mem(Mnemonic::Add, machine_code, index),
+        OpCode::OrMemMem => mem_mem(Mnemonic::Or, machine_code, index),
+        OpCode::AdcMemMem => mem_mem(Mnemonic::Adc, machine_code, index),
+        OpCode::SbbMemMem => mem_mem(Mnemonic::Sbb, machine_code, index),
+        OpCode::AndMemMem => mem_mem(Mnemonic::And, machine_code, index),
+        OpCode::SubMemMem => mem_mem(Mnemonic::Sub, machine_code, index),
+        OpCode::XorMemMem => mem_mem(Mnemonic::Xor, machine_code, index),
+        OpCode::CmpMemMem => mem_mem(Mnemonic::Cmp, machine_code, index),
+        OpCode::ImmediateArithmetic => {
+            let sign_extension = (first_byte & 0b00000010) >> 1;
+            let arithmetic_code: ArithmeticOpCode = reg_field(machine_code, index)?.into();
+            let opcode = match arithmetic_code {
+                ArithmeticOpCode::Add => Mnemonic::Add,
+                ArithmeticOpCode::Or => Mnemonic::Or,
+                ArithmeticOpCode::Adc => Mnemonic::Adc,
+                ArithmeticOpCode::Sbb => Mnemonic::Sbb,
+                ArithmeticOpCode::And => Mnemonic::And,
+                ArithmeticOpCode::Sub => Mnemonic::Sub,
+                ArithmeticOpCode::Xor => Mnemonic::Xor,
+                ArithmeticOpCode::Cmp => Mnemonic::Cmp,
+            };
+
+            immediate_to_rm(opcode, machine_code, index, sign_extension)
+        }
+        OpCode::ImmediateToAccumulator => accumulator_immediate(Mnemonic::Add, machine_code, index),
+        OpCode::OrImmediateToAccumulator => {
+            accumulator_immediate(Mnemonic::Or, machine_code, index)
+        }
+        OpCode::AdcImmediateToAccumulator => {
+            accumulator_immediate(Mnemonic::Adc, machine_code, index)
+        }
+        OpCode::SbbImmediateFromAccumulator => {
+            accumulator_immediate(Mnemonic::Sbb, machine_code, index)
+        }
+        OpCode::AndImmediateToAccumulator => {
+            accumulator_immediate(Mnemonic::And, machine_code, index)
+        }
+        OpCode::ImmediateFromAccumulator => {
+            accumulator_immediate(Mnemonic::Sub, machine_code, index)
+        }
+        OpCode::XorImmediateToAccumulator => {
+            accumulator_immediate(Mnemonic::Xor, machine_code, index)
+        }
+        OpCode::CmpImmediateToAccumulator => {
+            accumulator_immediate(Mnemonic::Cmp, machine_code, index)
+        }
+        OpCode::TestImmediateToAccumulator => {
+            accumulator_immediate(Mnemonic::Test, machine_code, index)
+        }
+        OpCode::TestMemMem => {
+            let word_byte: WordByte = (first_byte & 0b00000001).into();
+            reg_rm(Mnemonic::Test, machine_code, index, word_byte, false)
+        }
+        OpCode::XchgMemMem => {
+            let word_byte: WordByte = (first_byte & 0b00000001).into();
+            reg_rm(Mnemonic::Xchg, machine_code, index, word_byte, true)
+        }
+        OpCode::Lea => reg_memory(Mnemonic::Lea, machine_code, index),
+        OpCode::Les => reg_memory(Mnemonic::Les, machine_code, index),
+        OpCode::Lds => reg_memory(Mnemonic::Lds, machine_code, index),
+        OpCode::MovAccumulatorMem => {
+            let word_byte: WordByte = (first_byte & 0b00000001).into();
+            let address = word_at(machine_code, index + 1)?;
+            let memory = Operand::Memory(EffectiveAddress {
+                base: None,
+                displacement: Displacement::Word(address as i16),
+                width: word_byte,
+            });
+
+            let operands = if (first_byte & 0b00000010) == 0 {
+                vec![accumulator(word_byte), memory]
+            } else {
+                vec![memory, accumulator(word_byte)]
+            };
+
+            Ok((Mnemonic::Mov, operands, 3))
+        }
+        OpCode::MovToSegment | OpCode::MovFromSegment => {
+            let segment_register =
+                Operand::SegmentRegister(get_segment_register(byte_at(machine_code, index + 1)?));
+            let (rm, displacement_bytes) = rm_operand(machine_code, index, WordByte::Word)?;
+
+            let operands = if opcode == OpCode::MovToSegment {
+                vec![segment_register, rm]
+            } else {
+                vec![rm, segment_register]
+            };
+
+            Ok((Mnemonic::Mov, operands, 2 + displacement_bytes))
+        }
+        OpCode::IncRegister | OpCode::DecRegister | OpCode::PushRegister | OpCode::PopRegister => {
+            let opcode = match opcode {
+                OpCode::IncRegister => Mnemonic::Inc,
+                OpCode::DecRegister => Mnemonic::Dec,
+                OpCode::PushRegister => Mnemonic::Push,
+                _ => Mnemonic::Pop,
+            };
+            let register = get_rm_register_field(first_byte, WordByte::Word);
+
+            Ok((opcode, vec![Operand::Register(register)], 1))
+        }
+        OpCode::XchgAccumulator => {
+            let register = get_rm_register_field(first_byte, WordByte::Word);
+            if register == Register::Ax {
+                Ok((Mnemonic::Nop, vec![], 1))
+            } else {
+                Ok((
+                    Mnemonic::Xchg,
+                    vec![Operand::Register(Register::Ax), Operand::Register(register)],
+                    1,
+                ))
+            }
+        }
+        OpCode::PushSegment | OpCode::PopSegment => {
+            let opcode = if opcode == OpCode::PushSegment {
+                Mnemonic::Push
+            } else {
+                Mnemonic::Pop
+            };
+            let segment_register = get_segment_register(first_byte);
+
+            Ok((opcode, vec![Operand::SegmentRegister(segment_register)], 1))
+        }
+        OpCode::PopMem => unary_rm(Mnemonic::Pop, machine_code, index, WordByte::Word),
+        OpCode::ShiftRotate => shift_rotate(machine_code, index),
+        OpCode::Group3 => group_3(machine_code, index),
+        OpCode::Group5 => group_5(machine_code, index),
+        OpCode::Movs => string_instruction(Mnemonic::Movsb, Mnemonic::Movsw, machine_code, index),
+        OpCode::Cmps => string_instruction(Mnemonic::Cmpsb, Mnemonic::Cmpsw, machine_code, index),
+        OpCode::Stos => string_instruction(Mnemonic::Stosb, Mnemonic::Stosw, machine_code, index),
+        OpCode::Lods => string_instruction(Mnemonic::Lodsb, Mnemonic::Lodsw, machine_code, index),
+        OpCode::Scas => string_instruction(Mnemonic::Scasb, Mnemonic::Scasw, machine_code, index),
+        OpCode::Rep | OpCode::Lock | OpCode::SegmentOverride => {
+            unreachable!("prefixes are consumed by get_instruction")
+        }
+        OpCode::InFixedPort => port_instruction(Mnemonic::In, machine_code, index, true),
+        OpCode::OutFixedPort => port_instruction(Mnemonic::Out, machine_code, index, true),
+        OpCode::InVariablePort => port_instruction(Mnemonic::In, machine_code, index, false),
+        OpCode::OutVariablePort => port_instruction(Mnemonic::Out, machine_code, index, false),
+        OpCode::Int => byte_immediate(Mnemonic::Int, machine_code, index),
+        OpCode::Aam => byte_immediate(Mnemonic::Aam, machine_code, index),
+        OpCode::Aad => byte_immediate(Mnemonic::Aad, machine_code, index),
+        OpCode::CallNear => near_jump_opcode(Mnemonic::Call, machine_code, index),
+        OpCode::JmpNear => near_jump_opcode(Mnemonic::Jmp, machine_code, index),
+        OpCode::JmpShort => jump_opcode(Mnemonic::Jmp, machine_code, index),
+        OpCode::CallFar => far_jump_opcode(Mnemonic::CallFar, machine_code, index),
+        OpCode::JmpFar => far_jump_opcode(Mnemonic::JmpFar, machine_code, index),
+        OpCode::RetImmediate => word_immediate(Mnemonic::Ret, machine_code, index),
+        OpCode::RetfImmediate => word_immediate(Mnemonic::Retf, machine_code, index),
+        OpCode::Esc => esc_instruction(machine_code, index),
+        OpCode::Daa => Ok((Mnemonic::Daa, vec![], 1)),
+        OpCode::Das => Ok((Mnemonic::Das, vec![], 1)),
+        OpCode::Aaa => Ok((Mnemonic::Aaa, vec![], 1)),
+        OpCode::Aas => Ok((Mnemonic::Aas, vec![], 1)),
+        OpCode::Cbw => Ok((Mnemonic::Cbw, vec![], 1)),
+        OpCode::Cwd => Ok((Mnemonic::Cwd, vec![], 1)),
+        OpCode::Wait => Ok((Mnemonic::Wait, vec![], 1)),
+        OpCode::Pushf => Ok((Mnemonic::Pushf, vec![], 1)),
+        OpCode::Popf => Ok((Mnemonic::Popf, vec![], 1)),
+        OpCode::Sahf => Ok((Mnemonic::Sahf, vec![], 1)),
+        OpCode::Lahf => Ok((Mnemonic::Lahf, vec![], 1)),
+        OpCode::Ret => Ok((Mnemonic::Ret, vec![], 1)),
+        OpCode::Retf => Ok((Mnemonic::Retf, vec![], 1)),
+        OpCode::Int3 => Ok((Mnemonic::Int3, vec![], 1)),
+        OpCode::Into => Ok((Mnemonic::Into, vec![], 1)),
+        OpCode::Iret => Ok((Mnemonic::Iret, vec![], 1)),
+        OpCode::Xlat => Ok((Mnemonic::Xlat, vec![], 1)),
+        OpCode::Hlt => Ok((Mnemonic::Hlt, vec![], 1)),
+        OpCode::Cmc => Ok((Mnemonic::Cmc, vec![], 1)),
+        OpCode::Clc => Ok((Mnemonic::Clc, vec![], 1)),
+        OpCode::Stc => Ok((Mnemonic::Stc, vec![], 1)),
+        OpCode::Cli => Ok((Mnemonic::Cli, vec![], 1)),
+        OpCode::Sti => Ok((Mnemonic::Sti, vec![], 1)),
+        OpCode::Cld => Ok((Mnemonic::Cld, vec![], 1)),
+        OpCode::Std => Ok((Mnemonic::Std, vec![], 1)),
+        OpCode::JneJnz => jump_opcode(Mnemonic::JneJnz, machine_code, index),
+        OpCode::Je => jump_opcode(Mnemonic::Je, machine_code, index),
+        OpCode::Jl => jump_opcode(Mnemonic::Jl, machine_code, index),
+        OpCode::Jle => jump_opcode(Mnemonic::Jle, machine_code, index),
+        OpCode::Jb => jump_opcode(Mnemonic::Jb, machine_code, index),
+        OpCode::Jbe => jump_opcode(Mnemonic::Jbe, machine_code, index),
+        OpCode::Jp => jump_opcode(Mnemonic::Jp, machine_code, index),
+        OpCode::Jo => jump_opcode(Mnemonic::Jo, machine_code, index),
+        OpCode::Js => jump_opcode(Mnemonic::Js, machine_code, index),
+        OpCode::Jnl => jump_opcode(Mnemonic::Jnl, machine_code, index),
+        OpCode::Jg => jump_opcode(Mnemonic::Jg, machine_code, index),
+        OpCode::Jnb => jump_opcode(Mnemonic::Jnb, machine_code, index),
+        OpCode::Ja => jump_opcode(Mnemonic::Ja, machine_code, index),
+        OpCode::Jnp => jump_opcode(Mnemonic::Jnp, machine_code, index),
+        OpCode::Jno => jump_opcode(Mnemonic::Jno, machine_code, index),
+        OpCode::Jns => jump_opcode(Mnemonic::Jns, machine_code, index),
+        OpCode::Loop => jump_opcode(Mnemonic::Loop, machine_code, index),
+        OpCode::Loopz => jump_opcode(Mnemonic::Loopz, machine_code, index),
+        OpCode::Loopnz => jump_opcode(Mnemonic::Loopnz, machine_code, index),
+        OpCode::Jcxz => jump_opcode(Mnemonic::Jcxz, machine_code, index),
+    }
+}
+
+/// Decodes the instruction starting at index, including any prefixes
+/// machine_code: the machine code vector
+/// index: the index of the first byte of the instruction
+pub fn get_instruction(machine_code: &[u8], index: usize) -> Result<Instruction, DecodeError> {
+    let mut prefixes = Prefixes::default();
+    let mut opcode_index = index;
+
+    loop {
+        let byte = byte_at(machine_code, opcode_index)
+            .map_err(|error| extend_error(machine_code, error, index))?;
+        match get_opcode(machine_code, opcode_index)
+            .map_err(|error| extend_error(machine_code, error, index))?
+        {
+            OpCode::Lock => prefixes.lock = true,
+            OpCode::Rep => {
+                prefixes.repeat = if (byte & 0b00000001) == 0 {
+                    Some(Repeat::Repne)
+                } else {
+                    Some(Repeat::Rep)
+                };
+            }
+            OpCode::SegmentOverride => prefixes.segment = Some(get_segment_register(byte)),
+            _ => break,
+        }
+        opcode_index += 1;
+    }
+
+    let (opcode, operands, operation_size) = decode_operation(machine_code, opcode_index)
+        .map_err(|error| extend_error(machine_code, error, index))?;
+
+    Ok(Instruction {
+        opcode,
+        operands,
+        size: (opcode_index - index) + operation_size,
+        prefixes,
+    })
+}
+
+/// Widens an error raised partway through an instruction so that it covers the whole instruction
+/// error: the error raised while decoding part of the instruction
+/// index: the index of the first byte of the instruction, including prefixes
+fn extend_error(machine_code: &[u8], error: DecodeError, index: usize) -> DecodeError {
+    let end = match error.reason {
+        DecodeErrorReason::Truncated => usize::MAX,
+        _ => error.offset + error.bytes.len(),
+    };
+
+    DecodeError::new(machine_code, index, end, error.reason)
+}
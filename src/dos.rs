@@ -0,0 +1,638 @@
+/*
+Loading and running DOS .COM and .EXE programs. The program segment prefix is synthesized, and
+the int 20h and int 21h services that small programs rely on are implemented natively as interrupt
+hooks.
+ */
+
+use std::{cell::RefCell, collections::VecDeque, fmt, rc::Rc};
+
+use crate::clocks::Timing;
+use crate::common_assembly::WordByte;
+use crate::simulate::{
+    load_program, push_word, run_simulation, Execution, InterruptHooks, SimulationConfig,
+};
+use crate::simulator_state::{physical_address, SimMem, SimulationState, MEMORY_SIZE};
+
+/// the offset .COM programs are loaded at, just past the program segment prefix
+pub const COM_LOAD_OFFSET: u16 = 0x100;
+
+/// the segment the program segment prefix is placed in
+pub const PSP_SEGMENT: u16 = 0x1000;
+
+/// the first segment past the memory DOS gives the program, recorded in the program segment prefix
+const MEMORY_END_SEGMENT: u16 = 0xA000;
+
+/// the largest .COM program. The program and a word of stack have to fit in the segment after the
+/// program segment prefix.
+pub const MAX_COM_SIZE: usize = 0x10000 - COM_LOAD_OFFSET as usize - 2;
+
+/// the size of the fixed part of an MZ header
+const MZ_HEADER_SIZE: usize = 0x1C;
+
+/// the bytes of a paragraph, the unit of segments and of MZ header and allocation sizes
+const PARAGRAPH_SIZE: usize = 16;
+
+/// the bytes of a page, the unit of MZ file sizes
+const PAGE_SIZE: usize = 512;
+
+/// the interrupt that terminates a program
+const TERMINATE_INTERRUPT: u8 = 0x20;
+
+/// the interrupt for DOS function calls, selected by ah
+const DOS_FUNCTION_INTERRUPT: u8 = 0x21;
+
+/// what reading from an exhausted stdin buffer returns, as DOS does at the end of a redirected file
+const END_OF_FILE: u8 = 0x1A;
+
+/// The console a DOS program reads from and writes to, shared with the interrupt hooks
+#[derive(Default, Debug)]
+pub struct DosConsole {
+    /// the bytes left for the program to read
+    pub stdin: VecDeque<u8>,
+    /// everything the program has written
+    pub stdout: Vec<u8>,
+    /// the return code, once the program has terminated
+    pub exit_code: Option<u8>,
+    /// the value of ah for an int 21h function that isn't implemented, which stops the program
+    pub unsupported_function: Option<u8>,
+}
+
+/// The result of running a DOS program
+pub struct DosRun {
+    pub log: String,
+    pub stdout: Vec<u8>,
+    pub exit_code: Option<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DosError {
+    /// the program has more bytes than fit in its segment
+    ProgramTooLarge(usize),
+    /// the file is shorter than an MZ header
+    TruncatedHeader(usize),
+    /// the file doesn't start with MZ
+    BadSignature,
+    /// the header says the file is longer than it is
+    TruncatedImage { expected: usize, actual: usize },
+    /// the header paragraphs don't cover the fixed part of the header
+    HeaderTooSmall(usize),
+    /// the header paragraphs extend past the end of the file
+    HeaderTooLarge {
+        header_size: usize,
+        file_size: usize,
+    },
+    /// the relocation table extends past the end of the file
+    TruncatedRelocations,
+    /// a relocation points outside the load image
+    RelocationOutsideImage { segment: u16, offset: u16 },
+    /// the load image and its minimum allocation don't fit in memory at the load segment
+    ImageTooLarge(usize),
+}
+
+impl fmt::Display for DosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DosError::ProgramTooLarge(size) => write!(
+                f,
+                "a .COM program can be at most {} bytes, but this one is {} bytes",
+                MAX_COM_SIZE, size
+            ),
+            DosError::TruncatedHeader(size) => write!(
+                f,
+                "an MZ header is {} bytes, but the file is only {} bytes",
+                MZ_HEADER_SIZE, size
+            ),
+            DosError::BadSignature => write!(f, "the file does not start with an MZ signature"),
+            DosError::TruncatedImage { expected, actual } => write!(
+                f,
+                "the header gives a file size of {} bytes, but the file is {} bytes",
+                expected, actual
+            ),
+            DosError::HeaderTooSmall(header_size) => write!(
+                f,
+                "the header size is {} bytes, but an MZ header is at least {} bytes",
+                header_size, MZ_HEADER_SIZE
+            ),
+            DosError::HeaderTooLarge {
+                header_size,
+                file_size,
+            } => write!(
+                f,
+                "the header is {} bytes, which is more than the file size of {} bytes",
+                header_size, file_size
+            ),
+            DosError::TruncatedRelocations => {
+                write!(f, "the relocation table extends past the end of the file")
+            }
+            DosError::RelocationOutsideImage { segment, offset } => write!(
+                f,
+                "the relocation at {:04X}:{:04X} is outside the load image",
+                segment, offset
+            ),
+            DosError::ImageTooLarge(size) => write!(
+                f,
+                "the program needs {} bytes, which does not fit in memory",
+                size
+            ),
+        }
+    }
+}
+
+/// Writes the parts of a program segment prefix that programs commonly use
+/// psp_segment: the segment the program segment prefix starts at
+fn write_psp(sim_mem: &mut SimMem, psp_segment: u16) {
+    let write = |sim_mem: &mut SimMem, offset: u16, bytes: &[u8]| {
+        for (index, byte) in bytes.iter().enumerate() {
+            let address = physical_address(psp_segment, offset + index as u16);
+            sim_mem.write_byte(address, *byte);
+        }
+    };
+
+    for offset in 0..COM_LOAD_OFFSET {
+        write(sim_mem, offset, &[0]);
+    }
+    // int 20h, so returning to offset 0 terminates the program
+    write(sim_mem, 0x00, &[0xCD, TERMINATE_INTERRUPT]);
+    sim_mem.write_segmented(psp_segment, 0x02, WordByte::Word, MEMORY_END_SEGMENT);
+    // int 21h then retf, the far call entry point for DOS functions
+    write(sim_mem, 0x50, &[0xCD, DOS_FUNCTION_INTERRUPT, 0xCB]);
+    // an empty command tail, terminated by a carriage return
+    write(sim_mem, 0x80, &[0x00, 0x0D]);
+}
+
+/// Loads a .COM program at COM_LOAD_OFFSET after a program segment prefix, and sets the registers
+/// the way DOS does. Every segment register points at the program segment prefix and the stack
+/// starts at the top of the segment with a zero word pushed, so a near ret terminates the program.
+pub fn load_com(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    program: &[u8],
+) -> Result<(), DosError> {
+    if program.len() > MAX_COM_SIZE {
+        return Err(DosError::ProgramTooLarge(program.len()));
+    }
+
+    write_psp(sim_mem, PSP_SEGMENT);
+    let config = SimulationConfig {
+        load_segment: PSP_SEGMENT,
+        load_offset: COM_LOAD_OFFSET,
+        ..Default::default()
+    };
+    load_program(sim_state, sim_mem, program, &config);
+
+    sim_state.ds = PSP_SEGMENT;
+    sim_state.es = PSP_SEGMENT;
+    sim_state.ss = PSP_SEGMENT;
+    sim_state.sp = 0;
+    push_word(sim_state, sim_mem, 0);
+    sim_state.interrupt_flag = true;
+
+    Ok(())
+}
+
+/// The fields of an MZ header that loading uses
+struct MzHeader {
+    file_size: usize,
+    relocation_count: usize,
+    header_size: usize,
+    minimum_allocation: usize,
+    ss: u16,
+    sp: u16,
+    ip: u16,
+    cs: u16,
+    relocation_table: usize,
+}
+
+/// Whether a file is an .EXE program. DOS checks the signature rather than the file extension, and
+/// accepts it in either byte order.
+pub fn is_exe(program: &[u8]) -> bool {
+    program.starts_with(b"MZ") || program.starts_with(b"ZM")
+}
+
+/// Parses and checks an MZ header
+fn parse_mz_header(file: &[u8]) -> Result<MzHeader, DosError> {
+    if file.len() < MZ_HEADER_SIZE {
+        return Err(DosError::TruncatedHeader(file.len()));
+    }
+    if !is_exe(file) {
+        return Err(DosError::BadSignature);
+    }
+    let word = |offset: usize| u16::from_le_bytes([file[offset], file[offset + 1]]);
+
+    // the page count includes the last page, which may be partly used
+    let last_page_size = word(0x02) as usize;
+    let page_count = word(0x04) as usize;
+    let file_size = if last_page_size == 0 {
+        page_count * PAGE_SIZE
+    } else {
+        (page_count.saturating_sub(1)) * PAGE_SIZE + last_page_size
+    };
+    if file_size > file.len() {
+        return Err(DosError::TruncatedImage {
+            expected: file_size,
+            actual: file.len(),
+        });
+    }
+
+    let header_size = word(0x08) as usize * PARAGRAPH_SIZE;
+    if header_size < MZ_HEADER_SIZE {
+        return Err(DosError::HeaderTooSmall(header_size));
+    }
+    if header_size > file_size {
+        return Err(DosError::HeaderTooLarge {
+            header_size,
+            file_size,
+        });
+    }
+
+    let relocation_count = word(0x06) as usize;
+    let relocation_table = word(0x18) as usize;
+    if relocation_table + relocation_count * 4 > file.len() {
+        return Err(DosError::TruncatedRelocations);
+    }
+
+    Ok(MzHeader {
+        file_size,
+        relocation_count,
+        header_size,
+        minimum_allocation: word(0x0A) as usize * PARAGRAPH_SIZE,
+        ss: word(0x0E),
+        sp: word(0x10),
+        ip: word(0x14),
+        cs: word(0x16),
+        relocation_table,
+    })
+}
+
+/// Loads an .EXE program's image at a segment, with the program segment prefix in the 256 bytes
+/// before it. Every relocation has the load segment added to the word it points at, and cs:ip and
+/// ss:sp come from the header relative to the load segment. ds and es point at the program segment
+/// prefix, as DOS leaves them.
+/// returns: the length of the load image
+pub fn load_exe(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    file: &[u8],
+    load_segment: u16,
+) -> Result<usize, DosError> {
+    let header = parse_mz_header(file)?;
+    let image = &file[header.header_size..header.file_size];
+
+    let load_start = load_segment as usize * PARAGRAPH_SIZE;
+    let memory_end = MEMORY_END_SEGMENT as usize * PARAGRAPH_SIZE;
+    let required = image.len() + header.minimum_allocation;
+    if load_start + required > memory_end {
+        return Err(DosError::ImageTooLarge(required));
+    }
+
+    let psp_segment = load_segment.wrapping_sub((COM_LOAD_OFFSET as usize / PARAGRAPH_SIZE) as u16);
+    write_psp(sim_mem, psp_segment);
+    for (index, byte) in image.iter().enumerate() {
+        sim_mem.write_byte(load_start + index, *byte);
+    }
+
+    for relocation in 0..header.relocation_count {
+        let entry = header.relocation_table + relocation * 4;
+        let offset = u16::from_le_bytes([file[entry], file[entry + 1]]);
+        let segment = u16::from_le_bytes([file[entry + 2], file[entry + 3]]);
+        let image_offset = segment as usize * PARAGRAPH_SIZE + offset as usize;
+        if image_offset + 2 > image.len() {
+            return Err(DosError::RelocationOutsideImage { segment, offset });
+        }
+
+        let address = load_start + image_offset;
+        let value = sim_mem.read_word(address).wrapping_add(load_segment);
+        sim_mem.write_word(address, value);
+    }
+
+    sim_state.cs = load_segment.wrapping_add(header.cs);
+    sim_state.ip = header.ip;
+    sim_state.ss = load_segment.wrapping_add(header.ss);
+    sim_state.sp = header.sp;
+    sim_state.ds = psp_segment;
+    sim_state.es = psp_segment;
+    sim_state.interrupt_flag = true;
+
+    Ok(image.len())
+}
+
+/// Reads the next byte of stdin for the int 21h input functions
+fn read_stdin(console: &mut DosConsole) -> u8 {
+    console.stdin.pop_front().unwrap_or(END_OF_FILE)
+}
+
+/// Performs the int 21h function selected by ah
+fn dos_function(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    console: &mut DosConsole,
+) -> Execution {
+    let function = (sim_state.ax >> 8) as u8;
+    let set_al = |sim_state: &mut SimulationState, value: u8| {
+        sim_state.ax = (sim_state.ax & 0xFF00) | value as u16;
+    };
+
+    match function {
+        // terminate
+        0x00 => {
+            console.exit_code = Some(0);
+            return Execution::Halt;
+        }
+        // read a character with echo
+        0x01 => {
+            let character = read_stdin(console);
+            console.stdout.push(character);
+            set_al(sim_state, character);
+        }
+        // write the character in dl
+        0x02 => {
+            let character = sim_state.dx as u8;
+            console.stdout.push(character);
+            set_al(sim_state, character);
+        }
+        // read a character without echo
+        0x08 => {
+            let character = read_stdin(console);
+            set_al(sim_state, character);
+        }
+        // write the string at ds:dx, which is terminated by a '$'
+        0x09 => {
+            let mut offset = sim_state.dx;
+            for _ in 0..=u16::MAX {
+                let character = sim_mem.read_segmented(sim_state.ds, offset, WordByte::Byte) as u8;
+                if character == b'$' {
+                    break;
+                }
+                console.stdout.push(character);
+                offset = offset.wrapping_add(1);
+            }
+            set_al(sim_state, b'$');
+        }
+        // terminate with the return code in al
+        0x4C => {
+            console.exit_code = Some(sim_state.ax as u8);
+            return Execution::Halt;
+        }
+        _ => {
+            console.unsupported_function = Some(function);
+            return Execution::Halt;
+        }
+    }
+
+    Execution::Continue
+}
+
+/// Services int 20h and int 21h natively, with input and output going through console
+pub fn install_dos_hooks(interrupt_hooks: &mut InterruptHooks, console: &Rc<RefCell<DosConsole>>) {
+    let terminate_console = Rc::clone(console);
+    interrupt_hooks.insert(
+        TERMINATE_INTERRUPT,
+        Box::new(move |_, _| {
+            terminate_console.borrow_mut().exit_code = Some(0);
+            Execution::Halt
+        }),
+    );
+
+    let function_console = Rc::clone(console);
+    interrupt_hooks.insert(
+        DOS_FUNCTION_INTERRUPT,
+        Box::new(move |sim_state, sim_mem| {
+            dos_function(sim_state, sim_mem, &mut function_console.borrow_mut())
+        }),
+    );
+}
+
+/// Runs a DOS program until it terminates, halts or leaves the given range of memory
+/// timing: how the clocks of each instruction are estimated
+/// program_start: the physical address of the start of the range
+/// program_length: the number of bytes in the range
+fn run_dos_program(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    stdin: &[u8],
+    timing: Timing,
+    program_start: usize,
+    program_length: usize,
+) -> DosRun {
+    let console = Rc::new(RefCell::new(DosConsole {
+        stdin: stdin.iter().copied().collect(),
+        ..Default::default()
+    }));
+    let mut interrupt_hooks = InterruptHooks::new();
+    install_dos_hooks(&mut interrupt_hooks, &console);
+
+    let mut log = run_simulation(
+        sim_state,
+        sim_mem,
+        &mut interrupt_hooks,
+        timing,
+        program_start,
+        program_length,
+    );
+
+    let console = console.take();
+    if let Some(function) = console.unsupported_function {
+        log.push_str(&format!("Unsupported int 21h function {:#04x}\n", function));
+    }
+    log.push_str("Final registers:\n");
+    log.push_str(&format!("{}\n", sim_state.pretty_string()));
+
+    DosRun {
+        log,
+        stdout: console.stdout,
+        exit_code: console.exit_code,
+    }
+}
+
+/// Runs a .COM program until it terminates, halts or leaves its segment
+/// stdin: the bytes the program reads from the console
+/// timing: how the clocks of each instruction are estimated
+pub fn simulate_com(program: &[u8], stdin: &[u8], timing: Timing) -> Result<DosRun, DosError> {
+    let mut sim_state = SimulationState {
+        ..Default::default()
+    };
+    let mut sim_mem = SimMem::new(MEMORY_SIZE);
+    load_com(&mut sim_state, &mut sim_mem, program)?;
+
+    // the program segment prefix is part of the program, so a return to offset 0 still runs
+    Ok(run_dos_program(
+        &mut sim_state,
+        &mut sim_mem,
+        stdin,
+        timing,
+        physical_address(PSP_SEGMENT, 0),
+        0x10000,
+    ))
+}
+
+/// Runs an .EXE program loaded just past the program segment prefix at PSP_SEGMENT, until it
+/// terminates, halts or leaves its program segment prefix and load image
+/// stdin: the bytes the program reads from the console
+/// timing: how the clocks of each instruction are estimated
+pub fn simulate_exe(file: &[u8], stdin: &[u8], timing: Timing) -> Result<DosRun, DosError> {
+    let mut sim_state = SimulationState {
+        ..Default::default()
+    };
+    let mut sim_mem = SimMem::new(MEMORY_SIZE);
+    let load_segment = PSP_SEGMENT + (COM_LOAD_OFFSET as usize / PARAGRAPH_SIZE) as u16;
+    let image_length = load_exe(&mut sim_state, &mut sim_mem, file, load_segment)?;
+
+    Ok(run_dos_program(
+        &mut sim_state,
+        &mut sim_mem,
+        stdin,
+        timing,
+        physical_address(PSP_SEGMENT, 0),
+        COM_LOAD_OFFSET as usize + image_length,
+    ))
+}
+
+/// Runs a DOS program as an .EXE if it has an MZ signature, otherwise as a .COM program
+/// stdin: the bytes the program reads from the console
+/// timing: how the clocks of each instruction are estimated
+pub fn simulate_dos(program: &[u8], stdin: &[u8], timing: Timing) -> Result<DosRun, DosError> {
+    if is_exe(program) {
+        simulate_exe(program, stdin, timing)
+    } else {
+        simulate_com(program, stdin, timing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an .EXE with a header that just fits its relocation table and no extra allocation
+    /// image: the load image, which starts with the code at cs:ip 0000:0000
+    /// relocations: the (segment, offset) of each word in the image to relocate
+    fn exe(image: &[u8], relocations: &[(u16, u16)]) -> Vec<u8> {
+        let table_end = MZ_HEADER_SIZE + relocations.len() * 4;
+        let header_paragraphs = table_end.div_ceil(PARAGRAPH_SIZE);
+        let file_size = header_paragraphs * PARAGRAPH_SIZE + image.len();
+
+        let mut file = vec![0; header_paragraphs * PARAGRAPH_SIZE];
+        let mut set_word = |offset: usize, value: usize| {
+            file[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+        };
+        set_word(0x00, u16::from_le_bytes(*b"MZ") as usize);
+        set_word(0x02, file_size % PAGE_SIZE);
+        set_word(0x04, file_size.div_ceil(PAGE_SIZE));
+        set_word(0x06, relocations.len());
+        set_word(0x08, header_paragraphs);
+        set_word(0x10, 0x100);
+        set_word(0x18, MZ_HEADER_SIZE);
+        for (index, (segment, offset)) in relocations.iter().enumerate() {
+            set_word(MZ_HEADER_SIZE + index * 4, *offset as usize);
+            set_word(MZ_HEADER_SIZE + index * 4 + 2, *segment as usize);
+        }
+        file.extend_from_slice(image);
+        file
+    }
+
+    /// mov ax, 0 with its immediate relocated, then hlt
+    const RELOCATED_IMAGE: [u8; 4] = [0xB8, 0x00, 0x00, 0xF4];
+
+    #[test]
+    fn parses_a_valid_header() {
+        let file = exe(&RELOCATED_IMAGE, &[(0, 1)]);
+        let header = parse_mz_header(&file).unwrap();
+        assert_eq!(header.file_size, file.len());
+        assert_eq!(header.header_size, 32);
+        assert_eq!(header.relocation_count, 1);
+        assert_eq!(header.relocation_table, MZ_HEADER_SIZE);
+        assert_eq!(header.sp, 0x100);
+    }
+
+    #[test]
+    fn rejects_a_truncated_header() {
+        let file = exe(&RELOCATED_IMAGE, &[]);
+        assert_eq!(
+            parse_mz_header(&file[..MZ_HEADER_SIZE - 1]).err(),
+            Some(DosError::TruncatedHeader(MZ_HEADER_SIZE - 1))
+        );
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let mut file = exe(&RELOCATED_IMAGE, &[]);
+        file[0..2].copy_from_slice(b"NE");
+        assert_eq!(parse_mz_header(&file).err(), Some(DosError::BadSignature));
+    }
+
+    #[test]
+    fn rejects_a_file_shorter_than_its_header_says() {
+        let file = exe(&RELOCATED_IMAGE, &[]);
+        let actual = file.len() - 1;
+        assert_eq!(
+            parse_mz_header(&file[..actual]).err(),
+            Some(DosError::TruncatedImage {
+                expected: file.len(),
+                actual
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_a_header_smaller_than_the_fixed_part() {
+        let mut file = exe(&RELOCATED_IMAGE, &[]);
+        file[0x08] = 1;
+        assert_eq!(
+            parse_mz_header(&file).err(),
+            Some(DosError::HeaderTooSmall(PARAGRAPH_SIZE))
+        );
+    }
+
+    #[test]
+    fn rejects_a_truncated_relocation_table() {
+        let mut file = exe(&RELOCATED_IMAGE, &[]);
+        file[0x18] = 0x22;
+        file[0x06] = 1;
+        assert_eq!(
+            parse_mz_header(&file).err(),
+            Some(DosError::TruncatedRelocations)
+        );
+    }
+
+    #[test]
+    fn relocates_the_image_to_the_load_segment() {
+        let file = exe(&RELOCATED_IMAGE, &[(0, 1)]);
+        let mut sim_state = SimulationState {
+            ..Default::default()
+        };
+        let mut sim_mem = SimMem::new(MEMORY_SIZE);
+        let image_length = load_exe(&mut sim_state, &mut sim_mem, &file, 0x2000).unwrap();
+
+        assert_eq!(image_length, RELOCATED_IMAGE.len());
+        assert_eq!(sim_mem.read_word(physical_address(0x2000, 1)), 0x2000);
+        assert_eq!((sim_state.cs, sim_state.ip), (0x2000, 0));
+        assert_eq!((sim_state.ss, sim_state.sp), (0x2000, 0x100));
+        assert_eq!(sim_state.ds, 0x2000 - 0x10);
+    }
+
+    #[test]
+    fn rejects_a_relocation_outside_the_image() {
+        let file = exe(&RELOCATED_IMAGE, &[(0, 3)]);
+        let mut sim_state = SimulationState {
+            ..Default::default()
+        };
+        let mut sim_mem = SimMem::new(MEMORY_SIZE);
+        assert_eq!(
+            load_exe(&mut sim_state, &mut sim_mem, &file, 0x2000).err(),
+            Some(DosError::RelocationOutsideImage {
+                segment: 0,
+                offset: 3
+            })
+        );
+    }
+
+    #[test]
+    fn runs_an_exe_that_prints_and_exits() {
+        // mov dl, 'A'; mov ah, 2; int 21h; mov ax, 4C07h; int 21h
+        let image = [
+            0xB2, 0x41, 0xB4, 0x02, 0xCD, 0x21, 0xB8, 0x07, 0x4C, 0xCD, 0x21,
+        ];
+        let run = simulate_dos(&exe(&image, &[]), &[], Timing::default()).unwrap();
+        assert_eq!(run.stdout, b"A");
+        assert_eq!(run.exit_code, Some(7));
+    }
+}
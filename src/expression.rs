@@ -0,0 +1,369 @@
+/*
+Expressions over the simulated registers and flags, like `cx == 0 && zf`, for conditional
+breakpoints. Values are 16-bit and wrap around, flags are 0 or 1, and any non-zero value is true.
+Numbers are hexadecimal like the debugger's, and must start with a digit so they can't be mistaken for
+register names. From loosest to tightest binding, the operators are || then && then comparisons
+(== != < <= > >=, unsigned) then + - & | ^, then the unary ! and -.
+ */
+
+use std::fmt;
+
+use crate::common_assembly::{parse_register, parse_segment_register, Register, SegmentRegister};
+use crate::simulator_state::SimulationState;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum BinaryOperator {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    BitAnd,
+    BitOr,
+    BitXor,
+}
+
+impl BinaryOperator {
+    /// the operator's binding strength. Operators with higher precedence are applied first.
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOperator::Or => 1,
+            BinaryOperator::And => 2,
+            BinaryOperator::Equal
+            | BinaryOperator::NotEqual
+            | BinaryOperator::Less
+            | BinaryOperator::LessEqual
+            | BinaryOperator::Greater
+            | BinaryOperator::GreaterEqual => 3,
+            BinaryOperator::Add
+            | BinaryOperator::Subtract
+            | BinaryOperator::BitAnd
+            | BinaryOperator::BitOr
+            | BinaryOperator::BitXor => 4,
+        }
+    }
+
+    fn apply(&self, left: u16, right: u16) -> u16 {
+        match self {
+            BinaryOperator::Or => (left != 0 || right != 0) as u16,
+            BinaryOperator::And => (left != 0 && right != 0) as u16,
+            BinaryOperator::Equal => (left == right) as u16,
+            BinaryOperator::NotEqual => (left != right) as u16,
+            BinaryOperator::Less => (left < right) as u16,
+            BinaryOperator::LessEqual => (left <= right) as u16,
+            BinaryOperator::Greater => (left > right) as u16,
+            BinaryOperator::GreaterEqual => (left >= right) as u16,
+            BinaryOperator::Add => left.wrapping_add(right),
+            BinaryOperator::Subtract => left.wrapping_sub(right),
+            BinaryOperator::BitAnd => left & right,
+            BinaryOperator::BitOr => left | right,
+            BinaryOperator::BitXor => left ^ right,
+        }
+    }
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            BinaryOperator::Or => "||",
+            BinaryOperator::And => "&&",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessEqual => "<=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterEqual => ">=",
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::BitAnd => "&",
+            BinaryOperator::BitOr => "|",
+            BinaryOperator::BitXor => "^",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Expression {
+    Number(u16),
+    Register(Register),
+    SegmentRegister(SegmentRegister),
+    InstructionPointer,
+    /// a flag by its assembly name, like zf
+    Flag(String),
+    Not(Box<Expression>),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, BinaryOperator, Box<Expression>),
+}
+
+impl Expression {
+    pub fn evaluate(&self, sim_state: &SimulationState) -> u16 {
+        match self {
+            Expression::Number(value) => *value,
+            Expression::Register(register) => sim_state.get_register_value(*register),
+            Expression::SegmentRegister(segment_register) => {
+                sim_state.get_segment_register_value(*segment_register)
+            }
+            Expression::InstructionPointer => sim_state.ip,
+            Expression::Flag(name) => sim_state.get_flag(name).unwrap_or(false) as u16,
+            Expression::Not(operand) => (operand.evaluate(sim_state) == 0) as u16,
+            Expression::Negate(operand) => operand.evaluate(sim_state).wrapping_neg(),
+            Expression::Binary(left, operator, right) => {
+                operator.apply(left.evaluate(sim_state), right.evaluate(sim_state))
+            }
+        }
+    }
+
+    /// whether the expression evaluates to a non-zero value
+    pub fn is_true(&self, sim_state: &SimulationState) -> bool {
+        self.evaluate(sim_state) != 0
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum Token {
+    Number(u16),
+    Name(String),
+    Operator(BinaryOperator),
+    Not,
+    OpenParenthesis,
+    CloseParenthesis,
+}
+
+/// the operators, with the two character ones first so they are matched before their prefixes
+const OPERATORS: [(&str, BinaryOperator); 13] = [
+    ("||", BinaryOperator::Or),
+    ("&&", BinaryOperator::And),
+    ("==", BinaryOperator::Equal),
+    ("!=", BinaryOperator::NotEqual),
+    ("<=", BinaryOperator::LessEqual),
+    (">=", BinaryOperator::GreaterEqual),
+    ("<", BinaryOperator::Less),
+    (">", BinaryOperator::Greater),
+    ("+", BinaryOperator::Add),
+    ("-", BinaryOperator::Subtract),
+    ("&", BinaryOperator::BitAnd),
+    ("|", BinaryOperator::BitOr),
+    ("^", BinaryOperator::BitXor),
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while let Some(character) = rest.chars().next() {
+        let length = if character.is_ascii_alphanumeric() {
+            let length = rest
+                .find(|character: char| !character.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..length];
+            tokens.push(if character.is_ascii_digit() {
+                Token::Number(parse_number(word)?)
+            } else {
+                Token::Name(word.to_lowercase())
+            });
+            length
+        } else if let Some((symbol, operator)) = OPERATORS
+            .iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))
+        {
+            tokens.push(Token::Operator(*operator));
+            symbol.len()
+        } else {
+            tokens.push(match character {
+                '!' => Token::Not,
+                '(' => Token::OpenParenthesis,
+                ')' => Token::CloseParenthesis,
+                _ => return Err(format!("unexpected character '{}'", character)),
+            });
+            1
+        };
+
+        rest = rest[length..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// parses a hexadecimal number up to ffff, with or without a 0x prefix
+fn parse_number(word: &str) -> Result<u16, String> {
+    let lowercase = word.to_lowercase();
+    let digits = lowercase.strip_prefix("0x").unwrap_or(&lowercase);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid hexadecimal number '{}'", word))
+}
+
+/// looks up a register or flag name
+fn parse_name(name: &str) -> Result<Expression, String> {
+    if let Some(register) = parse_register(name) {
+        return Ok(Expression::Register(register));
+    }
+    if let Some(segment_register) = parse_segment_register(name) {
+        return Ok(Expression::SegmentRegister(segment_register));
+    }
+    if name == "ip" {
+        return Ok(Expression::InstructionPointer);
+    }
+    if SimulationState::default().get_flag(name).is_some() {
+        return Ok(Expression::Flag(name.to_owned()));
+    }
+    Err(format!("unknown register or flag '{}'", name))
+}
+
+/// A recursive descent parser over the tokens of one expression
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// parses binary operators binding at least as tightly as min_precedence. Every operator is
+    /// left associative.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expression, String> {
+        let mut left = self.parse_unary()?;
+
+        while let Some(Token::Operator(operator)) = self.peek() {
+            let operator = *operator;
+            if operator.precedence() < min_precedence {
+                break;
+            }
+            self.position += 1;
+
+            let right = self.parse_binary(operator.precedence() + 1)?;
+            left = Expression::Binary(Box::new(left), operator, Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expression::Number(value)),
+            Some(Token::Name(name)) => parse_name(&name),
+            Some(Token::Not) => Ok(Expression::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Operator(BinaryOperator::Subtract)) => {
+                Ok(Expression::Negate(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::OpenParenthesis) => {
+                let expression = self.parse_binary(0)?;
+                match self.next() {
+                    Some(Token::CloseParenthesis) => Ok(expression),
+                    _ => Err("expected ')'".to_owned()),
+                }
+            }
+            Some(Token::Operator(operator)) => Err(format!("unexpected '{}'", operator)),
+            Some(Token::CloseParenthesis) => Err("unexpected ')'".to_owned()),
+            None => Err("unexpected end of expression".to_owned()),
+        }
+    }
+}
+
+/// Parses an expression like `cx == 0 && zf`
+pub fn parse_expression(text: &str) -> Result<Expression, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        position: 0,
+    };
+
+    let expression = parser.parse_binary(0)?;
+    match parser.peek() {
+        None => Ok(expression),
+        Some(Token::CloseParenthesis) => Err("unexpected ')'".to_owned()),
+        Some(_) => Err("expected an operator".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// evaluates an expression against a state with ax = 1234, cx = 0 and zf set
+    fn evaluate(text: &str) -> u16 {
+        let sim_state = SimulationState {
+            ax: 0x1234,
+            cx: 0,
+            zero_flag: true,
+            ..Default::default()
+        };
+        parse_expression(text)
+            .unwrap_or_else(|error| panic!("{}: {}", text, error))
+            .evaluate(&sim_state)
+    }
+
+    #[test]
+    fn parses_breakpoint_conditions() {
+        assert_eq!(
+            parse_expression("cx == 0 && zf"),
+            Ok(Expression::Binary(
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Register(Register::Cx)),
+                    BinaryOperator::Equal,
+                    Box::new(Expression::Number(0)),
+                )),
+                BinaryOperator::And,
+                Box::new(Expression::Flag("zf".to_owned())),
+            ))
+        );
+        assert_eq!(evaluate("cx == 0 && zf"), 1);
+        assert_eq!(evaluate("cx == 0 && !zf"), 0);
+    }
+
+    #[test]
+    fn binds_operators_by_precedence() {
+        assert_eq!(evaluate("1 || 0 && 0"), 1);
+        assert_eq!(evaluate("ax == 1230 + 4"), 1);
+        assert_eq!(evaluate("2 + 3 == 5 && 1"), 1);
+        assert_eq!(evaluate("(1 || 0) && 0"), 0);
+        assert_eq!(evaluate("10 - 2 - 3"), 0xB);
+        assert_eq!(evaluate("ax & 0ff | 100"), 0x134);
+    }
+
+    #[test]
+    fn applies_unary_operators() {
+        assert_eq!(evaluate("-1"), 0xFFFF);
+        assert_eq!(evaluate("-cx"), 0);
+        assert_eq!(evaluate("2 - -1"), 3);
+        assert_eq!(evaluate("-1 > 0"), 1);
+        assert_eq!(evaluate("!ax"), 0);
+        assert_eq!(evaluate("!!ax"), 1);
+    }
+
+    #[test]
+    fn tells_not_equal_from_not() {
+        assert_eq!(evaluate("ax != 0"), 1);
+        assert_eq!(evaluate("ax !=0"), 1);
+        assert_eq!(evaluate("cx == !0"), 0);
+        assert_eq!(
+            tokenize("!=!"),
+            Ok(vec![Token::Operator(BinaryOperator::NotEqual), Token::Not])
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |text: &str| parse_expression(text).unwrap_err();
+        assert_eq!(error(""), "unexpected end of expression");
+        assert_eq!(error("cx =="), "unexpected end of expression");
+        assert_eq!(error("(cx"), "expected ')'");
+        assert_eq!(error("cx)"), "unexpected ')'");
+        assert_eq!(error("cx dx"), "expected an operator");
+        assert_eq!(error("&& cx"), "unexpected '&&'");
+        assert_eq!(error("cx = 0"), "unexpected character '='");
+        assert_eq!(error("10000"), "invalid hexadecimal number '10000'");
+        assert_eq!(error("foo"), "unknown register or flag 'foo'");
+    }
+}
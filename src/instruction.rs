@@ -0,0 +1,540 @@
+use std::fmt;
+
+use crate::common_assembly::{
+    register_to_assembly_name, segment_register_to_assembly_name, Register, SegmentRegister,
+    WordByte,
+};
+
+/// The operation an instruction performs, independent of the encoding it was decoded from
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Mnemonic {
+    // data transfer
+    Mov,
+    Push,
+    Pop,
+    Xchg,
+    In,
+    Out,
+    Xlat,
+    Lea,
+    Lds,
+    Les,
+    Lahf,
+    Sahf,
+    Pushf,
+    Popf,
+
+    // arithmetic
+    Add,
+    Adc,
+    Inc,
+    Aaa,
+    Daa,
+    Sub,
+    Sbb,
+    Dec,
+    Neg,
+    Cmp,
+    Aas,
+    Das,
+    Mul,
+    Imul,
+    Aam,
+    Div,
+    Idiv,
+    Aad,
+    Cbw,
+    Cwd,
+
+    // logic
+    Not,
+    Shl,
+    Shr,
+    Sar,
+    Rol,
+    Ror,
+    Rcl,
+    Rcr,
+    And,
+    Test,
+    Or,
+    Xor,
+
+    // string manipulation
+    Movsb,
+    Movsw,
+    Cmpsb,
+    Cmpsw,
+    Scasb,
+    Scasw,
+    Lodsb,
+    Lodsw,
+    Stosb,
+    Stosw,
+
+    // control transfer
+    Call,
+    CallFar,
+    Jmp,
+    JmpFar,
+    Ret,
+    Retf,
+    JneJnz,
+    Je,
+    Jl,
+    Jle,
+    Jb,
+    Jbe,
+    Jp,
+    Jo,
+    Js,
+    Jnl,
+    Jg,
+    Jnb,
+    Ja,
+    Jnp,
+    Jno,
+    Jns,
+    Loop,
+    Loopz,
+    Loopnz,
+    Jcxz,
+    Int,
+    Int3,
+    Into,
+    Iret,
+
+    // processor control
+    Clc,
+    Cmc,
+    Stc,
+    Cld,
+    Std,
+    Cli,
+    Sti,
+    Hlt,
+    Wait,
+    Esc,
+    Nop,
+}
+
+/// Takes a mnemonic and returns the string used for it in assembly
+pub fn mnemonic_to_assembly_name(mnemonic: Mnemonic) -> &'static str {
+    match mnemonic {
+        Mnemonic::Mov => "mov",
+        Mnemonic::Push => "push",
+        Mnemonic::Pop => "pop",
+        Mnemonic::Xchg => "xchg",
+        Mnemonic::In => "in",
+        Mnemonic::Out => "out",
+        Mnemonic::Xlat => "xlatb",
+        Mnemonic::Lea => "lea",
+        Mnemonic::Lds => "lds",
+        Mnemonic::Les => "les",
+        Mnemonic::Lahf => "lahf",
+        Mnemonic::Sahf => "sahf",
+        Mnemonic::Pushf => "pushf",
+        Mnemonic::Popf => "popf",
+        Mnemonic::Add => "add",
+        Mnemonic::Adc => "adc",
+        Mnemonic::Inc => "inc",
+        Mnemonic::Aaa => "aaa",
+        Mnemonic::Daa => "daa",
+        Mnemonic::Sub => "sub",
+        Mnemonic::Sbb => "sbb",
+        Mnemonic::Dec => "dec",
+        Mnemonic::Neg => "neg",
+        Mnemonic::Cmp => "cmp",
+        Mnemonic::Aas => "aas",
+        Mnemonic::Das => "das",
+        Mnemonic::Mul => "mul",
+        Mnemonic::Imul => "imul",
+        Mnemonic::Aam => "aam",
+        Mnemonic::Div => "div",
+        Mnemonic::Idiv => "idiv",
+        Mnemonic::Aad => "aad",
+        Mnemonic::Cbw => "cbw",
+        Mnemonic::Cwd => "cwd",
+        Mnemonic::Not => "not",
+        Mnemonic::Shl => "shl",
+        Mnemonic::Shr => "shr",
+        Mnemonic::Sar => "sar",
+        Mnemonic::Rol => "rol",
+        Mnemonic::Ror => "ror",
+        Mnemonic::Rcl => "rcl",
+        Mnemonic::Rcr => "rcr",
+        Mnemonic::And => "and",
+        Mnemonic::Test => "test",
+        Mnemonic::Or => "or",
+        Mnemonic::Xor => "xor",
+        Mnemonic::Movsb => "movsb",
+        Mnemonic::Movsw => "movsw",
+        Mnemonic::Cmpsb => "cmpsb",
+        Mnemonic::Cmpsw => "cmpsw",
+        Mnemonic::Scasb => "scasb",
+        Mnemonic::Scasw => "scasw",
+        Mnemonic::Lodsb => "lodsb",
+        Mnemonic::Lodsw => "lodsw",
+        Mnemonic::Stosb => "stosb",
+        Mnemonic::Stosw => "stosw",
+        Mnemonic::Call => "call",
+        Mnemonic::CallFar => "call",
+        Mnemonic::Jmp => "jmp",
+        Mnemonic::JmpFar => "jmp",
+        Mnemonic::Ret => "ret",
+        Mnemonic::Retf => "retf",
+        Mnemonic::JneJnz => "jnz",
+        Mnemonic::Je => "je",
+        Mnemonic::Jl => "jl",
+        Mnemonic::Jle => "jle",
+        Mnemonic::Jb => "jb",
+        Mnemonic::Jbe => "jbe",
+        Mnemonic::Jp => "jp",
+        Mnemonic::Jo => "jo",
+        Mnemonic::Js => "js",
+        Mnemonic::Jnl => "jnl",
+        Mnemonic::Jg => "jg",
+        Mnemonic::Jnb => "jnb",
+        Mnemonic::Ja => "ja",
+        Mnemonic::Jnp => "jnp",
+        Mnemonic::Jno => "jno",
+        Mnemonic::Jns => "jns",
+        Mnemonic::Loop => "loop",
+        Mnemonic::Loopz => "loopz",
+        Mnemonic::Loopnz => "loopnz",
+        Mnemonic::Jcxz => "jcxz",
+        Mnemonic::Int => "int",
+        Mnemonic::Int3 => "int3",
+        Mnemonic::Into => "into",
+        Mnemonic::Iret => "iret",
+        Mnemonic::Clc => "clc",
+        Mnemonic::Cmc => "cmc",
+        Mnemonic::Stc => "stc",
+        Mnemonic::Cld => "cld",
+        Mnemonic::Std => "std",
+        Mnemonic::Cli => "cli",
+        Mnemonic::Sti => "sti",
+        Mnemonic::Hlt => "hlt",
+        Mnemonic::Wait => "wait",
+        Mnemonic::Esc => "esc",
+        Mnemonic::Nop => "nop",
+    }
+}
+
+/// The registers that are summed in a memory address calculation, indexed by the rm field
+#[repr(u8)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum AddressBase {
+    BxSi = 0b000,
+    BxDi = 0b001,
+    BpSi = 0b010,
+    BpDi = 0b011,
+    Si = 0b100,
+    Di = 0b101,
+    Bp = 0b110,
+    Bx = 0b111,
+}
+
+impl From<u8> for AddressBase {
+    fn from(value: u8) -> Self {
+        match value {
+            0b000 => AddressBase::BxSi,
+            0b001 => AddressBase::BxDi,
+            0b010 => AddressBase::BpSi,
+            0b011 => AddressBase::BpDi,
+            0b100 => AddressBase::Si,
+            0b101 => AddressBase::Di,
+            0b110 => AddressBase::Bp,
+            0b111 => AddressBase::Bx,
+            _ => panic!("Bad rm field"),
+        }
+    }
+}
+
+/// Takes an address base and returns the string for the registers in assembly
+pub fn address_base_to_assembly_name(base: AddressBase) -> &'static str {
+    match base {
+        AddressBase::BxSi => "bx + si",
+        AddressBase::BxDi => "bx + di",
+        AddressBase::BpSi => "bp + si",
+        AddressBase::BpDi => "bp + di",
+        AddressBase::Si => "si",
+        AddressBase::Di => "di",
+        AddressBase::Bp => "bp",
+        AddressBase::Bx => "bx",
+    }
+}
+
+/// The displacement of a memory operand, sized as it was encoded
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Displacement {
+    None,
+    Byte(i8),
+    Word(i16),
+}
+
+impl Displacement {
+    /// the displacement as a signed 16 bit value
+    pub fn value(&self) -> i16 {
+        match self {
+            Displacement::None => 0,
+            Displacement::Byte(displacement) => *displacement as i16,
+            Displacement::Word(displacement) => *displacement,
+        }
+    }
+}
+
+/// A memory operand. A direct address has no base and a word displacement.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct EffectiveAddress {
+    pub base: Option<AddressBase>,
+    pub displacement: Displacement,
+    pub width: WordByte,
+}
+
+impl fmt::Display for EffectiveAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.base {
+            None => write!(f, "[{}]", self.displacement.value() as u16),
+            Some(base) => {
+                let address = address_base_to_assembly_name(base);
+                match self.displacement {
+                    Displacement::None => write!(f, "[{}]", address),
+                    _ => {
+                        let displacement = self.displacement.value();
+                        if displacement < 0 {
+                            write!(f, "[{} - {}]", address, -(displacement as i32))
+                        } else {
+                            write!(f, "[{} + {}]", address, displacement)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Operand {
+    Register(Register),
+    SegmentRegister(SegmentRegister),
+    Memory(EffectiveAddress),
+    Immediate(u16),
+    /// a byte immediate that is sign extended to the width of the destination
+    SignExtendedImmediate(i8),
+    /// displacement from the end of the instruction for short jumps
+    ShortRelative(i8),
+    /// displacement from the end of the instruction for near calls and jumps
+    NearRelative(i16),
+    FarAddress {
+        segment: u16,
+        offset: u16,
+    },
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Repeat {
+    /// 0xF3. rep for movs, lods and stos; repe / repz for cmps and scas
+    Rep,
+    /// 0xF2. repne / repnz
+    Repne,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub struct Prefixes {
+    pub lock: bool,
+    pub repeat: Option<Repeat>,
+    pub segment: Option<SegmentRegister>,
+}
+
+/// A decoded instruction
+/// opcode: the operation performed
+/// operands: destination first, in the order they are written in assembly
+/// size: the number of bytes in the encoded instruction, including prefixes
+/// prefixes: the prefix bytes that preceded the opcode
+#[derive(PartialEq, Clone, Debug)]
+pub struct Instruction {
+    pub opcode: Mnemonic,
+    pub operands: Vec<Operand>,
+    pub size: usize,
+    pub prefixes: Prefixes,
+}
+
+impl Instruction {
+    /// Whether memory operands need an explicit byte / word size to assemble. Shift counts do
+    /// not imply the size of the shifted operand.
+    fn needs_explicit_size(&self) -> bool {
+        match self.opcode {
+            Mnemonic::Shl
+            | Mnemonic::Shr
+            | Mnemonic::Sar
+            | Mnemonic::Rol
+            | Mnemonic::Ror
+            | Mnemonic::Rcl
+            | Mnemonic::Rcr => true,
+            _ => !self.operands.iter().any(|operand| {
+                matches!(operand, Operand::Register(_) | Operand::SegmentRegister(_))
+            }),
+        }
+    }
+
+    fn format_operand(&self, operand: &Operand, target_label: Option<&str>) -> String {
+        match operand {
+            Operand::Register(register) => register_to_assembly_name(*register),
+            Operand::SegmentRegister(segment_register) => {
+                segment_register_to_assembly_name(*segment_register)
+            }
+            Operand::Memory(address) => match self.opcode {
+                Mnemonic::CallFar | Mnemonic::JmpFar => format!("far {}", address),
+                _ => {
+                    if self.needs_explicit_size() {
+                        let size = match address.width {
+                            WordByte::Byte => "byte",
+                            WordByte::Word => "word",
+                        };
+                        format!("{} {}", size, address)
+                    } else {
+                        format!("{}", address)
+                    }
+                }
+            },
+            Operand::Immediate(immediate) => format!("{}", immediate),
+            Operand::SignExtendedImmediate(immediate) => format!("{}", immediate),
+            Operand::ShortRelative(_) | Operand::NearRelative(_) => match target_label {
+                Some(label) => label.to_owned(),
+                None => format!(
+                    "$ + {} + {}",
+                    self.size,
+                    self.relative_displacement().unwrap()
+                ),
+            },
+            Operand::FarAddress { segment, offset } => format!("{}:{}", segment, offset),
+        }
+    }
+
+    /// NASM has no mnemonic for the coprocessor escape, so it is written as data bytes
+    fn format_esc(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        let escape_code = match self.operands[0] {
+            Operand::Immediate(escape_code) => escape_code as u8,
+            _ => panic!("Expected escape code operand"),
+        };
+        let reg_field = (escape_code & 0b111) << 3;
+
+        let mut bytes = vec![0b11011000 | (escape_code >> 3)];
+        match self.operands[1] {
+            Operand::Register(register) => {
+                bytes.push(0b11000000 | reg_field | ((register as u8) & 0b111));
+            }
+            Operand::Memory(address) => {
+                let (mode, rm_field) = match (address.base, address.displacement) {
+                    (None, _) => (0b00, 0b110),
+                    (Some(base), Displacement::None) => (0b00, base as u8),
+                    (Some(base), Displacement::Byte(_)) => (0b01, base as u8),
+                    (Some(base), Displacement::Word(_)) => (0b10, base as u8),
+                };
+                bytes.push((mode << 6) | reg_field | rm_field);
+                match address.displacement {
+                    Displacement::None => {}
+                    Displacement::Byte(displacement) => bytes.push(displacement as u8),
+                    Displacement::Word(displacement) => {
+                        bytes.extend_from_slice(&(displacement as u16).to_le_bytes())
+                    }
+                }
+            }
+            _ => panic!("Expected register or memory operand for esc"),
+        }
+
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+        write!(
+            f,
+            "db {} ; esc {}, {}",
+            bytes.join(", "),
+            escape_code,
+            self.format_operand(&self.operands[1], None)
+        )
+    }
+
+    /// The displacement of a relative jump, call or loop, if the instruction is one
+    fn relative_displacement(&self) -> Option<i16> {
+        match self.operands.first() {
+            Some(Operand::ShortRelative(displacement)) => Some(*displacement as i16),
+            Some(Operand::NearRelative(displacement)) => Some(*displacement),
+            _ => None,
+        }
+    }
+
+    /// The address that a relative jump, call or loop transfers control to
+    /// address: the address of the first byte of this instruction
+    /// returns: None if the instruction is not a relative branch or the target is before address 0
+    pub fn branch_target(&self, address: usize) -> Option<usize> {
+        let displacement = self.relative_displacement()?;
+        let next_address = (address + self.size) as isize;
+        usize::try_from(next_address + displacement as isize).ok()
+    }
+
+    /// Formats the instruction like Display, but with the branch target written as label
+    pub fn format_with_label(&self, label: &str) -> String {
+        let mut result = String::new();
+        self.write_assembly(&mut result, Some(label))
+            .expect("Writing to a String cannot fail");
+        result
+    }
+
+    /// Writes the NASM form of the instruction
+    /// target_label: the name to use for the target of a relative branch, if it has one
+    fn write_assembly(&self, f: &mut dyn fmt::Write, target_label: Option<&str>) -> fmt::Result {
+        if self.opcode == Mnemonic::Esc {
+            return self.format_esc(f);
+        }
+
+        if self.prefixes.lock {
+            write!(f, "lock ")?;
+        }
+        if let Some(repeat) = self.prefixes.repeat {
+            let prefix = match (repeat, self.opcode) {
+                (Repeat::Repne, _) => "repne",
+                (
+                    Repeat::Rep,
+                    Mnemonic::Cmpsb | Mnemonic::Cmpsw | Mnemonic::Scasb | Mnemonic::Scasw,
+                ) => "repe",
+                (Repeat::Rep, _) => "rep",
+            };
+            write!(f, "{} ", prefix)?;
+        }
+        if let Some(segment) = self.prefixes.segment {
+            write!(f, "{} ", segment_register_to_assembly_name(segment))?;
+        }
+
+        write!(f, "{}", mnemonic_to_assembly_name(self.opcode))?;
+
+        // aam and aad are written without an operand for the usual base of 10
+        let operands: &[Operand] = match (self.opcode, self.operands.as_slice()) {
+            (Mnemonic::Aam | Mnemonic::Aad, [Operand::Immediate(10)]) => &[],
+            (_, operands) => operands,
+        };
+
+        match (self.opcode, operands.first()) {
+            (Mnemonic::Jmp, Some(Operand::ShortRelative(_))) => write!(f, " short")?,
+            (Mnemonic::Jmp, Some(Operand::NearRelative(_))) => write!(f, " near")?,
+            _ => {}
+        }
+
+        for (operand_index, operand) in operands.iter().enumerate() {
+            let separator = if operand_index == 0 { " " } else { ", " };
+            write!(
+                f,
+                "{}{}",
+                separator,
+                self.format_operand(operand, target_label)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_assembly(f, None)
+    }
+}
@@ -0,0 +1,26 @@
+/*
+Decoding, disassembly, assembly and simulation of 8086 machine code. The perfaware binary is a
+command line front end for this library.
+ */
+
+pub mod assemble;
+pub mod biu;
+pub mod byte_operations;
+pub mod clocks;
+pub mod common_assembly;
+pub mod debugger;
+pub mod decode;
+pub mod disassemble;
+pub mod dos;
+pub mod expression;
+pub mod instruction;
+pub mod simulate;
+pub mod simulator_state;
+
+pub use assemble::{assemble, AssembleError};
+pub use common_assembly::{DecodeError, DecodeErrorReason};
+pub use decode::get_instruction;
+pub use disassemble::disassemble;
+pub use instruction::Instruction;
+pub use simulate::simulate;
+pub use simulator_state::{SimMem, SimulationState};
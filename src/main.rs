@@ -1,38 +1,47 @@
-mod byte_operations;
-mod common_assembly;
-mod disassemble;
-mod simulate;
-mod simulator_state;
-
 use std::{
     fs::{self, remove_file, File},
-    io::Write,
+    io::{self, Write},
     iter::zip,
     path::{Path, PathBuf},
-    process::Command,
 };
 
-use argparse;
 use argparse::ArgumentParser;
-use disassemble::disassemble;
-use simulate::simulate;
-
-fn run_nasm(path: &str, outpath: &str) {
-    if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .arg("/C")
-            .arg("nasm")
-            .arg(path)
-            .arg("-o")
-            .arg(outpath)
-            .output()
-            .expect("failed to execute process");
-    } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(format!("nasm {} -o {}", path, outpath))
-            .output()
-            .expect("failed to execute process");
+use perfaware::clocks::{CpuModel, Timing};
+use perfaware::debugger::Debugger;
+use perfaware::dos::simulate_dos;
+use perfaware::simulate::{compare_cpu_timing, simulate_with_config, SimulationConfig};
+use perfaware::{assemble, disassemble};
+
+/// assembles the source at path and writes the machine code to outpath
+fn assemble_file(path: &str, outpath: &str) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+    let machine_code = assemble(&source).map_err(|error| format!("{}: {}", path, error))?;
+    fs::write(outpath, machine_code).map_err(|error| format!("{}: {}", outpath, error))
+}
+
+/// parses a load address written as hexadecimal SEGMENT:OFFSET, like 1000:0100
+fn parse_load_address(load_address: &str) -> Result<SimulationConfig, String> {
+    let invalid = || {
+        format!(
+            "invalid load address {}, expected SEGMENT:OFFSET",
+            load_address
+        )
+    };
+    let (segment, offset) = load_address.split_once(':').ok_or_else(invalid)?;
+
+    Ok(SimulationConfig {
+        load_segment: u16::from_str_radix(segment, 16).map_err(|_| invalid())?,
+        load_offset: u16::from_str_radix(offset, 16).map_err(|_| invalid())?,
+        ..Default::default()
+    })
+}
+
+/// parses the processor to estimate clocks for, 8086 or 8088
+fn parse_cpu_model(cpu_model: &str) -> Result<CpuModel, String> {
+    match cpu_model {
+        "8086" => Ok(CpuModel::I8086),
+        "8088" => Ok(CpuModel::I8088),
+        _ => Err(format!("unknown cpu {}, expected 8086 or 8088", cpu_model)),
     }
 }
 
@@ -41,6 +50,13 @@ fn main() {
     let mut target = "".to_owned();
     let mut should_reassemble = false;
     let mut should_simulate = false;
+    let mut load_address = "0000:0000".to_owned();
+    let mut should_run_dos = false;
+    let mut stdin_path = "".to_owned();
+    let mut cpu = "8086".to_owned();
+    let mut should_compare_timing = false;
+    let mut should_emulate_prefetch = false;
+    let mut should_debug = false;
 
     {
         // this block limits scope of borrows by ap.refer() method
@@ -58,21 +74,84 @@ fn main() {
             argparse::StoreTrue,
             "Whether or not to run a program simulation",
         );
+        ap.refer(&mut load_address).add_option(
+            &["--load-address"],
+            argparse::Store,
+            "The hexadecimal SEGMENT:OFFSET to load the program at when simulating",
+        );
+        ap.refer(&mut should_run_dos).add_option(
+            &["--dos"],
+            argparse::StoreTrue,
+            "Simulate the program as a DOS .COM program, or as an .EXE if it starts with MZ. .com and .exe files are run as well as listings",
+        );
+        ap.refer(&mut stdin_path).add_option(
+            &["--stdin"],
+            argparse::Store,
+            "A file whose contents a DOS program reads as console input",
+        );
+        ap.refer(&mut cpu).add_option(
+            &["--cpu"],
+            argparse::Store,
+            "The processor to estimate clocks for when simulating, 8086 or 8088",
+        );
+        ap.refer(&mut should_compare_timing).add_option(
+            &["--compare-timing"],
+            argparse::StoreTrue,
+            "Whether or not to report the clocks of each instruction on the 8086 and 8088 side by side",
+        );
+        ap.refer(&mut should_emulate_prefetch).add_option(
+            &["--prefetch"],
+            argparse::StoreTrue,
+            "Whether or not to emulate the instruction queue and bus cycles when estimating clocks",
+        );
+        ap.refer(&mut should_debug).add_option(
+            &["--debug"],
+            argparse::StoreTrue,
+            "Whether or not to step through the program in an interactive debugger",
+        );
         ap.parse_args_or_exit();
     }
 
+    let simulation_config = match parse_load_address(&load_address).and_then(|config| {
+        Ok(SimulationConfig {
+            timing: Timing {
+                cpu_model: parse_cpu_model(&cpu)?,
+                emulate_prefetch: should_emulate_prefetch,
+            },
+            ..config
+        })
+    }) {
+        Ok(simulation_config) => simulation_config,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1)
+        }
+    };
+
+    if should_run_dos && !should_simulate {
+        eprintln!("--dos only applies to --simulate");
+        std::process::exit(1)
+    }
+
+    let stdin = if stdin_path.is_empty() {
+        Vec::new()
+    } else {
+        match fs::read(&stdin_path) {
+            Ok(stdin) => stdin,
+            Err(error) => {
+                eprintln!("{}: {}", stdin_path, error);
+                std::process::exit(1)
+            }
+        }
+    };
+
     let path = Path::new(&target);
 
     let (dir_path, file_paths) = match fs::read_dir(path) {
         Ok(dir_iter) => {
             let mut file_paths: Vec<PathBuf> = Vec::new();
-            for dir_entry in dir_iter {
-                match dir_entry {
-                    Ok(file_path) => {
-                        file_paths.push(file_path.path().to_path_buf());
-                    }
-                    Err(_) => {}
-                }
+            for file_path in dir_iter.flatten() {
+                file_paths.push(file_path.path().to_path_buf());
             }
             (path, file_paths)
         }
@@ -87,7 +166,14 @@ fn main() {
     };
 
     for file_path in file_paths {
-        if file_path.extension().unwrap().to_str().unwrap() != "asm" {
+        let extension = file_path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        // DOS programs can also be run from machine code that was assembled elsewhere
+        let is_dos_binary = should_run_dos && (extension == "com" || extension == "exe");
+        if extension != "asm" && !is_dos_binary {
             continue;
         }
 
@@ -104,21 +190,34 @@ fn main() {
                 .unwrap()
         };
 
-        // assemble with nasm
-        run_nasm(&original_asm_path, &original_outpath);
+        let contents = if is_dos_binary {
+            match fs::read(&file_path) {
+                Ok(contents) => contents,
+                Err(error) => {
+                    eprintln!("Failed to read {}", &original_asm_path);
+                    eprintln!("{}", error);
+                    continue;
+                }
+            }
+        } else {
+            if let Err(error) = assemble_file(&original_asm_path, &original_outpath) {
+                eprintln!("Failed to assemble {}", error);
+                continue;
+            }
 
-        // read assembler output
-        let contents = match fs::read(&original_outpath) {
-            Ok(contents) => contents,
-            Err(error) => {
-                eprintln!("Failed to read {}", &original_outpath);
-                eprintln!("{}", error);
-                return;
+            // read assembler output
+            match fs::read(&original_outpath) {
+                Ok(contents) => contents,
+                Err(error) => {
+                    eprintln!("Failed to read {}", &original_outpath);
+                    eprintln!("{}", error);
+                    return;
+                }
             }
         };
 
-        // perform a diff check
-        if should_reassemble {
+        // perform a diff check. Only listings have an original to compare against.
+        if should_reassemble && !is_dos_binary {
             // disassemble with our disassembler
             let (gen_asm_path, gen_outpath) = {
                 let disassembly = disassemble(&contents);
@@ -128,7 +227,7 @@ fn main() {
 
                 // write to file
                 let mut file = File::create(&gen_asm_path).unwrap();
-                file.write(&disassembly.as_bytes())
+                file.write_all(disassembly.as_bytes())
                     .expect("Failed to write disassembly to file");
 
                 // gen outpath
@@ -144,8 +243,13 @@ fn main() {
                 )
             };
 
-            // assemble with nasm
-            run_nasm(&gen_asm_path, &gen_outpath);
+            if let Err(error) = assemble_file(&gen_asm_path, &gen_outpath) {
+                println!(
+                    "{} dissassembly failed to reassemble: {}",
+                    original_asm_path, error
+                );
+                continue;
+            }
 
             let test_passed: bool = {
                 let original_data = fs::read(&original_outpath).expect("Unexpected read error");
@@ -172,10 +276,39 @@ fn main() {
             }
         }
 
-        if should_simulate {
-            let simulation_log = simulate(&contents);
+        if should_debug {
+            println!(
+                "Debugging {}, type help for a list of commands",
+                original_asm_path
+            );
+            let mut debugger = Debugger::new(&contents, &simulation_config);
+            if let Err(error) = debugger.run_repl(io::stdin().lock(), &mut io::stdout()) {
+                eprintln!("{}", error);
+                std::process::exit(1)
+            }
+        } else if should_simulate && should_run_dos {
+            match simulate_dos(&contents, &stdin, simulation_config.timing) {
+                Ok(dos_run) => {
+                    println!("Simulation results:");
+                    print!("{}", dos_run.log);
+                    println!("Program output:");
+                    println!("{}", String::from_utf8_lossy(&dos_run.stdout));
+                    match dos_run.exit_code {
+                        Some(exit_code) => println!("Return code: {}", exit_code),
+                        None => println!("The program did not terminate"),
+                    }
+                }
+                Err(error) => eprintln!("Failed to load {}: {}", original_asm_path, error),
+            }
+        } else if should_simulate {
+            let simulation_log = simulate_with_config(&contents, &simulation_config);
             println!("Simulation results:");
             print!("{}", simulation_log);
         }
+
+        if should_compare_timing {
+            println!("Timing comparison:");
+            print!("{}", compare_cpu_timing(&contents, &simulation_config));
+        }
     }
 }
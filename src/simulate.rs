@@ -1,685 +1,1358 @@
-use crate::byte_operations::concat_bytes;
+use std::collections::HashMap;
+
+use crate::biu::InstructionTimer;
+use crate::clocks::{CpuModel, Timing};
 use crate::common_assembly::{
-    get_opcode, get_register_enum, get_rm_register_field, ArithmeticOpCode, Direction, Mode,
-    OpCode, Register, WordByte,
+    get_register_width, DecodeError, Register, SegmentRegister, WordByte,
+};
+use crate::decode::get_instruction;
+use crate::instruction::{AddressBase, EffectiveAddress, Instruction, Mnemonic, Operand, Repeat};
+use crate::simulator_state::{
+    get_sim_state_diff, physical_address, sign_bit, width_mask, SimMem, SimulationState,
+    MEMORY_SIZE,
 };
-use crate::disassemble::get_instruction;
-use crate::simulator_state::{get_sim_state_diff, SimMem, SimulationState};
-
-/// Returns a string and the number of bytes in the displacement for a no-displacement mov
-/// rm_field: the rm_field
-/// machine_code: the machine code vector
-/// index: The index of the opcode-containing byte
-/// returns: the memory address and the number of bytes in the displacement
-pub fn no_displacement_address(
+
+/// Computes the offset that a memory operand refers to within its segment
+/// sim_state: the current register state
+/// address: the memory operand
+pub fn effective_address(sim_state: &SimulationState, address: &EffectiveAddress) -> u16 {
+    let base = match address.base {
+        None => 0,
+        Some(AddressBase::BxSi) => sim_state.bx.wrapping_add(sim_state.si),
+        Some(AddressBase::BxDi) => sim_state.bx.wrapping_add(sim_state.di),
+        Some(AddressBase::BpSi) => sim_state.bp.wrapping_add(sim_state.si),
+        Some(AddressBase::BpDi) => sim_state.bp.wrapping_add(sim_state.di),
+        Some(AddressBase::Si) => sim_state.si,
+        Some(AddressBase::Di) => sim_state.di,
+        Some(AddressBase::Bp) => sim_state.bp,
+        Some(AddressBase::Bx) => sim_state.bx,
+    };
+
+    base.wrapping_add(address.displacement.value() as u16)
+}
+
+/// The segment register a memory operand is addressed through. bp-based addresses use the stack
+/// segment and everything else uses the data segment, unless the instruction has an override.
+/// instruction: the instruction the memory operand belongs to
+/// address: the memory operand
+pub fn operand_segment(instruction: &Instruction, address: &EffectiveAddress) -> SegmentRegister {
+    match (instruction.prefixes.segment, address.base) {
+        (Some(segment_register), _) => segment_register,
+        (None, Some(AddressBase::BpSi | AddressBase::BpDi | AddressBase::Bp)) => {
+            SegmentRegister::Ss
+        }
+        (None, _) => SegmentRegister::Ds,
+    }
+}
+
+/// Reads the value of a register, memory or immediate operand
+/// returns: None for a jump displacement or far address, which only a jump or call can use
+fn read_operand(
     sim_state: &SimulationState,
-    rm_field: u8,
-    machine_code: &Vec<u8>,
-    index: usize,
-) -> (usize, usize) {
-    if rm_field == 0b000 {
-        // ("[bx + si]".to_owned(), 0)
-        ((sim_state.bx + sim_state.si) as usize, 0)
-    } else if rm_field == 0b001 {
-        // ("[bx + di]".to_owned(), 0)
-        ((sim_state.bx + sim_state.di) as usize, 0)
-    } else if rm_field == 0b010 {
-        // ("[bp + si]".to_owned(), 0)
-        ((sim_state.bp + sim_state.si) as usize, 0)
-    } else if rm_field == 0b011 {
-        // ("[bp + di]".to_owned(), 0)
-        ((sim_state.bp + sim_state.di) as usize, 0)
-    } else if rm_field == 0b100 {
-        // ("si".to_owned(), 0)
-        (sim_state.si as usize, 0)
-    } else if rm_field == 0b101 {
-        // ("di".to_owned(), 0)
-        (sim_state.di as usize, 0)
-    } else if rm_field == 0b110 {
-        let low_byte = match machine_code.get(index + 2) {
-            Some(low_byte) => *low_byte,
-            None => panic!("Failed to fetch low byte for direct address"),
-        };
-        let high_byte = match machine_code.get(index + 3) {
-            Some(high_byte) => *high_byte,
-            None => panic!("Failed to fetch high byte for direct address"),
-        };
-        let displacement = concat_bytes(high_byte, low_byte);
-        (displacement as usize, 2)
-    } else if rm_field == 0b111 {
-        // ("bx".to_owned(), 0)
-        (sim_state.bx as usize, 0)
-    } else {
-        panic!("Bad rm field")
+    sim_mem: &SimMem,
+    instruction: &Instruction,
+    operand: &Operand,
+) -> Option<u16> {
+    match operand {
+        Operand::Register(register) => Some(sim_state.get_register_value(*register)),
+        Operand::SegmentRegister(segment_register) => {
+            Some(sim_state.get_segment_register_value(*segment_register))
+        }
+        Operand::Memory(address) => {
+            let segment =
+                sim_state.get_segment_register_value(operand_segment(instruction, address));
+            let offset = effective_address(sim_state, address);
+            Some(sim_mem.read_segmented(segment, offset, address.width))
+        }
+        Operand::Immediate(immediate) => Some(*immediate),
+        Operand::SignExtendedImmediate(immediate) => Some(*immediate as i16 as u16),
+        Operand::ShortRelative(_) | Operand::NearRelative(_) | Operand::FarAddress { .. } => None,
     }
 }
 
-/// Returns a string and the number of bytes in the displacement for a no-displacement arithmetic
-/// instruction. A separate function from the mov version b/c we need brackets around all addresses
-/// returned, not just some.
-/// rm_field: the rm_field
-/// machine_code: the machine code vector
-/// index: The index of the opcode-containing byte
-/// returns: the usize for the address and the number of bytes in the displacement (direct address case only)
-pub fn no_displacement_address_arithmetic(
-    rm_field: u8,
-    machine_code: &Vec<u8>,
-    index: usize,
-) -> (usize, usize) {
-    if rm_field == 0b000 {
-        // ("[bx + si]".to_owned(), 0)
-        todo!()
-    } else if rm_field == 0b001 {
-        // ("[bx + di]".to_owned(), 0)
-        todo!()
-    } else if rm_field == 0b010 {
-        // ("[bp + si]".to_owned(), 0)
-        todo!()
-    } else if rm_field == 0b011 {
-        // ("[bp + di]".to_owned(), 0)
-        todo!()
-    } else if rm_field == 0b100 {
-        // ("[si]".to_owned(), 0)
-        todo!()
-    } else if rm_field == 0b101 {
-        // ("[di]".to_owned(), 0)
-        todo!()
-    } else if rm_field == 0b110 {
-        let low_byte = match machine_code.get(index + 2) {
-            Some(low_byte) => *low_byte,
-            None => panic!("Failed to fetch low byte for direct address"),
-        };
-        let high_byte = match machine_code.get(index + 3) {
-            Some(high_byte) => *high_byte,
-            None => panic!("Failed to fetch high byte for direct address"),
-        };
-        let displacement = concat_bytes(high_byte, low_byte);
-        (displacement as usize, 2)
-    } else if rm_field == 0b111 {
-        // ("[bx]".to_owned(), 0)
-        todo!()
-    } else {
-        panic!("Bad rm field")
+/// Writes a value to a register or memory operand
+fn write_operand(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    instruction: &Instruction,
+    operand: &Operand,
+    value: u16,
+) {
+    match operand {
+        Operand::Register(register) => sim_state.set_register_value(*register, value),
+        Operand::SegmentRegister(segment_register) => {
+            sim_state.set_segment_register_value(*segment_register, value)
+        }
+        Operand::Memory(address) => {
+            let segment =
+                sim_state.get_segment_register_value(operand_segment(instruction, address));
+            let offset = effective_address(sim_state, address);
+            sim_mem.write_segmented(segment, offset, address.width, value);
+        }
+        _ => panic!("Unexpected destination operand"),
     }
 }
 
-/// Takes the rm_field and returns the corresponding displacement address
-/// rm_field: the rm_field
-/// displacement: The displacement from the address
-pub fn rm_field_to_displacement(
+/// Moves ip by the displacement of a relative jump. ip must already point to the next instruction
+fn relative_jump(sim_state: &mut SimulationState, instruction: &Instruction) {
+    let displacement = match instruction.operands[0] {
+        Operand::ShortRelative(displacement) => displacement as i16,
+        Operand::NearRelative(displacement) => displacement,
+        _ => panic!("Expected a relative jump operand"),
+    };
+
+    sim_state.ip = sim_state.ip.wrapping_add(displacement as u16);
+}
+
+/// Simulates lea, which loads the offset of a memory operand, and lds / les, which load a far
+/// pointer stored at the memory operand into a register and ds / es
+fn simulate_load_address(
+    sim_state: &mut SimulationState,
+    sim_mem: &SimMem,
+    instruction: &Instruction,
+) {
+    let (register, address) = match instruction.operands.as_slice() {
+        [Operand::Register(register), Operand::Memory(address)] => (*register, address),
+        _ => panic!("Expected a register and a memory operand"),
+    };
+    let offset = effective_address(sim_state, address);
+
+    let segment_register = match instruction.opcode {
+        Mnemonic::Lds => SegmentRegister::Ds,
+        Mnemonic::Les => SegmentRegister::Es,
+        _ => {
+            sim_state.set_register_value(register, offset);
+            return;
+        }
+    };
+
+    let segment = sim_state.get_segment_register_value(operand_segment(instruction, address));
+    let pointer_offset = sim_mem.read_segmented(segment, offset, WordByte::Word);
+    let pointer_segment = sim_mem.read_segmented(segment, offset.wrapping_add(2), WordByte::Word);
+    sim_state.set_register_value(register, pointer_offset);
+    sim_state.set_segment_register_value(segment_register, pointer_segment);
+}
+
+/// Pushes a word onto the stack at ss:sp
+pub fn push_word(sim_state: &mut SimulationState, sim_mem: &mut SimMem, value: u16) {
+    sim_state.sp = sim_state.sp.wrapping_sub(2);
+    sim_mem.write_segmented(sim_state.ss, sim_state.sp, WordByte::Word, value);
+}
+
+/// Pops a word from the stack at ss:sp
+pub fn pop_word(sim_state: &mut SimulationState, sim_mem: &SimMem) -> u16 {
+    let value = sim_mem.read_segmented(sim_state.ss, sim_state.sp, WordByte::Word);
+    sim_state.sp = sim_state.sp.wrapping_add(2);
+    value
+}
+
+/// Reads the segment and offset of a far call or jmp target
+/// returns: (segment, offset)
+fn far_target(
     sim_state: &SimulationState,
-    rm_field: u8,
-    displacement: u16,
-) -> usize {
-    if rm_field == 0b000 {
-        (sim_state.bx + sim_state.si + displacement) as usize
-    } else if rm_field == 0b001 {
-        (sim_state.bx + sim_state.di + displacement) as usize
-    } else if rm_field == 0b010 {
-        (sim_state.bp + sim_state.si + displacement) as usize
-    } else if rm_field == 0b011 {
-        (sim_state.bp + sim_state.di + displacement) as usize
-    } else if rm_field == 0b100 {
-        (sim_state.si + displacement) as usize
-    } else if rm_field == 0b101 {
-        (sim_state.di + displacement) as usize
-    } else if rm_field == 0b110 {
-        (sim_state.bp + displacement) as usize
-    } else if rm_field == 0b111 {
-        (sim_state.bx + displacement) as usize
-    } else {
-        panic!("Bad rm field")
+    sim_mem: &SimMem,
+    instruction: &Instruction,
+) -> (u16, u16) {
+    match &instruction.operands[0] {
+        Operand::FarAddress { segment, offset } => (*segment, *offset),
+        // the offset is stored first, followed by the segment
+        Operand::Memory(address) => {
+            let segment_register = operand_segment(instruction, address);
+            let segment = sim_state.get_segment_register_value(segment_register);
+            let offset = effective_address(sim_state, address);
+            (
+                sim_mem.read_segmented(segment, offset.wrapping_add(2), WordByte::Word),
+                sim_mem.read_segmented(segment, offset, WordByte::Word),
+            )
+        }
+        _ => panic!("Expected a far address or memory operand"),
     }
 }
 
-fn simulate_mem_mem(
+/// Transfers control for call and jmp. ip must already point to the next instruction.
+/// returns: Unsupported when a near target can't be read
+fn simulate_call_jmp(
     sim_state: &mut SimulationState,
     sim_mem: &mut SimMem,
-    direction: Direction,
-    word_byte: WordByte,
-    address_calculation: usize,
-    register: Register,
-) {
-    match direction {
-        Direction::RegRm => match word_byte {
-            WordByte::Byte => {
-                sim_mem.mem[address_calculation] = sim_state.get_register_value(register) as u8
-            }
-            WordByte::Word => {
-                let register_value: u16 = sim_state.get_register_value(register);
-                sim_mem.mem[address_calculation] = (register_value & 0xFF) as u8;
-                sim_mem.mem[address_calculation + 1] = (register_value & 0xFF00) as u8;
-            }
-        },
-        Direction::RmReg => match word_byte {
-            WordByte::Byte => {
-                sim_state.set_register_value(register, sim_mem.mem[address_calculation] as u16);
-            }
-            WordByte::Word => {
-                let value: u16 = ((sim_mem.mem[address_calculation + 1] as u16) << 8)
-                    + sim_mem.mem[address_calculation] as u16;
-                sim_state.set_register_value(register, value);
+    instruction: &Instruction,
+) -> Execution {
+    let is_far = matches!(instruction.opcode, Mnemonic::CallFar | Mnemonic::JmpFar);
+    let (segment, offset) = if is_far {
+        far_target(sim_state, sim_mem, instruction)
+    } else {
+        let offset = match instruction.operands[0] {
+            Operand::ShortRelative(displacement) => {
+                sim_state.ip.wrapping_add(displacement as i16 as u16)
             }
-        },
+            Operand::NearRelative(displacement) => sim_state.ip.wrapping_add(displacement as u16),
+            ref operand => match read_operand(sim_state, sim_mem, instruction, operand) {
+                Some(offset) => offset,
+                None => return Execution::Unsupported,
+            },
+        };
+        (sim_state.cs, offset)
+    };
+
+    if matches!(instruction.opcode, Mnemonic::Call | Mnemonic::CallFar) {
+        if is_far {
+            push_word(sim_state, sim_mem, sim_state.cs);
+        }
+        push_word(sim_state, sim_mem, sim_state.ip);
+    }
+
+    sim_state.cs = segment;
+    sim_state.ip = offset;
+    Execution::Continue
+}
+
+/// Returns from a near or far call, then releases any bytes given by an immediate operand
+fn simulate_return(sim_state: &mut SimulationState, sim_mem: &SimMem, instruction: &Instruction) {
+    sim_state.ip = pop_word(sim_state, sim_mem);
+    if instruction.opcode == Mnemonic::Retf {
+        sim_state.cs = pop_word(sim_state, sim_mem);
+    }
+
+    if let Some(Operand::Immediate(release)) = instruction.operands.first() {
+        sim_state.sp = sim_state.sp.wrapping_add(*release);
     }
 }
 
-/// get the disassembly string and the number of bytes that were a part of the instruction for
-/// any disassembly with the form [opcode:6 d:1 w:1] [mod:2 reg:3 rm:3] [disp-lo] [disp-hi]
-fn mem_mem_disassembly(
-    opcode: OpCode,
-    machine_code: &Vec<u8>,
-    index: usize,
+/// Transfers control to an interrupt handler. Pushes the flags, cs and ip, clears the interrupt and
+/// trap flags, then loads cs:ip from the interrupt vector table at the bottom of memory.
+/// interrupt_number: the index of the handler's 4-byte entry in the interrupt vector table
+pub fn raise_interrupt(
     sim_state: &mut SimulationState,
     sim_mem: &mut SimMem,
-) -> i8 {
-    let first_byte = machine_code[index];
+    interrupt_number: u8,
+) {
+    push_word(sim_state, sim_mem, sim_state.get_flags_register());
+    push_word(sim_state, sim_mem, sim_state.cs);
+    push_word(sim_state, sim_mem, sim_state.ip);
+    sim_state.interrupt_flag = false;
+    sim_state.trap_flag = false;
+
+    let vector_offset = (interrupt_number as u16) * 4;
+    sim_state.ip = sim_mem.read_segmented(0, vector_offset, WordByte::Word);
+    sim_state.cs = sim_mem.read_segmented(0, vector_offset + 2, WordByte::Word);
+}
 
-    let direction: Direction = ((first_byte & 0b00000010) >> 1).into();
-    let word_byte: WordByte = (first_byte & 0b00000001).into();
+/// Whether the simulation keeps running after an instruction
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Execution {
+    Continue,
+    Halt,
+    /// the instruction can't be simulated, like in and out, so it had no effect
+    Unsupported,
+}
 
-    let second_byte = machine_code[index + 1];
-    let mode: Mode = ((second_byte & 0b11000000) >> 6).into();
-    let register_field = (second_byte & 0b00111000) >> 3;
-    let register = get_register_enum(register_field, word_byte);
+/// What executing an instruction did
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Executed {
+    pub execution: Execution,
+    /// whether control was transferred instead of falling through to the next instruction, by a
+    /// taken jump or loop, a call, return or interrupt, or a divide error
+    pub transferred_control: bool,
+}
 
-    let index_increment = match mode {
-        Mode::MemNoDisplacement => {
-            let rm_field = second_byte & 0b00000111;
+/// A native interrupt handler. It runs in place of the handler in the interrupt vector table, with
+/// ip already pointing past the instruction that raised the interrupt, and nothing pushed.
+pub type InterruptHook = Box<dyn FnMut(&mut SimulationState, &mut SimMem) -> Execution>;
 
-            let (address_calculation, displacement_byte_count) =
-                no_displacement_address(&sim_state, rm_field, &machine_code, index);
+/// Native handlers for interrupt numbers, which take priority over the interrupt vector table
+#[derive(Default)]
+pub struct InterruptHooks {
+    hooks: HashMap<u8, InterruptHook>,
+}
 
-            simulate_mem_mem(
-                sim_state,
-                sim_mem,
-                direction,
-                word_byte,
-                address_calculation,
-                register,
-            );
+impl InterruptHooks {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Services an interrupt number natively, replacing any previous hook for it
+    pub fn insert(&mut self, interrupt_number: u8, hook: InterruptHook) {
+        self.hooks.insert(interrupt_number, hook);
+    }
 
-            2 + displacement_byte_count
+    /// Returns an interrupt number to its handler in the interrupt vector table
+    pub fn remove(&mut self, interrupt_number: u8) -> Option<InterruptHook> {
+        self.hooks.remove(&interrupt_number)
+    }
+}
+
+/// Runs the hook for an interrupt number if there is one, otherwise transfers control to the
+/// handler in the interrupt vector table
+pub fn service_interrupt(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    interrupt_hooks: &mut InterruptHooks,
+    interrupt_number: u8,
+) -> Execution {
+    match interrupt_hooks.hooks.get_mut(&interrupt_number) {
+        Some(hook) => hook(sim_state, sim_mem),
+        None => {
+            raise_interrupt(sim_state, sim_mem, interrupt_number);
+            Execution::Continue
         }
-        Mode::Mem8BitDisplacement => {
-            let rm_field = second_byte & 0b0000111;
-            let displacement = machine_code[index + 2];
-            let address_calculation =
-                rm_field_to_displacement(&sim_state, rm_field, displacement as u16);
+    }
+}
 
-            simulate_mem_mem(
-                sim_state,
-                sim_mem,
-                direction,
-                word_byte,
-                address_calculation,
-                register,
-            );
+/// Returns from an interrupt handler by popping ip, cs and the flags
+fn simulate_interrupt_return(sim_state: &mut SimulationState, sim_mem: &SimMem) {
+    sim_state.ip = pop_word(sim_state, sim_mem);
+    sim_state.cs = pop_word(sim_state, sim_mem);
+    let flags_register = pop_word(sim_state, sim_mem);
+    sim_state.set_flags_register(flags_register);
+}
+
+/// sign extends the low byte of a value when word_byte is Byte
+fn sign_extend(value: u16, word_byte: WordByte) -> i32 {
+    match word_byte {
+        WordByte::Byte => value as u8 as i8 as i32,
+        WordByte::Word => value as i16 as i32,
+    }
+}
+
+/// performs mul and imul. Byte operands multiply al into ax, word operands multiply ax into dx:ax.
+/// Carry and overflow are set when the upper half of the result is significant. The other
+/// arithmetic flags are undefined and left unchanged.
+/// returns: Unsupported when the operand can't be read
+fn simulate_multiply(
+    sim_state: &mut SimulationState,
+    sim_mem: &SimMem,
+    instruction: &Instruction,
+) -> Execution {
+    let operand = &instruction.operands[0];
+    let Some(source) = read_operand(sim_state, sim_mem, instruction, operand) else {
+        return Execution::Unsupported;
+    };
+    let word_byte = operand_width(operand);
+    let accumulator = sim_state.ax & width_mask(word_byte);
+
+    let (product, upper_half_significant) = if instruction.opcode == Mnemonic::Mul {
+        let product = (accumulator as u32) * ((source & width_mask(word_byte)) as u32);
+        let upper_half = match word_byte {
+            WordByte::Byte => product >> 8,
+            WordByte::Word => product >> 16,
+        };
+        (product, upper_half != 0)
+    } else {
+        let product = sign_extend(accumulator, word_byte) * sign_extend(source, word_byte);
+        // the result fits in the lower half if sign extending the lower half gives it back
+        let lower_half = sign_extend(product as u16, word_byte);
+        (product as u32, lower_half != product)
+    };
+
+    match word_byte {
+        WordByte::Byte => sim_state.ax = product as u16,
+        WordByte::Word => {
+            sim_state.ax = product as u16;
+            sim_state.dx = (product >> 16) as u16;
+        }
+    }
+    sim_state.carry_flag = upper_half_significant;
+    sim_state.overflow_flag = upper_half_significant;
+    Execution::Continue
+}
+
+/// performs div and idiv. Byte operands divide ax into al with the remainder in ah, word
+/// operands divide dx:ax into ax with the remainder in dx. Dividing by zero or producing a
+/// quotient that does not fit raises interrupt 0. The arithmetic flags are undefined and left
+/// unchanged.
+/// returns: how the simulation continues after the divide error interrupt, if one was raised, or
+/// Unsupported when the operand can't be read
+fn simulate_divide(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    interrupt_hooks: &mut InterruptHooks,
+    instruction: &Instruction,
+) -> Option<Execution> {
+    let operand = &instruction.operands[0];
+    let Some(source) = read_operand(sim_state, sim_mem, instruction, operand) else {
+        return Some(Execution::Unsupported);
+    };
+    let word_byte = operand_width(operand);
+    let source = source & width_mask(word_byte);
+    let dividend = match word_byte {
+        WordByte::Byte => sim_state.ax as u32,
+        WordByte::Word => ((sim_state.dx as u32) << 16) | sim_state.ax as u32,
+    };
 
-            3
+    let result = if source == 0 {
+        None
+    } else if instruction.opcode == Mnemonic::Div {
+        let quotient = dividend / source as u32;
+        let remainder = dividend % source as u32;
+        if quotient > width_mask(word_byte) as u32 {
+            None
+        } else {
+            Some((quotient as u16, remainder as u16))
+        }
+    } else {
+        let dividend = match word_byte {
+            WordByte::Byte => dividend as u16 as i16 as i32,
+            WordByte::Word => dividend as i32,
+        };
+        let divisor = sign_extend(source, word_byte);
+        // rust division truncates toward zero and gives the remainder the dividend's sign, like
+        // the 8086
+        let quotient = dividend as i64 / divisor as i64;
+        let remainder = dividend as i64 % divisor as i64;
+        // the 8086 rejects the most negative quotient, unlike later processors
+        let limit = (sign_bit(word_byte) - 1) as i64;
+        if quotient.abs() > limit {
+            None
+        } else {
+            Some((quotient as u16, remainder as u16))
         }
-        Mode::Mem16BitDisplacement => {
-            let rm_field = second_byte & 0b0000111;
-            let displacement = concat_bytes(machine_code[index + 3], machine_code[index + 2]);
-            let address_calculation = rm_field_to_displacement(&sim_state, rm_field, displacement);
+    };
 
-            simulate_mem_mem(
+    match (result, word_byte) {
+        (None, _) => {
+            return Some(service_interrupt(
                 sim_state,
                 sim_mem,
-                direction,
-                word_byte,
-                address_calculation,
-                register,
-            );
-
-            4
+                interrupt_hooks,
+                DIVIDE_ERROR_INTERRUPT,
+            ))
+        }
+        (Some((quotient, remainder)), WordByte::Byte) => {
+            sim_state.ax = ((remainder & 0xFF) << 8) | (quotient & 0xFF);
         }
-        Mode::Register => {
-            let second_register = get_rm_register_field(second_byte, word_byte);
+        (Some((quotient, remainder)), WordByte::Word) => {
+            sim_state.ax = quotient;
+            sim_state.dx = remainder;
+        }
+    }
 
-            let (src_register, dest_register) = match direction {
-                Direction::RegRm => (register, second_register),
-                Direction::RmReg => (second_register, register),
-            };
+    None
+}
 
-            // update simstate for register to register mem mov
-            match opcode {
-                OpCode::MovMem => {
-                    let value = sim_state.get_register_value(src_register);
-                    sim_state.set_register_value(dest_register, value);
-                }
-                OpCode::AddMemMem => {
-                    let operand_value = sim_state.get_register_value(src_register);
-                    let dest_value = sim_state.get_register_value(dest_register);
-                    let value = operand_value + dest_value;
-                    sim_state.set_register_value(dest_register, value);
-                    sim_state.set_flags(value);
-                }
-                OpCode::SubMemMem => {
-                    let operand_value = sim_state.get_register_value(src_register);
-                    let dest_value = sim_state.get_register_value(dest_register);
-                    let value = if dest_value > operand_value {
-                        dest_value - operand_value
-                    } else {
-                        let diff = operand_value - dest_value;
-                        let low_bytes = 0b1000000000000000 - diff;
-                        0b1000000000000000 + low_bytes
-                    };
-                    sim_state.set_register_value(dest_register, value);
-                    sim_state.set_flags(value);
-                }
-                OpCode::CmpMemMem => {
-                    let operand_value = sim_state.get_register_value(src_register);
-                    let dest_value = sim_state.get_register_value(dest_register);
-                    let value = if dest_value >= operand_value {
-                        dest_value - operand_value
-                    } else {
-                        let diff = operand_value - dest_value;
-                        let low_bytes = 0b1000000000000000 - diff;
-                        0b1000000000000000 + low_bytes
-                    };
-
-                    sim_state.set_flags(value);
-                }
-                _ => panic!("Unexpected opcode for mem to mem instruction"),
-            };
+/// performs daa and das. The 8086 applies the whole correction to al with one addition or
+/// subtraction, so the undefined overflow flag comes from that operation. When the auxiliary carry
+/// is already set, the high digit is only corrected above 0x9F instead of 0x99.
+fn simulate_decimal_adjust(sim_state: &mut SimulationState, instruction: &Instruction) {
+    let al = sim_state.ax & 0xFF;
+    let (auxiliary_carry, carry) = (sim_state.auxiliary_carry_flag, sim_state.carry_flag);
 
-            2
-        }
+    let low_digit_adjust = (al & 0x0F) > 9 || auxiliary_carry;
+    let high_digit_limit = if auxiliary_carry { 0x9F } else { 0x99 };
+    let high_digit_adjust = al > high_digit_limit || carry;
+    let correction = (low_digit_adjust as u16 * 0x06) | (high_digit_adjust as u16 * 0x60);
+
+    let result = if instruction.opcode == Mnemonic::Daa {
+        sim_state.add_with_flags(al, correction, false, WordByte::Byte)
+    } else {
+        sim_state.subtract_with_flags(al, correction, false, WordByte::Byte)
     };
+    sim_state.auxiliary_carry_flag = low_digit_adjust;
+    sim_state.carry_flag = high_digit_adjust;
+    sim_state.ax = (sim_state.ax & 0xFF00) | result;
+}
+
+/// performs aaa and aas. The 8086 adjusts al and ah separately, so al never carries into ah. The
+/// undefined sign, zero, parity and overflow flags come from the adjustment of al, before its high
+/// digit is cleared.
+fn simulate_ascii_adjust(sim_state: &mut SimulationState, instruction: &Instruction) {
+    let al = sim_state.ax & 0xFF;
+    let mut ah = sim_state.ax >> 8;
 
-    index_increment as i8
+    let adjust = (al & 0x0F) > 9 || sim_state.auxiliary_carry_flag;
+    let correction = adjust as u16 * 0x06;
+
+    let result = if instruction.opcode == Mnemonic::Aaa {
+        ah = ah.wrapping_add(adjust as u16);
+        sim_state.add_with_flags(al, correction, false, WordByte::Byte)
+    } else {
+        ah = ah.wrapping_sub(adjust as u16);
+        sim_state.subtract_with_flags(al, correction, false, WordByte::Byte)
+    };
+    sim_state.auxiliary_carry_flag = adjust;
+    sim_state.carry_flag = adjust;
+    sim_state.ax = ((ah & 0xFF) << 8) | (result & 0x0F);
 }
 
-/// common function for accumulator arithmetic
-/// operation: the string for the operation. e.g. 'add', 'sub', 'cmp'
-/// machine_code: the vector containing the machine code
-/// index: the index for the first byte (containing the opcode)
-fn accumulator_arithmetic(_operation: OpCode, machine_code: &Vec<u8>, index: usize) -> i8 {
-    let first_byte = machine_code[index];
+/// performs aam and aad with the base given by their immediate, usually 10. aam with a base of 0
+/// raises the divide error interrupt. aam sets the flags like a logic instruction on al, while aad
+/// adds ah * base to al and takes all of its flags from that addition.
+/// returns: how the simulation continues after the divide error interrupt, if one was raised
+fn simulate_ascii_multiply_divide(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    interrupt_hooks: &mut InterruptHooks,
+    instruction: &Instruction,
+) -> Option<Execution> {
+    let base = match instruction.operands.as_slice() {
+        [Operand::Immediate(base)] => *base & 0xFF,
+        _ => panic!("Expected an immediate base"),
+    };
+    let al = sim_state.ax & 0xFF;
+    let ah = sim_state.ax >> 8;
 
-    let word_byte: WordByte = (first_byte & 0b00000001).into();
-    let (_, _, index_increment) = match word_byte {
-        WordByte::Byte => {
-            let data = machine_code[index + 1] as u16;
-            (data, "al", 2)
+    if instruction.opcode == Mnemonic::Aam {
+        if base == 0 {
+            return Some(service_interrupt(
+                sim_state,
+                sim_mem,
+                interrupt_hooks,
+                DIVIDE_ERROR_INTERRUPT,
+            ));
         }
-        WordByte::Word => {
-            let data = concat_bytes(machine_code[index + 2], machine_code[index + 1]);
-            (data, "ax", 3)
+        let (quotient, remainder) = (al / base, al % base);
+        sim_state.set_logic_flags(remainder, WordByte::Byte);
+        sim_state.ax = (quotient << 8) | remainder;
+    } else {
+        let result = sim_state.add_with_flags(al, ah.wrapping_mul(base), false, WordByte::Byte);
+        sim_state.ax = result;
+    }
+
+    None
+}
+
+/// Whether a conditional jump is taken
+/// opcode: one of the jcc mnemonics
+fn jump_condition(sim_state: &SimulationState, opcode: Mnemonic) -> bool {
+    let less = sim_state.sign_flag != sim_state.overflow_flag;
+
+    match opcode {
+        Mnemonic::Je => sim_state.zero_flag,
+        Mnemonic::JneJnz => !sim_state.zero_flag,
+        Mnemonic::Jl => less,
+        Mnemonic::Jle => less || sim_state.zero_flag,
+        Mnemonic::Jnl => !less,
+        Mnemonic::Jg => !less && !sim_state.zero_flag,
+        Mnemonic::Jb => sim_state.carry_flag,
+        Mnemonic::Jbe => sim_state.carry_flag || sim_state.zero_flag,
+        Mnemonic::Jnb => !sim_state.carry_flag,
+        Mnemonic::Ja => !sim_state.carry_flag && !sim_state.zero_flag,
+        Mnemonic::Jp => sim_state.parity_flag,
+        Mnemonic::Jnp => !sim_state.parity_flag,
+        Mnemonic::Jo => sim_state.overflow_flag,
+        Mnemonic::Jno => !sim_state.overflow_flag,
+        Mnemonic::Js => sim_state.sign_flag,
+        Mnemonic::Jns => !sim_state.sign_flag,
+        _ => panic!("Expected a conditional jump"),
+    }
+}
+
+/// The width of a register or memory operand
+fn operand_width(operand: &Operand) -> WordByte {
+    match operand {
+        Operand::Register(register) => get_register_width(*register),
+        Operand::Memory(address) => address.width,
+        _ => panic!("Expected a register or memory operand"),
+    }
+}
+
+/// performs the two operand arithmetic and logic instructions on register and memory destinations
+/// returns: Unsupported when an operand can't be read
+fn simulate_arithmetic(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    instruction: &Instruction,
+) -> Execution {
+    let (destination, source) = match instruction.operands.as_slice() {
+        [destination, source] => (destination, source),
+        _ => panic!("Expected two operands for arithmetic"),
+    };
+
+    let (Some(destination_value), Some(source_value)) = (
+        read_operand(sim_state, sim_mem, instruction, destination),
+        read_operand(sim_state, sim_mem, instruction, source),
+    ) else {
+        return Execution::Unsupported;
+    };
+    let word_byte = operand_width(destination);
+    let carry = sim_state.carry_flag;
+
+    let value = match instruction.opcode {
+        Mnemonic::Add => {
+            sim_state.add_with_flags(destination_value, source_value, false, word_byte)
+        }
+        Mnemonic::Adc => {
+            sim_state.add_with_flags(destination_value, source_value, carry, word_byte)
+        }
+        Mnemonic::Sub | Mnemonic::Cmp => {
+            sim_state.subtract_with_flags(destination_value, source_value, false, word_byte)
         }
+        Mnemonic::Sbb => {
+            sim_state.subtract_with_flags(destination_value, source_value, carry, word_byte)
+        }
+        Mnemonic::And | Mnemonic::Test | Mnemonic::Or | Mnemonic::Xor => {
+            let value = match instruction.opcode {
+                Mnemonic::Or => destination_value | source_value,
+                Mnemonic::Xor => destination_value ^ source_value,
+                _ => destination_value & source_value,
+            };
+            sim_state.set_logic_flags(value, word_byte);
+            value
+        }
+        _ => panic!("Expected an arithmetic or logic instruction"),
     };
 
-    index_increment as i8
-}
-
-/// Get the immediate from the instruction and return both it and the number of bytes in the immediate value
-/// machine_code: the vector containing all of the machine code
-/// index: the index of the first byte in the instruction
-/// low_byte_index: the index of the less significant byte. Will not be used if immediate is single byte
-/// high_byte_index: the index of the more significant byte. Will not be used if immediate is single byte
-/// word_byte: the word/byte field enum
-/// sign_extension: the sign_extension field
-fn get_immediate(
-    machine_code: &Vec<u8>,
-    index: usize,
-    low_byte_index: usize,
-    high_byte_index: usize,
-    word_byte: WordByte,
-    sign_extension: u8,
-) -> (u16, usize) {
-    let byte_value = (machine_code[index + low_byte_index] as u16, 1);
+    if !matches!(instruction.opcode, Mnemonic::Cmp | Mnemonic::Test) {
+        write_operand(sim_state, sim_mem, instruction, destination, value);
+    }
+    Execution::Continue
+}
 
-    match word_byte {
-        WordByte::Byte => byte_value,
-        WordByte::Word => {
-            if sign_extension == 0 {
-                (
-                    concat_bytes(
-                        machine_code[index + high_byte_index],
-                        machine_code[index + low_byte_index],
-                    ),
-                    2,
-                )
+/// performs the single operand instructions not, neg, inc and dec
+/// returns: Unsupported when the operand can't be read
+fn simulate_unary(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    instruction: &Instruction,
+) -> Execution {
+    let operand = &instruction.operands[0];
+    let Some(value) = read_operand(sim_state, sim_mem, instruction, operand) else {
+        return Execution::Unsupported;
+    };
+    let word_byte = operand_width(operand);
+
+    let result = match instruction.opcode {
+        // not changes no flags
+        Mnemonic::Not => !value,
+        Mnemonic::Neg => sim_state.subtract_with_flags(0, value, false, word_byte),
+        // inc and dec leave the carry flag alone
+        Mnemonic::Inc | Mnemonic::Dec => {
+            let carry = sim_state.carry_flag;
+            let result = if instruction.opcode == Mnemonic::Inc {
+                sim_state.add_with_flags(value, 1, false, word_byte)
             } else {
-                byte_value
-            }
+                sim_state.subtract_with_flags(value, 1, false, word_byte)
+            };
+            sim_state.carry_flag = carry;
+            result
         }
-    }
+        _ => panic!("Expected a single operand instruction"),
+    };
+
+    write_operand(sim_state, sim_mem, instruction, operand, result);
+    Execution::Continue
 }
 
-/// Common function for jump opcodes
-/// machine_code: the vector containing all of our machine code
-/// index: the index of the first byte of the instruction
-/// operation: the jump operation string
-fn get_jump_offset(machine_code: &Vec<u8>, index: usize) -> i8 {
-    // add 2 b/c ip register *should* be incremented before execution
-    machine_code[index + 1] as i8 + 2
+/// performs the shifts and rotates, one bit at a time. The count is 1 or cl, and the 8086 uses all
+/// 8 bits of cl. A count of zero changes nothing, including the flags.
+/// returns: Unsupported when the operand can't be read
+fn simulate_shift_rotate(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    instruction: &Instruction,
+) -> Execution {
+    let (operand, count) = match instruction.operands.as_slice() {
+        [operand, Operand::Register(Register::Cl)] => (operand, sim_state.cx & 0xFF),
+        [operand, _] => (operand, 1),
+        _ => panic!("Expected an operand and a count"),
+    };
+    let Some(value) = read_operand(sim_state, sim_mem, instruction, operand) else {
+        return Execution::Unsupported;
+    };
+    if count == 0 {
+        return Execution::Continue;
+    }
+
+    let word_byte = operand_width(operand);
+    let mask = width_mask(word_byte);
+    let sign = sign_bit(word_byte);
+    let mut value = value & mask;
+
+    for _ in 0..count {
+        let low_bit = (value & 1) != 0;
+        let high_bit = (value & sign) != 0;
+
+        value = match instruction.opcode {
+            Mnemonic::Shl => {
+                sim_state.carry_flag = high_bit;
+                (value << 1) & mask
+            }
+            Mnemonic::Shr => {
+                sim_state.carry_flag = low_bit;
+                value >> 1
+            }
+            Mnemonic::Sar => {
+                sim_state.carry_flag = low_bit;
+                (value >> 1) | (value & sign)
+            }
+            Mnemonic::Rol => {
+                sim_state.carry_flag = high_bit;
+                ((value << 1) & mask) | high_bit as u16
+            }
+            Mnemonic::Ror => {
+                sim_state.carry_flag = low_bit;
+                (value >> 1) | if low_bit { sign } else { 0 }
+            }
+            Mnemonic::Rcl => {
+                let carry = sim_state.carry_flag;
+                sim_state.carry_flag = high_bit;
+                ((value << 1) & mask) | carry as u16
+            }
+            Mnemonic::Rcr => {
+                let carry = sim_state.carry_flag;
+                sim_state.carry_flag = low_bit;
+                (value >> 1) | if carry { sign } else { 0 }
+            }
+            _ => panic!("Expected a shift or rotate"),
+        };
+
+        // overflow is only defined for a count of 1, but the 8086 computes it on every step
+        let result_high_bit = (value & sign) != 0;
+        sim_state.overflow_flag = match instruction.opcode {
+            // whether the sign changed
+            Mnemonic::Shl | Mnemonic::Rol | Mnemonic::Rcl => {
+                result_high_bit != sim_state.carry_flag
+            }
+            Mnemonic::Shr => high_bit,
+            Mnemonic::Sar => false,
+            // whether the top two bits differ
+            _ => result_high_bit != ((value & (sign >> 1)) != 0),
+        };
+    }
+
+    // rotates only change carry and overflow
+    if matches!(
+        instruction.opcode,
+        Mnemonic::Shl | Mnemonic::Shr | Mnemonic::Sar
+    ) {
+        sim_state.set_result_flags(value, word_byte);
+    }
+
+    write_operand(sim_state, sim_mem, instruction, operand, value);
+    Execution::Continue
 }
 
-fn interpret_unsigned_as_signed(a: u16, bytes: usize) -> i16 {
-    let (neg_part, pos_part) = if bytes == 1 {
-        ((a & 0x80) as i16, (a & 0x7F) as i16)
-    } else {
-        ((a & 0x8000) as i16, (a & 0x7FFF) as i16)
+/// performs one iteration of movs, cmps, scas, lods or stos. The source is ds:si, which a segment
+/// override can change, and the destination is always es:di. si and di step by the operand size,
+/// backwards when the direction flag is set.
+fn simulate_string_step(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    instruction: &Instruction,
+) {
+    let word_byte = match instruction.opcode {
+        Mnemonic::Movsb | Mnemonic::Cmpsb | Mnemonic::Scasb | Mnemonic::Lodsb | Mnemonic::Stosb => {
+            WordByte::Byte
+        }
+        _ => WordByte::Word,
+    };
+    let accumulator = match word_byte {
+        WordByte::Byte => Register::Al,
+        WordByte::Word => Register::Ax,
+    };
+    let step = match (word_byte, sim_state.direction_flag) {
+        (WordByte::Byte, false) => 1u16,
+        (WordByte::Word, false) => 2,
+        (WordByte::Byte, true) => 1u16.wrapping_neg(),
+        (WordByte::Word, true) => 2u16.wrapping_neg(),
     };
 
-    -1 * neg_part + pos_part
+    let source_segment = sim_state
+        .get_segment_register_value(instruction.prefixes.segment.unwrap_or(SegmentRegister::Ds));
+    let (uses_source, uses_destination) = match instruction.opcode {
+        Mnemonic::Movsb | Mnemonic::Movsw | Mnemonic::Cmpsb | Mnemonic::Cmpsw => (true, true),
+        Mnemonic::Lodsb | Mnemonic::Lodsw => (true, false),
+        _ => (false, true),
+    };
+
+    match instruction.opcode {
+        Mnemonic::Movsb | Mnemonic::Movsw => {
+            let value = sim_mem.read_segmented(source_segment, sim_state.si, word_byte);
+            sim_mem.write_segmented(sim_state.es, sim_state.di, word_byte, value);
+        }
+        Mnemonic::Cmpsb | Mnemonic::Cmpsw => {
+            let source = sim_mem.read_segmented(source_segment, sim_state.si, word_byte);
+            let destination = sim_mem.read_segmented(sim_state.es, sim_state.di, word_byte);
+            sim_state.subtract_with_flags(source, destination, false, word_byte);
+        }
+        Mnemonic::Scasb | Mnemonic::Scasw => {
+            let value = sim_state.get_register_value(accumulator);
+            let destination = sim_mem.read_segmented(sim_state.es, sim_state.di, word_byte);
+            sim_state.subtract_with_flags(value, destination, false, word_byte);
+        }
+        Mnemonic::Lodsb | Mnemonic::Lodsw => {
+            let value = sim_mem.read_segmented(source_segment, sim_state.si, word_byte);
+            sim_state.set_register_value(accumulator, value);
+        }
+        Mnemonic::Stosb | Mnemonic::Stosw => {
+            let value = sim_state.get_register_value(accumulator);
+            sim_mem.write_segmented(sim_state.es, sim_state.di, word_byte, value);
+        }
+        _ => panic!("Expected a string instruction"),
+    }
+
+    if uses_source {
+        sim_state.si = sim_state.si.wrapping_add(step);
+    }
+    if uses_destination {
+        sim_state.di = sim_state.di.wrapping_add(step);
+    }
 }
 
-pub fn simulate(machine_code: &Vec<u8>) -> String {
-    let mut sim_log = "".to_owned();
-    let mut sim_state = SimulationState {
-        ..Default::default()
+/// performs a string instruction, repeating it cx times when it has a rep prefix. repe and repne
+/// also stop cmps and scas as soon as the zero flag no longer matches.
+fn simulate_string(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    instruction: &Instruction,
+) {
+    let repeat = match instruction.prefixes.repeat {
+        None => return simulate_string_step(sim_state, sim_mem, instruction),
+        Some(repeat) => repeat,
     };
-    let mut sim_mem = SimMem::new(2 << 20);
+    let compares = matches!(
+        instruction.opcode,
+        Mnemonic::Cmpsb | Mnemonic::Cmpsw | Mnemonic::Scasb | Mnemonic::Scasw
+    );
+
+    while sim_state.cx != 0 {
+        simulate_string_step(sim_state, sim_mem, instruction);
+        sim_state.cx = sim_state.cx.wrapping_sub(1);
+
+        // the zero flag only ends the repetition for the comparing instructions
+        if compares && sim_state.zero_flag != (repeat == Repeat::Rep) {
+            break;
+        }
+    }
+}
 
-    while (sim_state.ip as usize) < machine_code.len() {
-        let previous_state = sim_state.clone();
+/// Where and how a program is simulated
+/// load_segment: the segment the program is loaded into. cs starts with this value.
+/// load_offset: the offset within load_segment of the first byte of the program. ip starts with
+/// this value.
+/// timing: how the clocks of each instruction are estimated
+#[derive(Default, Clone)]
+pub struct SimulationConfig {
+    pub load_segment: u16,
+    pub load_offset: u16,
+    pub timing: Timing,
+}
 
-        let index: usize = sim_state.ip as usize;
-
-        let first_byte = machine_code[index];
-        let opcode = get_opcode(first_byte);
-
-        let ip_offset: i8 = match opcode {
-            OpCode::RegisterImmediateMov => {
-                let word_byte: WordByte = ((first_byte & 0b00001000) >> 3).into();
-                let register_field = first_byte & 0b00000111;
-                let register = get_register_enum(register_field, word_byte);
-                let second_byte = machine_code[index + 1];
-
-                let (immediate, immediate_bytes) = match word_byte {
-                    WordByte::Byte => (second_byte as u16, 1),
-                    WordByte::Word => {
-                        let third_byte = machine_code[index + 2];
-                        let immediate = concat_bytes(third_byte, second_byte);
-                        (immediate, 2)
-                    }
-                };
+/// the interrupt raised when a division fails
+const DIVIDE_ERROR_INTERRUPT: u8 = 0;
 
-                // 1 byte for the opcode + the number of bytes in the immediate
-                let index_increment = immediate_bytes + 1;
+/// the interrupt raised by int3
+const BREAKPOINT_INTERRUPT: u8 = 3;
 
-                sim_state.set_register_value(register, immediate);
+/// the interrupt raised by into when the overflow flag is set
+const OVERFLOW_INTERRUPT: u8 = 4;
 
-                index_increment
-            }
-            OpCode::ImmediateToMem => {
-                let word_byte: WordByte = (first_byte & 0b00000001).into();
-
-                let second_byte = machine_code[index + 1];
-
-                let mode: Mode = ((second_byte & 0b11000000) >> 6).into();
-
-                let (dest_arg, immediate, index_increment) = match mode {
-                    Mode::MemNoDisplacement => {
-                        let rm_field = second_byte & 0b00000111;
-
-                        let (address_calculation, displacement_bytes) =
-                            no_displacement_address_arithmetic(rm_field, machine_code, index);
-
-                        // 2 bytes + displacment bytes is the low data byte
-                        let low_byte_index = 2 + displacement_bytes;
-                        // 2 bytes + displacment bytes + 1 low byte + 1 is the high data byte
-                        let high_byte_index = 3 + displacement_bytes;
-
-                        let (immediate, data_increment) = get_immediate(
-                            machine_code,
-                            index,
-                            low_byte_index,
-                            high_byte_index,
-                            word_byte,
-                            0,
-                        );
-
-                        (
-                            address_calculation,
-                            immediate,
-                            2 + displacement_bytes + data_increment,
-                        )
-                    }
-                    Mode::Mem8BitDisplacement => {
-                        let rm_field = second_byte & 0b0000111;
-                        let displacement = machine_code[index + 2];
-                        let address_calculation =
-                            rm_field_to_displacement(&sim_state, rm_field, displacement as u16);
-
-                        let (immediate, data_increment) =
-                            get_immediate(&machine_code, index, 3, 4, word_byte, 0);
-
-                        (address_calculation, immediate, 3 + data_increment)
-                    }
-                    Mode::Mem16BitDisplacement => {
-                        let rm_field = second_byte & 0b0000111;
-                        let displacement =
-                            concat_bytes(machine_code[index + 3], machine_code[index + 2]);
-                        let address_calculation =
-                            rm_field_to_displacement(&sim_state, rm_field, displacement);
-
-                        let (immediate, data_increment) =
-                            get_immediate(&machine_code, index, 4, 5, word_byte, 0);
-
-                        (address_calculation, immediate, 4 + data_increment)
-                    }
-                    Mode::Register => {
-                        panic!("Unexpected register mode when moving to memory")
-                    }
-                };
+/// the most bytes fetched for one instruction. 8086 instructions are at most 6 bytes plus prefixes.
+const MAX_INSTRUCTION_BYTES: u16 = 16;
 
-                // let instruction = format!("mov {}, {}\n", dest_arg, immediate);
+/// Copies a program into memory at the configured load address and points cs:ip at it
+pub fn load_program(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    machine_code: &[u8],
+    config: &SimulationConfig,
+) {
+    let load_address = physical_address(config.load_segment, config.load_offset);
+    for (index, byte) in machine_code.iter().enumerate() {
+        sim_mem.write_byte(load_address + index, *byte);
+    }
+
+    sim_state.cs = config.load_segment;
+    sim_state.ip = config.load_offset;
+}
 
-                match word_byte {
-                    WordByte::Byte => {
-                        sim_mem.mem[dest_arg] = immediate as u8;
-                    }
-                    WordByte::Word => {
-                        sim_mem.mem[dest_arg] = (immediate & 0xFF) as u8;
-                        sim_mem.mem[dest_arg + 1] = ((immediate & 0xFF00) >> 8) as u8;
-                    }
-                }
+/// Decodes the instruction at cs:ip
+pub fn fetch_instruction(
+    sim_state: &SimulationState,
+    sim_mem: &SimMem,
+) -> Result<Instruction, DecodeError> {
+    // instruction bytes wrap around within the code segment
+    let bytes: Vec<u8> = (0..MAX_INSTRUCTION_BYTES)
+        .map(|index| {
+            sim_mem.peek_byte(physical_address(
+                sim_state.cs,
+                sim_state.ip.wrapping_add(index),
+            ))
+        })
+        .collect();
+
+    get_instruction(&bytes, 0).map_err(|mut error| {
+        error.offset = sim_state.ip as usize;
+        error
+    })
+}
 
-                index_increment as i8
+/// Executes a decoded instruction. ip must already point to the next instruction.
+/// interrupt_hooks: native handlers for interrupts the instruction raises
+/// returns: whether the simulation should halt, after a hlt or when an interrupt hook asks it to,
+/// and whether the instruction transferred control
+pub fn execute_instruction(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    interrupt_hooks: &mut InterruptHooks,
+    instruction: &Instruction,
+) -> Executed {
+    let mut execution = Execution::Continue;
+    let mut transferred_control = false;
+
+    match instruction.opcode {
+        Mnemonic::Mov => {
+            match read_operand(sim_state, sim_mem, instruction, &instruction.operands[1]) {
+                Some(value) => write_operand(
+                    sim_state,
+                    sim_mem,
+                    instruction,
+                    &instruction.operands[0],
+                    value,
+                ),
+                None => execution = Execution::Unsupported,
             }
-            OpCode::MovMem => mem_mem_disassembly(
-                OpCode::MovMem,
-                &machine_code,
-                index,
-                &mut sim_state,
-                &mut sim_mem,
-            ),
-            OpCode::AddMemMem => mem_mem_disassembly(
-                OpCode::AddMemMem,
-                &machine_code,
-                index,
-                &mut sim_state,
-                &mut sim_mem,
-            ),
-            OpCode::SubMemMem => mem_mem_disassembly(
-                OpCode::SubMemMem,
-                &machine_code,
-                index,
-                &mut sim_state,
-                &mut sim_mem,
-            ),
-            OpCode::CmpMemMem => mem_mem_disassembly(
-                OpCode::CmpMemMem,
-                &machine_code,
-                index,
-                &mut sim_state,
-                &mut sim_mem,
-            ),
-            OpCode::ImmediateArithmetic => {
-                let word_byte: WordByte = (first_byte & 0b00000001).into();
-
-                let sign_extension = (first_byte & 0b00000010) >> 1;
-
-                let second_byte = machine_code[index + 1];
-                let mode: Mode = ((second_byte & 0b11000000) >> 6).into();
-                let arithmetic_code: ArithmeticOpCode = ((second_byte & 0b00111000) >> 3).into();
-
-                let index_increment = match mode {
-                    Mode::MemNoDisplacement => {
-                        let rm_field = second_byte & 0b00000111;
-
-                        let (_, displacement_bytes) =
-                            no_displacement_address_arithmetic(rm_field, &machine_code, index);
-
-                        // 2 bytes + displacment bytes is the low data byte
-                        let low_byte_index = 2 + displacement_bytes;
-                        // 2 bytes + displacment bytes + 1 low byte + 1 is the high data byte
-                        let high_byte_index = 3 + displacement_bytes;
-
-                        let (_, data_increment) = get_immediate(
-                            &machine_code,
-                            index,
-                            low_byte_index,
-                            high_byte_index,
-                            word_byte,
-                            sign_extension,
-                        );
-
-                        2 + displacement_bytes + data_increment
-                    }
-                    Mode::Mem8BitDisplacement => {
-                        let rm_field = second_byte & 0b0000111;
-                        let displacement = machine_code[index + 2];
-                        let _ = rm_field_to_displacement(&sim_state, rm_field, displacement as u16);
-
-                        let (_, data_increment) =
-                            get_immediate(&machine_code, index, 3, 4, word_byte, sign_extension);
-
-                        3 + data_increment
-                    }
-                    Mode::Mem16BitDisplacement => {
-                        let rm_field = second_byte & 0b0000111;
-                        let displacement =
-                            concat_bytes(machine_code[index + 3], machine_code[index + 2]);
-                        let _ = rm_field_to_displacement(&sim_state, rm_field, displacement);
-
-                        let (_, data_increment) =
-                            get_immediate(&machine_code, index, 4, 5, word_byte, sign_extension);
-
-                        4 + data_increment
-                    }
-                    Mode::Register => {
-                        let register = get_rm_register_field(second_byte, word_byte);
-                        let low_byte_index = 2;
-                        let high_byte_index = 3;
-                        let (immediate, immediate_bytes) = get_immediate(
-                            &machine_code,
-                            index,
-                            low_byte_index,
-                            high_byte_index,
-                            word_byte,
-                            sign_extension,
-                        );
-
-                        match arithmetic_code {
-                            ArithmeticOpCode::Add => {
-                                let value = if sign_extension == 0 {
-                                    sim_state.get_register_value(register) + immediate
-                                } else {
-                                    let signed_result = interpret_unsigned_as_signed(
-                                        sim_state.get_register_value(register),
-                                        2,
-                                    ) + interpret_unsigned_as_signed(
-                                        immediate,
-                                        immediate_bytes,
-                                    );
-                                    signed_result as u16
-                                };
-                                sim_state.set_register_value(register, value);
-                                sim_state.set_flags(value);
-                            }
-                            ArithmeticOpCode::Sub => {
-                                let value = if sign_extension == 0 {
-                                    sim_state.get_register_value(register) - immediate
-                                } else {
-                                    let signed_result = interpret_unsigned_as_signed(
-                                        sim_state.get_register_value(register),
-                                        2,
-                                    ) - interpret_unsigned_as_signed(
-                                        immediate,
-                                        immediate_bytes,
-                                    );
-                                    signed_result as u16
-                                };
-                                sim_state.set_register_value(register, value);
-                                sim_state.set_flags(value);
-                            }
-                            ArithmeticOpCode::Cmp => {
-                                let value = if sign_extension == 0 {
-                                    sim_state.get_register_value(register) - immediate
-                                } else {
-                                    let signed_result = interpret_unsigned_as_signed(
-                                        sim_state.get_register_value(register),
-                                        2,
-                                    ) - interpret_unsigned_as_signed(
-                                        immediate,
-                                        immediate_bytes,
-                                    );
-                                    signed_result as u16
-                                };
-                                sim_state.set_flags(value);
-                            }
-                        }
-
-                        2 + immediate_bytes
-                    }
+        }
+        Mnemonic::Add
+        | Mnemonic::Adc
+        | Mnemonic::Sub
+        | Mnemonic::Sbb
+        | Mnemonic::Cmp
+        | Mnemonic::And
+        | Mnemonic::Test
+        | Mnemonic::Or
+        | Mnemonic::Xor => execution = simulate_arithmetic(sim_state, sim_mem, instruction),
+        Mnemonic::Not | Mnemonic::Neg | Mnemonic::Inc | Mnemonic::Dec => {
+            execution = simulate_unary(sim_state, sim_mem, instruction)
+        }
+        Mnemonic::Shl
+        | Mnemonic::Shr
+        | Mnemonic::Sar
+        | Mnemonic::Rol
+        | Mnemonic::Ror
+        | Mnemonic::Rcl
+        | Mnemonic::Rcr => execution = simulate_shift_rotate(sim_state, sim_mem, instruction),
+        Mnemonic::JneJnz
+        | Mnemonic::Je
+        | Mnemonic::Jl
+        | Mnemonic::Jle
+        | Mnemonic::Jb
+        | Mnemonic::Jbe
+        | Mnemonic::Jp
+        | Mnemonic::Jo
+        | Mnemonic::Js
+        | Mnemonic::Jnl
+        | Mnemonic::Jg
+        | Mnemonic::Jnb
+        | Mnemonic::Ja
+        | Mnemonic::Jnp
+        | Mnemonic::Jno
+        | Mnemonic::Jns => {
+            // a taken jump transfers control even when its displacement is 0
+            transferred_control = jump_condition(sim_state, instruction.opcode);
+            if transferred_control {
+                relative_jump(sim_state, instruction);
+            }
+        }
+        Mnemonic::Loop | Mnemonic::Loopz | Mnemonic::Loopnz => {
+            // loops decrement cx without touching the flags
+            sim_state.cx = sim_state.cx.wrapping_sub(1);
+            transferred_control = sim_state.cx != 0
+                && match instruction.opcode {
+                    Mnemonic::Loopz => sim_state.zero_flag,
+                    Mnemonic::Loopnz => !sim_state.zero_flag,
+                    _ => true,
                 };
-
-                index_increment as i8
+            if transferred_control {
+                relative_jump(sim_state, instruction);
+            }
+        }
+        Mnemonic::Jcxz => {
+            transferred_control = sim_state.cx == 0;
+            if transferred_control {
+                relative_jump(sim_state, instruction);
+            }
+        }
+        Mnemonic::Push => {
+            let value = match instruction.operands[0] {
+                // the 8086 pushes the value of sp after it has been decremented
+                Operand::Register(Register::Sp) => Some(sim_state.sp.wrapping_sub(2)),
+                ref operand => read_operand(sim_state, sim_mem, instruction, operand),
+            };
+            match value {
+                Some(value) => push_word(sim_state, sim_mem, value),
+                None => execution = Execution::Unsupported,
             }
-            OpCode::ImmediateToAccumulator => {
-                accumulator_arithmetic(OpCode::ImmediateToAccumulator, &machine_code, index)
+        }
+        Mnemonic::Pop => {
+            let value = pop_word(sim_state, sim_mem);
+            write_operand(
+                sim_state,
+                sim_mem,
+                instruction,
+                &instruction.operands[0],
+                value,
+            );
+        }
+        Mnemonic::Pushf => {
+            let flags_register = sim_state.get_flags_register();
+            push_word(sim_state, sim_mem, flags_register);
+        }
+        Mnemonic::Popf => {
+            let flags_register = pop_word(sim_state, sim_mem);
+            sim_state.set_flags_register(flags_register);
+        }
+        Mnemonic::Call | Mnemonic::CallFar | Mnemonic::Jmp | Mnemonic::JmpFar => {
+            execution = simulate_call_jmp(sim_state, sim_mem, instruction);
+            transferred_control = execution != Execution::Unsupported;
+        }
+        Mnemonic::Ret | Mnemonic::Retf => {
+            simulate_return(sim_state, sim_mem, instruction);
+            transferred_control = true;
+        }
+        Mnemonic::Mul | Mnemonic::Imul => {
+            execution = simulate_multiply(sim_state, sim_mem, instruction)
+        }
+        Mnemonic::Div | Mnemonic::Idiv => {
+            if let Some(divide_execution) =
+                simulate_divide(sim_state, sim_mem, interrupt_hooks, instruction)
+            {
+                execution = divide_execution;
+                transferred_control = divide_execution != Execution::Unsupported;
             }
-            OpCode::ImmediateFromAccumulator => {
-                accumulator_arithmetic(OpCode::ImmediateFromAccumulator, &machine_code, index)
+        }
+        Mnemonic::Movsb
+        | Mnemonic::Movsw
+        | Mnemonic::Cmpsb
+        | Mnemonic::Cmpsw
+        | Mnemonic::Scasb
+        | Mnemonic::Scasw
+        | Mnemonic::Lodsb
+        | Mnemonic::Lodsw
+        | Mnemonic::Stosb
+        | Mnemonic::Stosw => simulate_string(sim_state, sim_mem, instruction),
+        Mnemonic::Daa | Mnemonic::Das => simulate_decimal_adjust(sim_state, instruction),
+        Mnemonic::Aaa | Mnemonic::Aas => simulate_ascii_adjust(sim_state, instruction),
+        Mnemonic::Aam | Mnemonic::Aad => {
+            if let Some(interrupt_execution) =
+                simulate_ascii_multiply_divide(sim_state, sim_mem, interrupt_hooks, instruction)
+            {
+                execution = interrupt_execution;
+                transferred_control = true;
             }
-            OpCode::CmpImmediateToAccumulator => {
-                accumulator_arithmetic(OpCode::CmpImmediateToAccumulator, &machine_code, index)
+        }
+        Mnemonic::Cbw => sim_state.ax = sim_state.ax as u8 as i8 as i16 as u16,
+        Mnemonic::Cwd => {
+            sim_state.dx = if (sim_state.ax & 0x8000) != 0 {
+                0xFFFF
+            } else {
+                0
+            }
+        }
+        Mnemonic::Xlat => {
+            // al indexes a table at ds:bx, unless the segment is overridden
+            let segment = sim_state.get_segment_register_value(
+                instruction.prefixes.segment.unwrap_or(SegmentRegister::Ds),
+            );
+            let offset = sim_state.bx.wrapping_add(sim_state.ax & 0xFF);
+            let value = sim_mem.read_segmented(segment, offset, WordByte::Byte);
+            sim_state.ax = (sim_state.ax & 0xFF00) | value;
+        }
+        // lahf and sahf transfer sign, zero, auxiliary carry, parity and carry through ah
+        Mnemonic::Lahf => {
+            sim_state.ax = (sim_state.ax & 0x00FF) | ((sim_state.get_flags_register() & 0xFF) << 8)
+        }
+        Mnemonic::Sahf => {
+            let flags_register = (sim_state.get_flags_register() & 0xFF00) | (sim_state.ax >> 8);
+            sim_state.set_flags_register(flags_register);
+        }
+        Mnemonic::Clc => sim_state.carry_flag = false,
+        Mnemonic::Stc => sim_state.carry_flag = true,
+        Mnemonic::Cmc => sim_state.carry_flag = !sim_state.carry_flag,
+        Mnemonic::Cld => sim_state.direction_flag = false,
+        Mnemonic::Std => sim_state.direction_flag = true,
+        Mnemonic::Cli => sim_state.interrupt_flag = false,
+        Mnemonic::Sti => sim_state.interrupt_flag = true,
+        Mnemonic::Int => {
+            let interrupt_number = match instruction.operands.as_slice() {
+                [Operand::Immediate(interrupt_number)] => *interrupt_number as u8,
+                _ => panic!("Expected an interrupt number"),
+            };
+            execution = service_interrupt(sim_state, sim_mem, interrupt_hooks, interrupt_number);
+            transferred_control = true;
+        }
+        Mnemonic::Int3 => {
+            execution =
+                service_interrupt(sim_state, sim_mem, interrupt_hooks, BREAKPOINT_INTERRUPT);
+            transferred_control = true;
+        }
+        Mnemonic::Into => {
+            transferred_control = sim_state.overflow_flag;
+            if transferred_control {
+                execution =
+                    service_interrupt(sim_state, sim_mem, interrupt_hooks, OVERFLOW_INTERRUPT);
             }
-            OpCode::JneJnz => {
-                if !sim_state.zero_flag {
-                    get_jump_offset(&machine_code, index)
-                } else {
-                    2
-                }
+        }
+        Mnemonic::Iret => {
+            simulate_interrupt_return(sim_state, sim_mem);
+            transferred_control = true;
+        }
+        Mnemonic::Hlt => execution = Execution::Halt,
+        Mnemonic::Xchg => match (
+            read_operand(sim_state, sim_mem, instruction, &instruction.operands[0]),
+            read_operand(sim_state, sim_mem, instruction, &instruction.operands[1]),
+        ) {
+            (Some(first), Some(second)) => {
+                write_operand(
+                    sim_state,
+                    sim_mem,
+                    instruction,
+                    &instruction.operands[0],
+                    second,
+                );
+                write_operand(
+                    sim_state,
+                    sim_mem,
+                    instruction,
+                    &instruction.operands[1],
+                    first,
+                );
             }
-            OpCode::Je => unimplemented!(),
-            OpCode::Jl => unimplemented!(),
-            OpCode::Jle => unimplemented!(),
-            OpCode::Jb => unimplemented!(),
-            OpCode::Jbe => unimplemented!(),
-            OpCode::Jp => unimplemented!(),
-            OpCode::Jo => unimplemented!(),
-            OpCode::Js => unimplemented!(),
-            OpCode::Jnl => unimplemented!(),
-            OpCode::Jg => unimplemented!(),
-            OpCode::Jnb => unimplemented!(),
-            OpCode::Ja => unimplemented!(),
-            OpCode::Jnp => unimplemented!(),
-            OpCode::Jno => unimplemented!(),
-            OpCode::Jns => unimplemented!(),
-            OpCode::Loop => unimplemented!(),
-            OpCode::Loopz => unimplemented!(),
-            OpCode::Loopnz => unimplemented!(),
-            OpCode::Jcxz => unimplemented!(),
-        };
+            _ => execution = Execution::Unsupported,
+        },
+        Mnemonic::Lea | Mnemonic::Lds | Mnemonic::Les => {
+            simulate_load_address(sim_state, sim_mem, instruction)
+        }
+        // there is no coprocessor, so wait never has to wait
+        Mnemonic::Nop | Mnemonic::Wait => {}
+        // there are no ports or coprocessor to simulate
+        Mnemonic::In | Mnemonic::Out | Mnemonic::Esc => execution = Execution::Unsupported,
+    }
 
-        // remove newline from instruction
-        let (mut instruction, _) = get_instruction(machine_code, index);
-        instruction.truncate(instruction.len() - 1);
+    Executed {
+        execution,
+        transferred_control,
+    }
+}
 
-        sim_state.ip = ((sim_state.ip as i16) + (ip_offset as i16)) as u16;
+/// Simulates a program loaded at address 0
+pub fn simulate(machine_code: &[u8]) -> String {
+    simulate_with_config(machine_code, &SimulationConfig::default())
+}
 
-        let state_diff = get_sim_state_diff(&previous_state, &sim_state);
-        sim_log.push_str(&format!("{} ; {}", &instruction, state_diff));
-    }
+/// Simulates a program until cs:ip leaves it or it halts. Returns the simulation log.
+pub fn simulate_with_config(machine_code: &[u8], config: &SimulationConfig) -> String {
+    simulate_with_hooks(machine_code, config, &mut InterruptHooks::new())
+}
 
-    sim_log.push_str(&format!("Final registers:\n"));
+/// Simulates a program like simulate_with_config, servicing the hooked interrupts natively
+pub fn simulate_with_hooks(
+    machine_code: &[u8],
+    config: &SimulationConfig,
+    interrupt_hooks: &mut InterruptHooks,
+) -> String {
+    let mut sim_state = SimulationState {
+        ..Default::default()
+    };
+    let mut sim_mem = SimMem::new(MEMORY_SIZE);
+    load_program(&mut sim_state, &mut sim_mem, machine_code, config);
+
+    let program_start = physical_address(config.load_segment, config.load_offset);
+    let mut sim_log = run_simulation(
+        &mut sim_state,
+        &mut sim_mem,
+        interrupt_hooks,
+        config.timing,
+        program_start,
+        machine_code.len(),
+    );
+
+    sim_log.push_str("Final registers:\n");
     sim_log.push_str(&format!("{}\n", sim_state.pretty_string()));
 
     sim_log
 }
+
+/// Whether cs:ip is within a range of memory
+/// program_start: the physical address of the start of the range
+/// program_length: the number of bytes in the range
+pub fn in_program(
+    sim_state: &SimulationState,
+    program_start: usize,
+    program_length: usize,
+) -> bool {
+    let address = physical_address(sim_state.cs, sim_state.ip);
+    (address + MEMORY_SIZE - program_start) % MEMORY_SIZE < program_length
+}
+
+/// Fetches, decodes and executes the instruction at cs:ip. cs:ip is left on an unsupported
+/// instruction.
+/// returns: the instruction, whether the simulation should halt after it and whether it
+/// transferred control
+pub fn step(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    interrupt_hooks: &mut InterruptHooks,
+) -> Result<(Instruction, Executed), DecodeError> {
+    let instruction = fetch_instruction(sim_state, sim_mem)?;
+    let instruction_ip = sim_state.ip;
+
+    // ip points to the next instruction while the current one executes
+    sim_state.ip = sim_state.ip.wrapping_add(instruction.size as u16);
+
+    let executed = execute_instruction(sim_state, sim_mem, interrupt_hooks, &instruction);
+    if executed.execution == Execution::Unsupported {
+        sim_state.ip = instruction_ip;
+    }
+    Ok((instruction, executed))
+}
+
+/// Runs instructions from cs:ip until it leaves a range of memory, an instruction halts or an
+/// instruction can't be decoded or simulated, estimating the clocks of each instruction under every
+/// timing given. Returns the log, with a line from log_instruction for each instruction.
+/// timings: how the clocks of each instruction are estimated, once per timing
+/// program_start: the physical address of the start of the range
+/// program_length: the number of bytes in the range
+/// log_instruction: makes the log line of an instruction from the states before and after it ran
+/// and its clocks and their breakdown under each timing
+fn run_timed(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    interrupt_hooks: &mut InterruptHooks,
+    timings: &[Timing],
+    program_start: usize,
+    program_length: usize,
+    mut log_instruction: impl FnMut(
+        &Instruction,
+        &SimulationState,
+        &SimulationState,
+        &[(u32, String)],
+    ) -> String,
+) -> String {
+    let mut log = "".to_owned();
+    let mut timers: Vec<InstructionTimer> = timings
+        .iter()
+        .map(|timing| InstructionTimer::new(*timing, sim_state.ip))
+        .collect();
+
+    while in_program(sim_state, program_start, program_length) {
+        let previous_state = sim_state.clone();
+
+        let (instruction, executed) = match step(sim_state, sim_mem, interrupt_hooks) {
+            Ok(step) => step,
+            Err(error) => {
+                log.push_str(&format!("Decode error: {}\n", error));
+                break;
+            }
+        };
+        if executed.execution == Execution::Unsupported {
+            log.push_str(&format!("Unsupported instruction: {}\n", instruction));
+            break;
+        }
+
+        let clocks: Vec<(u32, String)> = timers
+            .iter_mut()
+            .map(|timer| {
+                timer.time(
+                    &instruction,
+                    &previous_state,
+                    sim_state,
+                    executed.transferred_control,
+                )
+            })
+            .collect();
+        log.push_str(&log_instruction(
+            &instruction,
+            &previous_state,
+            sim_state,
+            &clocks,
+        ));
+
+        if executed.execution == Execution::Halt {
+            break;
+        }
+    }
+
+    log
+}
+
+/// Runs instructions from cs:ip until it leaves a range of memory, an instruction halts or an
+/// instruction can't be decoded or simulated. Returns the simulation log, with the estimated clocks
+/// of each instruction and the total.
+/// timing: how the clocks of each instruction are estimated
+/// program_start: the physical address of the start of the range
+/// program_length: the number of bytes in the range
+pub fn run_simulation(
+    sim_state: &mut SimulationState,
+    sim_mem: &mut SimMem,
+    interrupt_hooks: &mut InterruptHooks,
+    timing: Timing,
+    program_start: usize,
+    program_length: usize,
+) -> String {
+    let mut total_clocks: u64 = 0;
+    let mut sim_log = run_timed(
+        sim_state,
+        sim_mem,
+        interrupt_hooks,
+        &[timing],
+        program_start,
+        program_length,
+        |instruction, previous_state, sim_state, clocks| {
+            let (clocks, breakdown) = &clocks[0];
+            total_clocks += *clocks as u64;
+
+            let state_diff = get_sim_state_diff(previous_state, sim_state);
+            format!(
+                "{} ; Clocks: +{} = {}{} | {}",
+                instruction, clocks, total_clocks, breakdown, state_diff
+            )
+        },
+    );
+
+    sim_log.push_str(&format!("Total clocks: {}\n", total_clocks));
+
+    sim_log
+}
+
+/// Simulates a program once and reports the estimated clocks of every instruction on the 8086 and
+/// the 8088 side by side, with the totals and how much slower the 8088 is. The configured cpu model
+/// is ignored, but the instruction queue is emulated for both if the timing asks for it.
+pub fn compare_cpu_timing(machine_code: &[u8], config: &SimulationConfig) -> String {
+    let mut sim_state = SimulationState {
+        ..Default::default()
+    };
+    let mut sim_mem = SimMem::new(MEMORY_SIZE);
+    load_program(&mut sim_state, &mut sim_mem, machine_code, config);
+    let program_start = physical_address(config.load_segment, config.load_offset);
+    let timing_for = |cpu_model| Timing {
+        cpu_model,
+        ..config.timing
+    };
+
+    let mut report = format!("{:<32} | {:<28} | {}\n", "instruction", "8086", "8088");
+    let (mut total_8086, mut total_8088): (u64, u64) = (0, 0);
+    report.push_str(&run_timed(
+        &mut sim_state,
+        &mut sim_mem,
+        &mut InterruptHooks::new(),
+        &[timing_for(CpuModel::I8086), timing_for(CpuModel::I8088)],
+        program_start,
+        machine_code.len(),
+        |instruction, _, _, clocks| {
+            let [(clocks_8086, breakdown_8086), (clocks_8088, breakdown_8088)] = clocks else {
+                panic!("Expected the clocks of the 8086 and the 8088");
+            };
+            total_8086 += *clocks_8086 as u64;
+            total_8088 += *clocks_8088 as u64;
+
+            let column_8086 = format!("+{} = {}{}", clocks_8086, total_8086, breakdown_8086);
+            let column_8088 = format!("+{} = {}{}", clocks_8088, total_8088, breakdown_8088);
+            format!(
+                "{:<32} | {:<28} | {}\n",
+                instruction.to_string(),
+                column_8086,
+                column_8088
+            )
+        },
+    ));
+
+    report.push_str(&format!(
+        "Total clocks: 8086 {}, 8088 {}\n",
+        total_8086, total_8088
+    ));
+    if total_8086 != 0 {
+        let slowdown = (total_8088 as f64 / total_8086 as f64 - 1.0) * 100.0;
+        report.push_str(&format!("The 8088 takes {:.1}% more clocks\n", slowdown));
+    }
+
+    report
+}
@@ -1,9 +1,19 @@
 /*
 There are four 16-bit registers that could be addressed as both 16-bits and 8-bit sections (ax, bx, cx, dx).
 There are four 16-bit registers that could only be used in their entirety (sp, bp, si, di).
+There are four 16-bit segment registers (es, cs, ss, ds). A segment register shifted left by 4 bits plus a
+16-bit offset gives a 20-bit physical address.
  */
 
-use crate::common_assembly::Register;
+use std::cell::RefCell;
+
+use crate::byte_operations::concat_bytes;
+use crate::common_assembly::{
+    parse_register, parse_segment_register, Register, SegmentRegister, WordByte,
+};
+
+/// the 8086 has 20 address lines, so it can address 1 MiB
+pub const MEMORY_SIZE: usize = 1 << 20;
 
 #[derive(Default, Clone)]
 pub struct SimulationState {
@@ -16,8 +26,20 @@ pub struct SimulationState {
     pub si: u16,
     pub di: u16,
 
-    pub sign_flag: bool,
+    pub es: u16,
+    pub cs: u16,
+    pub ss: u16,
+    pub ds: u16,
+
+    pub carry_flag: bool,
+    pub parity_flag: bool,
+    pub auxiliary_carry_flag: bool,
     pub zero_flag: bool,
+    pub sign_flag: bool,
+    pub overflow_flag: bool,
+    pub direction_flag: bool,
+    pub interrupt_flag: bool,
+    pub trap_flag: bool,
 
     pub ip: u16,
 }
@@ -44,7 +66,8 @@ impl SimulationState {
         }
     }
 
-    /// sets the value of a register. Note that we don't currently support writing to half registers
+    /// sets the value of a register. Writing a half register only changes its byte of the full
+    /// register, using the low byte of value
     pub fn set_register_value(&mut self, register: Register, value: u16) {
         match register {
             Register::Al => {
@@ -98,19 +121,190 @@ impl SimulationState {
         };
     }
 
-    /// sets arithmetic flags based on the value in value
-    pub fn set_flags(&mut self, value: u16) {
-        if (value & 0x80) > 0 {
-            self.sign_flag = true;
-        } else {
-            self.sign_flag = false;
+    pub fn get_segment_register_value(&self, segment_register: SegmentRegister) -> u16 {
+        match segment_register {
+            SegmentRegister::Es => self.es,
+            SegmentRegister::Cs => self.cs,
+            SegmentRegister::Ss => self.ss,
+            SegmentRegister::Ds => self.ds,
+        }
+    }
+
+    pub fn set_segment_register_value(&mut self, segment_register: SegmentRegister, value: u16) {
+        match segment_register {
+            SegmentRegister::Es => self.es = value,
+            SegmentRegister::Cs => self.cs = value,
+            SegmentRegister::Ss => self.ss = value,
+            SegmentRegister::Ds => self.ds = value,
+        }
+    }
+
+    /// packs the flags into the 16-bit flags register, as pushf stores it. The reserved bits read
+    /// as 1 on the 8086.
+    pub fn get_flags_register(&self) -> u16 {
+        let mut flags_register = 0b1111000000000010;
+        for (flag, bit) in [
+            (self.carry_flag, 0),
+            (self.parity_flag, 2),
+            (self.auxiliary_carry_flag, 4),
+            (self.zero_flag, 6),
+            (self.sign_flag, 7),
+            (self.trap_flag, 8),
+            (self.interrupt_flag, 9),
+            (self.direction_flag, 10),
+            (self.overflow_flag, 11),
+        ] {
+            flags_register |= (flag as u16) << bit;
+        }
+
+        flags_register
+    }
+
+    /// unpacks the 16-bit flags register, as popf loads it
+    pub fn set_flags_register(&mut self, flags_register: u16) {
+        let bit = |bit: u16| (flags_register & (1 << bit)) != 0;
+
+        self.carry_flag = bit(0);
+        self.parity_flag = bit(2);
+        self.auxiliary_carry_flag = bit(4);
+        self.zero_flag = bit(6);
+        self.sign_flag = bit(7);
+        self.trap_flag = bit(8);
+        self.interrupt_flag = bit(9);
+        self.direction_flag = bit(10);
+        self.overflow_flag = bit(11);
+    }
+
+    /// sets the zero, sign and parity flags from a result
+    /// result: the result of the operation. Bits above the operand width are ignored.
+    /// word_byte: the width of the operation
+    pub fn set_result_flags(&mut self, result: u16, word_byte: WordByte) {
+        let result = result & width_mask(word_byte);
+
+        self.zero_flag = result == 0;
+        self.sign_flag = (result & sign_bit(word_byte)) != 0;
+        // parity only considers the low byte, even for word operations
+        self.parity_flag = (result as u8).count_ones().is_multiple_of(2);
+    }
+
+    /// adds two values and sets all of the arithmetic flags, as add and adc do
+    /// carry: whether to add an extra 1, for adc
+    /// returns: the sum, truncated to the operand width
+    pub fn add_with_flags(
+        &mut self,
+        destination: u16,
+        source: u16,
+        carry: bool,
+        word_byte: WordByte,
+    ) -> u16 {
+        let mask = width_mask(word_byte);
+        let (destination, source) = (destination & mask, source & mask);
+        let sum = destination as u32 + source as u32 + carry as u32;
+        let result = (sum as u16) & mask;
+
+        self.carry_flag = sum > mask as u32;
+        self.auxiliary_carry_flag = ((destination ^ source ^ result) & 0x10) != 0;
+        // overflow when both operands have the same sign and the result's sign differs
+        self.overflow_flag =
+            ((destination ^ result) & (source ^ result) & sign_bit(word_byte)) != 0;
+        self.set_result_flags(result, word_byte);
+
+        result
+    }
+
+    /// subtracts source from destination and sets all of the arithmetic flags, as sub, sbb and
+    /// cmp do
+    /// borrow: whether to subtract an extra 1, for sbb
+    /// returns: the difference, truncated to the operand width
+    pub fn subtract_with_flags(
+        &mut self,
+        destination: u16,
+        source: u16,
+        borrow: bool,
+        word_byte: WordByte,
+    ) -> u16 {
+        let mask = width_mask(word_byte);
+        let (destination, source) = (destination & mask, source & mask);
+        let result = destination.wrapping_sub(source).wrapping_sub(borrow as u16) & mask;
+
+        self.carry_flag = (source as u32 + borrow as u32) > destination as u32;
+        self.auxiliary_carry_flag = ((destination ^ source ^ result) & 0x10) != 0;
+        // overflow when the operands have different signs and the result's sign differs from the
+        // destination's
+        self.overflow_flag =
+            ((destination ^ source) & (destination ^ result) & sign_bit(word_byte)) != 0;
+        self.set_result_flags(result, word_byte);
+
+        result
+    }
+
+    /// sets the flags for and, or, xor and test, which always clear carry and overflow
+    pub fn set_logic_flags(&mut self, result: u16, word_byte: WordByte) {
+        self.carry_flag = false;
+        self.overflow_flag = false;
+        self.auxiliary_carry_flag = false;
+        self.set_result_flags(result, word_byte);
+    }
+
+    /// the value of a register by its assembly name, including the segment registers, ip and flags
+    pub fn get_named_register(&self, name: &str) -> Option<u16> {
+        if let Some(register) = parse_register(name) {
+            return Some(self.get_register_value(register));
         }
+        if let Some(segment_register) = parse_segment_register(name) {
+            return Some(self.get_segment_register_value(segment_register));
+        }
+        match name.to_lowercase().as_str() {
+            "ip" => Some(self.ip),
+            "flags" => Some(self.get_flags_register()),
+            _ => None,
+        }
+    }
 
-        if value == 0 {
-            self.zero_flag = true;
+    /// sets a register by its assembly name like get_named_register. Byte registers keep the low
+    /// byte of the value.
+    /// returns: whether there is a register with that name
+    pub fn set_named_register(&mut self, name: &str, value: u16) -> bool {
+        if let Some(register) = parse_register(name) {
+            self.set_register_value(register, value);
+        } else if let Some(segment_register) = parse_segment_register(name) {
+            self.set_segment_register_value(segment_register, value);
         } else {
-            self.zero_flag = false;
+            match name.to_lowercase().as_str() {
+                "ip" => self.ip = value,
+                "flags" => self.set_flags_register(value),
+                _ => return false,
+            }
         }
+        true
+    }
+
+    /// the value of a flag by its assembly name, like zf
+    pub fn get_flag(&self, name: &str) -> Option<bool> {
+        let name = name.to_lowercase();
+        flag_letters(self)
+            .into_iter()
+            .find(|(_, letter)| name == format!("{}f", letter.to_ascii_lowercase()))
+            .map(|(flag, _)| flag)
+    }
+
+    /// sets a flag by its assembly name, like zf
+    /// returns: whether there is a flag with that name
+    pub fn set_flag(&mut self, name: &str, value: bool) -> bool {
+        let flag = match name.to_lowercase().as_str() {
+            "cf" => &mut self.carry_flag,
+            "pf" => &mut self.parity_flag,
+            "af" => &mut self.auxiliary_carry_flag,
+            "zf" => &mut self.zero_flag,
+            "sf" => &mut self.sign_flag,
+            "of" => &mut self.overflow_flag,
+            "tf" => &mut self.trap_flag,
+            "if" => &mut self.interrupt_flag,
+            "df" => &mut self.direction_flag,
+            _ => return false,
+        };
+        *flag = value;
+        true
     }
 
     pub fn pretty_string(&self) -> String {
@@ -124,6 +318,10 @@ impl SimulationState {
                 "bp: {:#06X}({})\n",
                 "si: {:#06X}({})\n",
                 "di: {:#06X}({})\n",
+                "es: {:#06X}({})\n",
+                "cs: {:#06X}({})\n",
+                "ss: {:#06X}({})\n",
+                "ds: {:#06X}({})\n",
                 "ip: {:#06X}({})\n",
             ),
             self.ax,
@@ -142,6 +340,14 @@ impl SimulationState {
             self.si,
             self.di,
             self.di,
+            self.es,
+            self.es,
+            self.cs,
+            self.cs,
+            self.ss,
+            self.ss,
+            self.ds,
+            self.ds,
             self.ip,
             self.ip,
         );
@@ -153,13 +359,44 @@ impl SimulationState {
     }
 }
 
+/// the bits that hold a value of the given width
+pub fn width_mask(word_byte: WordByte) -> u16 {
+    match word_byte {
+        WordByte::Byte => 0xFF,
+        WordByte::Word => 0xFFFF,
+    }
+}
+
+/// the most significant bit of a value of the given width
+pub fn sign_bit(word_byte: WordByte) -> u16 {
+    match word_byte {
+        WordByte::Byte => 0x80,
+        WordByte::Word => 0x8000,
+    }
+}
+
+/// returns each flag paired with the letter used to print it. The status flags come first in
+/// CPAZSO order, followed by the control flags.
+fn flag_letters(sim_state: &SimulationState) -> [(bool, char); 9] {
+    [
+        (sim_state.carry_flag, 'C'),
+        (sim_state.parity_flag, 'P'),
+        (sim_state.auxiliary_carry_flag, 'A'),
+        (sim_state.zero_flag, 'Z'),
+        (sim_state.sign_flag, 'S'),
+        (sim_state.overflow_flag, 'O'),
+        (sim_state.trap_flag, 'T'),
+        (sim_state.interrupt_flag, 'I'),
+        (sim_state.direction_flag, 'D'),
+    ]
+}
+
 /// add flags string to the mutable string passed in as an argument
 fn add_flags_string(sim_state: &SimulationState, result: &mut String) {
-    if sim_state.sign_flag {
-        result.push_str("S");
-    }
-    if sim_state.zero_flag {
-        result.push_str("Z");
+    for (flag, letter) in flag_letters(sim_state) {
+        if flag {
+            result.push(letter);
+        }
     }
 }
 
@@ -191,31 +428,222 @@ pub fn get_sim_state_diff(before: &SimulationState, after: &SimulationState) ->
     if before.di != after.di {
         result.push_str(&format!("di: {:#06X} -> {:#06X} ", before.di, after.di));
     }
+    if before.es != after.es {
+        result.push_str(&format!("es: {:#06X} -> {:#06X} ", before.es, after.es));
+    }
+    if before.cs != after.cs {
+        result.push_str(&format!("cs: {:#06X} -> {:#06X} ", before.cs, after.cs));
+    }
+    if before.ss != after.ss {
+        result.push_str(&format!("ss: {:#06X} -> {:#06X} ", before.ss, after.ss));
+    }
+    if before.ds != after.ds {
+        result.push_str(&format!("ds: {:#06X} -> {:#06X} ", before.ds, after.ds));
+    }
     if before.ip != after.ip {
         result.push_str(&format!("ip: {:#06X} -> {:#06X} ", before.ip, after.ip));
     }
 
-    if before.sign_flag != after.sign_flag || before.zero_flag != after.zero_flag {
+    if flag_letters(before) != flag_letters(after) {
         result.push_str("Flags: ");
-        add_flags_string(&before, &mut result);
+        add_flags_string(before, &mut result);
         result.push_str(" -> ");
-        add_flags_string(&after, &mut result);
+        add_flags_string(after, &mut result);
     }
 
-    result.push_str("\n");
+    result.push('\n');
 
     result
 }
 
+/// Whether a memory access read or wrote
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// One byte of memory read or written by a simulated instruction
+/// address: the physical address
+/// value: the byte read, or the byte written
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct MemoryAccess {
+    pub address: usize,
+    pub value: u8,
+    pub kind: AccessKind,
+}
+
+/// Called for every byte read or written through SimMem, apart from instruction fetches and peeks
+pub type MemoryAccessHook = Box<dyn FnMut(MemoryAccess)>;
+
+/// Simulated physical memory. Addresses wrap around at the end of memory.
 #[derive(Default)]
 pub struct SimMem {
     pub mem: Vec<u8>,
+    /// reads take &self, so the hook is borrowed mutably through a RefCell
+    access_hook: RefCell<Option<MemoryAccessHook>>,
+}
+
+/// the 20-bit physical address of segment:offset. Addresses past 1 MiB wrap around to 0.
+pub fn physical_address(segment: u16, offset: u16) -> usize {
+    (((segment as usize) << 4) + offset as usize) % MEMORY_SIZE
 }
 
 impl SimMem {
     pub fn new(capacity: usize) -> Self {
         Self {
             mem: vec![0; capacity],
+            ..Default::default()
         }
     }
+
+    /// Calls a hook for every byte read or written from now on, replacing any previous hook
+    pub fn set_access_hook(&mut self, access_hook: Option<MemoryAccessHook>) {
+        self.access_hook = RefCell::new(access_hook);
+    }
+
+    fn call_access_hook(&self, address: usize, value: u8, kind: AccessKind) {
+        if let Some(access_hook) = self.access_hook.borrow_mut().as_mut() {
+            access_hook(MemoryAccess {
+                address: address % self.mem.len(),
+                value,
+                kind,
+            });
+        }
+    }
+
+    /// reads a byte without calling the access hook, for instruction fetches and inspecting memory
+    pub fn peek_byte(&self, address: usize) -> u8 {
+        self.mem[address % self.mem.len()]
+    }
+
+    pub fn read_byte(&self, address: usize) -> u8 {
+        let value = self.peek_byte(address);
+        self.call_access_hook(address, value, AccessKind::Read);
+        value
+    }
+
+    /// reads a little-endian word
+    pub fn read_word(&self, address: usize) -> u16 {
+        concat_bytes(self.read_byte(address + 1), self.read_byte(address))
+    }
+
+    pub fn write_byte(&mut self, address: usize, value: u8) {
+        let length = self.mem.len();
+        self.mem[address % length] = value;
+        self.call_access_hook(address, value, AccessKind::Write);
+    }
+
+    /// writes a little-endian word
+    pub fn write_word(&mut self, address: usize, value: u16) {
+        self.write_byte(address, (value & 0xFF) as u8);
+        self.write_byte(address + 1, ((value & 0xFF00) >> 8) as u8);
+    }
+
+    /// reads a byte or word at segment:offset. The second byte of a word at offset 0xFFFF comes
+    /// from offset 0 of the same segment, as on the 8086.
+    pub fn read_segmented(&self, segment: u16, offset: u16, word_byte: WordByte) -> u16 {
+        let low_byte = self.read_byte(physical_address(segment, offset));
+        match word_byte {
+            WordByte::Byte => low_byte as u16,
+            WordByte::Word => {
+                let high_byte = self.read_byte(physical_address(segment, offset.wrapping_add(1)));
+                concat_bytes(high_byte, low_byte)
+            }
+        }
+    }
+
+    /// writes a byte or word at segment:offset, wrapping within the segment like read_segmented
+    pub fn write_segmented(&mut self, segment: u16, offset: u16, word_byte: WordByte, value: u16) {
+        self.write_byte(physical_address(segment, offset), (value & 0xFF) as u8);
+        if word_byte == WordByte::Word {
+            self.write_byte(
+                physical_address(segment, offset.wrapping_add(1)),
+                ((value & 0xFF00) >> 8) as u8,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the result, carry, auxiliary carry and overflow of an add or subtract from a clear state
+    fn arithmetic(
+        subtract: bool,
+        destination: u16,
+        source: u16,
+        carry: bool,
+        word_byte: WordByte,
+    ) -> (u16, bool, bool, bool) {
+        let mut sim_state = SimulationState {
+            ..Default::default()
+        };
+        let result = if subtract {
+            sim_state.subtract_with_flags(destination, source, carry, word_byte)
+        } else {
+            sim_state.add_with_flags(destination, source, carry, word_byte)
+        };
+        (
+            result,
+            sim_state.carry_flag,
+            sim_state.auxiliary_carry_flag,
+            sim_state.overflow_flag,
+        )
+    }
+
+    #[test]
+    fn byte_add_flags() {
+        let add = |destination, source, carry| {
+            arithmetic(false, destination, source, carry, WordByte::Byte)
+        };
+        assert_eq!(add(0x0F, 0x01, false), (0x10, false, true, false));
+        assert_eq!(add(0x7F, 0x01, false), (0x80, false, true, true));
+        assert_eq!(add(0xFF, 0x01, false), (0x00, true, true, false));
+        assert_eq!(add(0x80, 0x80, false), (0x00, true, false, true));
+        assert_eq!(add(0x7F, 0x00, true), (0x80, false, true, true));
+    }
+
+    #[test]
+    fn word_add_flags() {
+        let add = |destination, source, carry| {
+            arithmetic(false, destination, source, carry, WordByte::Word)
+        };
+        assert_eq!(add(0x00FF, 0x0001, false), (0x0100, false, true, false));
+        assert_eq!(add(0x0080, 0x0080, false), (0x0100, false, false, false));
+        assert_eq!(add(0x7FFF, 0x0001, false), (0x8000, false, true, true));
+        assert_eq!(add(0xFFFF, 0x0001, false), (0x0000, true, true, false));
+        assert_eq!(add(0x8000, 0x8000, false), (0x0000, true, false, true));
+    }
+
+    #[test]
+    fn byte_subtract_flags() {
+        let subtract = |destination, source, borrow| {
+            arithmetic(true, destination, source, borrow, WordByte::Byte)
+        };
+        assert_eq!(subtract(0x10, 0x01, false), (0x0F, false, true, false));
+        assert_eq!(subtract(0x80, 0x01, false), (0x7F, false, true, true));
+        assert_eq!(subtract(0x00, 0x01, false), (0xFF, true, true, false));
+        assert_eq!(subtract(0x7F, 0xFF, false), (0x80, true, false, true));
+        assert_eq!(subtract(0x10, 0x00, true), (0x0F, false, true, false));
+    }
+
+    #[test]
+    fn word_subtract_flags() {
+        let subtract = |destination, source, borrow| {
+            arithmetic(true, destination, source, borrow, WordByte::Word)
+        };
+        assert_eq!(
+            subtract(0x0100, 0x0001, false),
+            (0x00FF, false, true, false)
+        );
+        assert_eq!(
+            subtract(0x0080, 0x0001, false),
+            (0x007F, false, true, false)
+        );
+        assert_eq!(subtract(0x8000, 0x0001, false), (0x7FFF, false, true, true));
+        assert_eq!(subtract(0x0000, 0x0001, false), (0xFFFF, true, true, false));
+        assert_eq!(subtract(0x7FFF, 0xFFFF, false), (0x8000, true, false, true));
+    }
 }
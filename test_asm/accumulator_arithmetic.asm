@@ -0,0 +1,19 @@
+bits 16
+
+; accumulator forms
+mov ax, 1000
+add ax, 30000
+add al, 200
+sub ax, 4000
+sub al, 1
+cmp ax, 27307
+cmp al, 171
+
+; the same operations on bx use the register-immediate forms
+mov bx, 1000
+add bx, 30000
+add bl, 200
+sub bx, 4000
+sub bl, 1
+cmp bx, 27307
+cmp bl, 171
@@ -0,0 +1,15 @@
+bits 16
+
+; packed
+daa
+das
+
+; unpacked
+aaa
+aas
+aam
+aad
+
+; other bases
+aam 16
+aad 7
@@ -0,0 +1,12 @@
+bits 16
+
+int 33
+int 0
+int 255
+int3
+into
+iret
+
+; interrupt flag
+cli
+sti
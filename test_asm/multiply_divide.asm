@@ -0,0 +1,25 @@
+bits 16
+
+; register operands
+mul cl
+mul bx
+imul ch
+imul si
+div bl
+div cx
+idiv dh
+idiv di
+
+; memory operands need a size
+mul byte [bx]
+mul word [bp + 2]
+imul byte [si - 8]
+imul word [1000]
+div byte [bx + di + 300]
+div word [bp]
+idiv byte [es:bx]
+idiv word [ds:bp + si]
+
+; sign extension for idiv
+cbw
+cwd
@@ -0,0 +1,40 @@
+bits 16
+
+; segment overrides
+mov ax, [es:bx]
+mov [cs:bp + 4], cl
+mov bx, [ss:1000]
+add word [ds:bp + si], 7
+cmp byte [es:di - 1], 12
+inc word [cs:bx]
+es xlat
+xlat
+
+; lock
+lock xchg [bx], ax
+lock inc word [si]
+lock not byte [bp + di + 8]
+lock add [es:bx + 2], dx
+
+; loads
+lea ax, [bx + si + 4]
+lea di, [bp - 6]
+lds si, [bx]
+les di, [es:1000]
+xchg ax, cx
+xchg bl, dh
+xchg [bp], si
+
+; flags and ports
+lahf
+sahf
+clc
+cmc
+stc
+in al, 200
+in ax, dx
+out 44, ax
+out dx, al
+nop
+wait
+hlt
@@ -0,0 +1,26 @@
+bits 16
+
+; by 1
+shl ax, 1
+shr bl, 1
+sar cx, 1
+rol dh, 1
+ror si, 1
+rcl di, 1
+rcr bp, 1
+
+; by cl
+shl ax, cl
+shr bl, cl
+sar cx, cl
+rol dh, cl
+ror si, cl
+rcl di, cl
+rcr bp, cl
+
+; memory operands
+shl byte [bx], 1
+shr word [bp + si + 4], 1
+sar byte [1000], cl
+rol word [di - 2], cl
+rcr byte [es:si], 1
@@ -0,0 +1,33 @@
+bits 16
+
+; registers, segment registers and memory
+push ax
+push sp
+push es
+push cs
+push word [bx + si + 4]
+push word [1000]
+pop cx
+pop ds
+pop word [bp - 2]
+pushf
+popf
+
+; near and far transfers
+call near_procedure
+call bx
+call [bx + 6]
+call 4660:22136
+call far [bp + di]
+jmp short near_procedure
+jmp near near_procedure
+jmp di
+jmp [si]
+jmp 4660:22136
+jmp far [1000]
+
+near_procedure:
+ret
+ret 8
+retf
+retf 4
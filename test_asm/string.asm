@@ -0,0 +1,33 @@
+bits 16
+
+; single
+movsb
+movsw
+cmpsb
+cmpsw
+scasb
+scasw
+lodsb
+lodsw
+stosb
+stosw
+
+; repeated
+rep movsb
+rep movsw
+repe cmpsb
+repne cmpsw
+repe scasb
+repne scasw
+rep lodsb
+rep stosw
+
+; the ds:si source can be overridden
+es movsb
+rep cs movsw
+repe ss cmpsb
+es lodsw
+
+; direction flag
+std
+cld